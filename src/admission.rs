@@ -0,0 +1,159 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::CONFIG;
+
+const ROWS: usize = 4;
+// counters saturate at this value, like TinyLFU's 4-bit counters
+const MAX_COUNT: u8 = 15;
+// the most the doorkeeper can count to, its current and previous windows' counts
+pub const MAX_MIN_REQUESTS: u32 = 2 * MAX_COUNT as u32;
+
+lazy_static::lazy_static! {
+    // recorded on every hit, so it's updated in place rather than behind a lock
+    pub static ref SKETCH: FrequencySketch = {
+        FrequencySketch::new(CONFIG.max_cache_entries.max(1024))
+    };
+
+    pub static ref DOORKEEPER: Mutex<Doorkeeper> = {
+        Mutex::new(Doorkeeper::new(
+            CONFIG.max_cache_entries.max(1024),
+            CONFIG.admission_window_seconds as u128 * 1000,
+        ))
+    };
+}
+
+/// Count-min sketch of how often keys are requested. All counters are
+/// halved periodically so the estimates favor recent traffic. Counters
+/// are atomics, so concurrent updates can interleave with a halving,
+/// which is fine for estimates.
+pub struct FrequencySketch {
+    rows: Vec<Vec<AtomicU8>>,
+    width: usize,
+    additions: AtomicUsize,
+    reset_at: usize,
+}
+impl FrequencySketch {
+    pub fn new(capacity: usize) -> Self {
+        let width = capacity.next_power_of_two();
+        Self {
+            rows: (0..ROWS)
+                .map(|_| (0..width).map(|_| AtomicU8::new(0)).collect())
+                .collect(),
+            width,
+            additions: AtomicUsize::new(0),
+            reset_at: width * 10,
+        }
+    }
+
+    fn index(&self, key: &str, row: usize) -> usize {
+        let mut h = DefaultHasher::new();
+        row.hash(&mut h);
+        key.hash(&mut h);
+        (h.finish() as usize) & (self.width - 1)
+    }
+
+    pub fn increment(&self, key: &str) {
+        for row in 0..ROWS {
+            let ind = self.index(key, row);
+            self.rows[row][ind]
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+                    if c < MAX_COUNT {
+                        Some(c + 1)
+                    } else {
+                        None
+                    }
+                })
+                .ok();
+        }
+        // exactly one addition lands on `reset_at`, and it does the reset
+        if self.additions.fetch_add(1, Ordering::Relaxed) + 1 == self.reset_at {
+            self.reset();
+        }
+    }
+
+    pub fn estimate(&self, key: &str) -> u8 {
+        (0..ROWS)
+            .map(|row| self.rows[row][self.index(key, row)].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn reset(&self) {
+        for row in self.rows.iter() {
+            for counter in row.iter() {
+                counter
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| Some(c / 2))
+                    .ok();
+            }
+        }
+        self.additions
+            .fetch_sub(self.reset_at / 2, Ordering::Relaxed);
+    }
+}
+
+/// Record a request for `key`, returning its estimated frequency
+pub fn record(key: &str) -> u8 {
+    SKETCH.increment(key);
+    SKETCH.estimate(key)
+}
+
+pub fn estimate(key: &str) -> u8 {
+    SKETCH.estimate(key)
+}
+
+/// Counts requests per key within a sliding window, made of the
+/// current and previous window's sketches. Keys have to be requested
+/// a few times within the window before they're worth caching.
+pub struct Doorkeeper {
+    current: FrequencySketch,
+    previous: FrequencySketch,
+    capacity: usize,
+    window_millis: u128,
+    rotated_millis: u128,
+}
+impl Doorkeeper {
+    pub fn new(capacity: usize, window_millis: u128) -> Self {
+        Self {
+            current: FrequencySketch::new(capacity),
+            previous: FrequencySketch::new(capacity),
+            capacity,
+            window_millis,
+            rotated_millis: crate::cache::now_millis(),
+        }
+    }
+
+    fn maybe_rotate(&mut self, now: u128) {
+        if now.saturating_sub(self.rotated_millis) < self.window_millis {
+            return;
+        }
+        let fresh = FrequencySketch::new(self.capacity);
+        self.previous = std::mem::replace(&mut self.current, fresh);
+        self.rotated_millis = now;
+    }
+
+    /// Record a request for `key`, returning how many times
+    /// it's been requested within the window
+    pub fn record(&mut self, key: &str) -> u8 {
+        self.maybe_rotate(crate::cache::now_millis());
+        self.current.increment(key);
+        self.current
+            .estimate(key)
+            .saturating_add(self.previous.estimate(key))
+    }
+}
+
+/// Whether a key not yet in the cache has been requested
+/// often enough recently to be worth storing
+pub fn passes_doorkeeper(key: &str) -> bool {
+    if CONFIG.admission_min_requests <= 1 {
+        return true;
+    }
+    let count = DOORKEEPER
+        .lock()
+        .expect("doorkeeper lock poisoned")
+        .record(key);
+    count as u32 >= CONFIG.admission_min_requests
+}
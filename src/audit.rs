@@ -0,0 +1,54 @@
+use actix_web::HttpRequest;
+use slog::{o, Drain};
+
+use crate::{CONFIG, LOG};
+
+lazy_static::lazy_static! {
+    // Resets, purges, and admin api calls, kept apart from the
+    // request logs in AUDIT_LOG_FILE when it's set
+    static ref AUDIT_LOG: slog::Logger = match &CONFIG.audit_log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("unable to open audit_log_file");
+            let drain = slog_json::Json::default(file).fuse();
+            let drain = slog_async::Async::new(drain).build().fuse();
+            slog::Logger::root(drain, o!("app" => "badge-cache", "audit" => true))
+        }
+        None => LOG.new(o!("audit" => true)),
+    };
+}
+
+/// Who's making a request: their ip, and which token they used if any.
+/// Tokens are identified by a prefix of their hash, never recorded as is.
+pub fn actor(request: &HttpRequest) -> String {
+    let ip = request
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string();
+    let token = request
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer ").trim())
+        .filter(|t| !t.is_empty());
+    match token {
+        Some(token) => {
+            let hash = crate::cache::content_hash(token.as_bytes());
+            format!("ip:{} token:{}", ip, &hash[..8])
+        }
+        None => format!("ip:{}", ip),
+    }
+}
+
+pub fn record(action: &str, actor: &str, keys: &[String]) {
+    slog::info!(
+        AUDIT_LOG, "{}", action;
+        "action" => action,
+        "actor" => actor,
+        "keys" => keys.join(","),
+    );
+}
@@ -0,0 +1,233 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, http, Error};
+use futures::future::{ok, Ready};
+use futures::Future;
+
+use crate::errors::ServiceError;
+use crate::CONFIG;
+
+// where api keys can be given
+const API_KEY_HEADER: &str = "x-api-key";
+pub const API_KEY_PARAM: &str = "api_key";
+
+lazy_static::lazy_static! {
+    // `Basic` credentials as they'd appear in the header, so they can
+    // be compared without decoding what's sent
+    static ref BASIC_CREDENTIALS: Vec<String> = CONFIG
+        .basic_auth_users
+        .iter()
+        .map(|user| crate::text::base64(user.as_bytes()))
+        .collect();
+}
+
+/// The parts of the api that can be opened or locked down together
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Group {
+    Admin,
+    Reset,
+    Debug,
+    Api,
+    // liveness checks, never worth locking away from load balancers
+    Health,
+    // badges, and everything else
+    Badges,
+}
+impl Group {
+    pub const ALL: &'static [Group] = &[
+        Group::Admin,
+        Group::Reset,
+        Group::Debug,
+        Group::Api,
+        Group::Health,
+        Group::Badges,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Group::Admin => "admin",
+            Group::Reset => "reset",
+            Group::Debug => "debug",
+            Group::Api => "api",
+            Group::Health => "health",
+            Group::Badges => "badges",
+        }
+    }
+
+    pub fn for_path(path: &str) -> Group {
+        let first = path.trim_start_matches('/').split('/').next().unwrap_or("");
+        match first {
+            "admin" => Group::Admin,
+            "purge" => Group::Reset,
+            // `/reset` on its own is the reset page
+            "reset" if path.len() > "/reset".len() => Group::Reset,
+            "debug" => Group::Debug,
+            "api" | "openapi.json" | "stats" | "metrics" => Group::Api,
+            "status" | "health" => Group::Health,
+            _ => Group::Badges,
+        }
+    }
+}
+impl std::str::FromStr for Group {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim().to_lowercase();
+        Group::ALL
+            .iter()
+            .find(|g| g.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("unknown route group: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Requirement {
+    Open,
+    Required,
+}
+impl std::str::FromStr for Requirement {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "open" => Ok(Requirement::Open),
+            "required" => Ok(Requirement::Required),
+            _ => anyhow::bail!("unknown auth requirement: {}", s),
+        }
+    }
+}
+
+pub fn requirement(group: Group) -> Requirement {
+    CONFIG
+        .auth_route_groups
+        .iter()
+        .find(|(g, _)| *g == group)
+        .map(|(_, r)| *r)
+        .unwrap_or(Requirement::Open)
+}
+
+/// The configured api key the request was made with, if any
+fn api_key(req: &ServiceRequest) -> Option<&'static str> {
+    if CONFIG.api_keys.is_empty() {
+        return None;
+    }
+    let header = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let param = req.query_string().split('&').find_map(|p| {
+        let mut kv = p.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(API_KEY_PARAM), Some(v)) => Some(v),
+            _ => None,
+        }
+    });
+    header.into_iter().chain(param).find_map(|given| {
+        CONFIG
+            .api_keys
+            .iter()
+            .find(|key| crate::signing::constant_time_eq(given, key))
+            .map(String::as_str)
+    })
+}
+
+fn basic_authorized(req: &ServiceRequest) -> bool {
+    let given = match req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+    {
+        Some(given) => given.trim(),
+        None => return false,
+    };
+    BASIC_CREDENTIALS
+        .iter()
+        .any(|expected| crate::signing::constant_time_eq(given, expected))
+}
+
+/// Require an api key or basic auth credentials on the route groups
+/// `AUTH_ROUTE_GROUPS` marks as required. This is on top of the tokens
+/// the admin and debug endpoints check themselves.
+pub struct Auth;
+impl Auth {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl<S, B> Transform<S> for Auth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthMiddleware { service })
+    }
+}
+
+pub struct AuthMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let group = Group::for_path(req.path());
+        let key = api_key(&req);
+        if requirement(group) == Requirement::Required && key.is_none() && !basic_authorized(&req) {
+            crate::metrics::inc("auth_rejections_total", &[("group", group.as_str())]);
+            let resp = req.error_response(ServiceError::Unauthenticated);
+            return Box::pin(async move { Ok(resp) });
+        }
+        // every request made with a key counts against its quota,
+        // whether or not its route group needs one
+        let quota = match key.map(crate::quota::record).transpose() {
+            Ok(quota) => quota.flatten(),
+            Err(e) => {
+                crate::metrics::inc("quota_rejections_total", &[("group", group.as_str())]);
+                let resp = req.error_response(e);
+                return Box::pin(async move { Ok(resp) });
+            }
+        };
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some((limit, remaining)) = quota {
+                let headers = res.headers_mut();
+                headers.insert(
+                    http::HeaderName::from_static("x-ratelimit-limit"),
+                    http::HeaderValue::from(limit),
+                );
+                headers.insert(
+                    http::HeaderName::from_static("x-ratelimit-remaining"),
+                    http::HeaderValue::from(remaining),
+                );
+            }
+            Ok(res)
+        })
+    }
+}
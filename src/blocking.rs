@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use actix_web::error::BlockingError;
+use actix_web::web;
+
+use crate::errors::{self, ServiceError};
+use crate::CONFIG;
+
+// actix sizes its blocking pool from this the first time the pool is used
+const POOL_SIZE_VAR: &str = "ACTIX_THREADPOOL";
+
+static IN_FLIGHT: AtomicI64 = AtomicI64::new(0);
+
+/// Size the blocking pool. This has to run before anything touches the
+/// pool, including actix-files streaming a file's body.
+pub fn initialize() {
+    if CONFIG.blocking_io_threads > 0 {
+        std::env::set_var(POOL_SIZE_VAR, CONFIG.blocking_io_threads.to_string());
+    }
+}
+
+/// Counts a call as in flight for as long as it's held, so calls whose
+/// request goes away while they wait are still counted out
+struct InFlight;
+impl InFlight {
+    fn begin() -> Self {
+        let in_flight = IN_FLIGHT.fetch_add(1, Ordering::SeqCst) + 1;
+        crate::metrics::set_gauge("blocking_io_in_flight", &[], in_flight as f64);
+        InFlight
+    }
+}
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let in_flight = IN_FLIGHT.fetch_sub(1, Ordering::SeqCst) - 1;
+        crate::metrics::set_gauge("blocking_io_in_flight", &[], in_flight as f64);
+    }
+}
+
+/// Run blocking file io on the blocking pool instead of a worker thread.
+/// The time reported includes any wait for a free thread, so a pool
+/// that's too small shows up as latency here rather than on every request.
+pub async fn run<F, T>(op: &'static str, f: F) -> errors::Result<T>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let start = std::time::Instant::now();
+    let in_flight = InFlight::begin();
+    let result = web::block(f).await;
+    drop(in_flight);
+    crate::metrics::observe(
+        "blocking_io_duration_seconds",
+        &[("op", op)],
+        start.elapsed().as_secs_f64(),
+    );
+    match result {
+        Ok(v) => Ok(v),
+        Err(BlockingError::Error(e)) => Err(ServiceError::CacheIo(e)),
+        Err(BlockingError::Canceled) => Err(ServiceError::internal("blocking io canceled")),
+    }
+}
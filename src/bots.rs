@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{http, HttpRequest};
+
+use crate::CONFIG;
+
+lazy_static::lazy_static! {
+    // when each throttled user-agent pattern may next be let through
+    static ref NEXT_ALLOWED: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+pub enum Verdict {
+    Allow,
+    Deny,
+    Throttle { retry_after: Duration },
+}
+
+/// The first configured pattern that's a case-insensitive substring of `user_agent`
+fn matching<'a>(patterns: &'a [String], user_agent: &str) -> Option<&'a str> {
+    patterns
+        .iter()
+        .find(|p| user_agent.contains(p.as_str()))
+        .map(|p| p.as_str())
+}
+
+/// Space out requests from user-agents matching `pattern`, all of
+/// them sharing a single `BOT_THROTTLE_RPS` budget per pattern
+fn throttle(pattern: &str) -> Verdict {
+    if CONFIG.bot_throttle_rps <= 0. {
+        return Verdict::Allow;
+    }
+    let interval = Duration::from_secs_f64(1. / CONFIG.bot_throttle_rps);
+    let mut next = NEXT_ALLOWED.lock().expect("bot throttle lock poisoned");
+    let now = Instant::now();
+    match next.get(pattern) {
+        Some(at) if *at > now => Verdict::Throttle {
+            retry_after: *at - now,
+        },
+        _ => {
+            next.insert(pattern.to_string(), now + interval);
+            Verdict::Allow
+        }
+    }
+}
+
+/// Check a badge request's user-agent against the deny and throttle
+/// patterns, before it gets anywhere near the cache or upstream
+pub fn check(request: &HttpRequest) -> Verdict {
+    if CONFIG.bot_deny_patterns.is_empty() && CONFIG.bot_throttle_patterns.is_empty() {
+        return Verdict::Allow;
+    }
+    let user_agent = request
+        .headers()
+        .get(http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_lowercase();
+    if let Some(pattern) = matching(&CONFIG.bot_deny_patterns, &user_agent) {
+        crate::metrics::inc(
+            "bot_requests_blocked_total",
+            &[("action", "deny"), ("pattern", pattern)],
+        );
+        return Verdict::Deny;
+    }
+    if let Some(pattern) = matching(&CONFIG.bot_throttle_patterns, &user_agent) {
+        let verdict = throttle(pattern);
+        if let Verdict::Throttle { .. } = verdict {
+            crate::metrics::inc(
+                "bot_requests_blocked_total",
+                &[("action", "throttle"), ("pattern", pattern)],
+            );
+        }
+        return verdict;
+    }
+    Verdict::Allow
+}
@@ -0,0 +1,109 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::cache::{self, CachedFile, INDEX_FILE};
+
+// zstd's default level
+const COMPRESSION_LEVEL: i32 = 0;
+
+fn read_index(path: &Path) -> anyhow::Result<Vec<CachedFile>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)
+            .with_context(|| format!("unreadable cache index {:?}", path))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Bundle the persisted cache index, and the content it references, into
+/// a `.tar.zst` at `out`. Expired entries and ones whose content isn't on
+/// disk are left out. Returns the number of entries exported.
+pub fn export(out: &Path) -> anyhow::Result<usize> {
+    let dir = cache::cache_dir();
+    let now = cache::now_millis();
+    let entries = read_index(&dir.join(INDEX_FILE))?
+        .into_iter()
+        .filter(|e| !cache::is_expired(e, now))
+        .filter(|e| {
+            e.file_name()
+                .map(|f| dir.join(f).is_file())
+                .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+
+    let file = fs::File::create(out).with_context(|| format!("unable to create {:?}", out))?;
+    let mut tar = tar::Builder::new(zstd::Encoder::new(file, COMPRESSION_LEVEL)?);
+    let index = serde_json::to_vec(&entries)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(index.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime((now / 1000) as u64);
+    header.set_cksum();
+    tar.append_data(&mut header, INDEX_FILE, index.as_slice())?;
+
+    // content is shared between entries, only bundle each file once
+    let mut bundled = HashSet::new();
+    for file_name in entries.iter().filter_map(|e| e.file_name()) {
+        if bundled.insert(file_name.clone()) {
+            tar.append_path_with_name(dir.join(&file_name), &file_name)?;
+        }
+    }
+    tar.into_inner()?.finish()?;
+    Ok(entries.len())
+}
+
+/// Unpack a bundle made by `export` into the cache dir, merging its index
+/// with any existing one. Where both have an entry for a key, the more
+/// recently fetched one is kept. Imported entries are picked up on the next
+/// startup, which requires PERSIST_INDEX. Returns the number of entries imported.
+pub fn import(input: &Path) -> anyhow::Result<usize> {
+    let dir = cache::cache_dir();
+    fs::create_dir_all(&dir)?;
+    let file = fs::File::open(input).with_context(|| format!("unable to open {:?}", input))?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+
+    let mut imported = None;
+    for file in archive.entries()? {
+        let mut file = file?;
+        let name = file.path()?.to_string_lossy().to_string();
+        if name == INDEX_FILE {
+            let mut bytes = vec![];
+            file.read_to_end(&mut bytes)?;
+            imported = Some(
+                serde_json::from_slice::<Vec<CachedFile>>(&bytes)
+                    .context("unreadable index in bundle")?,
+            );
+            continue;
+        }
+        // content files are stored flat, named by their hash
+        if name.contains('/') || name.contains('\\') || name.starts_with('.') {
+            anyhow::bail!("unexpected file in bundle: {}", name);
+        }
+        file.unpack(dir.join(&name))?;
+    }
+    let imported = imported.ok_or_else(|| anyhow::anyhow!("no index in bundle {:?}", input))?;
+    let count = imported.len();
+
+    let index_path = dir.join(INDEX_FILE);
+    let mut merged = read_index(&index_path)?
+        .into_iter()
+        .map(|e| (e.cache_name.clone(), e))
+        .collect::<HashMap<_, _>>();
+    for entry in imported {
+        match merged.get(&entry.cache_name) {
+            Some(existing) if existing.created_millis >= entry.created_millis => (),
+            _ => {
+                merged.insert(entry.cache_name.clone(), entry);
+            }
+        }
+    }
+    let merged = merged.into_values().collect::<Vec<_>>();
+    let tmp_path = index_path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&merged)?)?;
+    fs::rename(&tmp_path, &index_path)?;
+    Ok(count)
+}
@@ -0,0 +1,2119 @@
+use actix_web::rt;
+use async_mutex::Mutex;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::clock::{Clock, SystemClock};
+use crate::errors::{self, ServiceError};
+use crate::service::{Kind, Params};
+use crate::{CONFIG, LOG};
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CachedFile {
+    pub cache_name: String,
+    // what was requested, for listing entries. empty for entries
+    // restored from an index written before these were recorded
+    #[serde(default)]
+    pub kind: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub upstream_url: String,
+    pub created_millis: u128,
+    // per-entry ttl, jittered so entries fetched together don't all expire together
+    pub ttl_millis: u128,
+    pub ext: String,
+    // sha256 of the badge body, content is stored content-addressed
+    // so identical badges across keys share a single copy
+    pub content_hash: Option<String>,
+    // upstream's etag for the content, used to revalidate
+    #[serde(default)]
+    pub upstream_etag: Option<String>,
+    // applied to upstream's svg before it's stored
+    #[serde(default)]
+    pub theme: Option<crate::processing::Theme>,
+    // a local logo embedded in upstream's svg before it's stored
+    #[serde(default)]
+    pub logo: Option<String>,
+}
+impl CachedFile {
+    /// The content-addressed name this entry's body is stored under
+    pub fn file_name(&self) -> Option<String> {
+        self.content_hash
+            .as_ref()
+            .map(|hash| format!("{}.{}", hash, self.ext))
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref CACHE: Mutex<HashMap<String, Arc<Mutex<CachedFile>>>> = {
+        Mutex::new(HashMap::with_capacity(512))
+    };
+
+    pub static ref TIERS: Vec<Box<dyn CacheTier + Send + Sync>> = load_tiers();
+
+    pub static ref CLOCK: Box<dyn Clock + Send + Sync> = Box::new(SystemClock);
+
+    // Immutable copies of entries with content, read on the hit path
+    // without touching the CACHE or entry locks so hits never wait on
+    // misses or cleanup. Kept in sync with CACHE via `publish`/`unpublish`.
+    static ref SNAPSHOTS: dashmap::DashMap<String, Arc<CachedFile>> = dashmap::DashMap::new();
+
+    // Content file name -> keys whose snapshots point at it, so content
+    // can be found by name without scanning SNAPSHOTS. Kept with it.
+    static ref CONTENT_KEYS: dashmap::DashMap<String, HashSet<String>> = dashmap::DashMap::new();
+
+    // Freshly fetched content that's still being written out to the tiers,
+    // keyed by content file name
+    static ref PENDING: std::sync::Mutex<HashMap<String, Bytes>> = {
+        std::sync::Mutex::new(HashMap::new())
+    };
+
+    // Keys upstream doesn't know about, mapped to when we'll ask again
+    static ref NEGATIVE: dashmap::DashMap<String, u128> = dashmap::DashMap::new();
+
+    // keys upstream rate limited us on, mapped to when we can ask again
+    static ref RATE_LIMITED: dashmap::DashMap<String, u128> = dashmap::DashMap::new();
+
+    // Entries cleanup dropped from the index whose content is kept on
+    // disk for DISK_RETENTION_MILLIS past their ttl, to refill the index
+    // with a revalidation instead of a full fetch
+    static ref RETIRED: dashmap::DashMap<String, CachedFile> = dashmap::DashMap::new();
+
+    // Number of times each entry has been served from the cache
+    static ref HITS: dashmap::DashMap<String, u64> = dashmap::DashMap::new();
+}
+
+// where the index is persisted across restarts, relative to the cache dir
+pub const INDEX_FILE: &str = "index.json";
+
+fn forget_content_key(snapshot: &CachedFile) {
+    let file_name = match snapshot.file_name() {
+        Some(file_name) => file_name,
+        None => return,
+    };
+    let empty = match CONTENT_KEYS.get_mut(&file_name) {
+        Some(mut keys) => {
+            keys.remove(&snapshot.cache_name);
+            keys.is_empty()
+        }
+        None => false,
+    };
+    if empty {
+        CONTENT_KEYS.remove_if(&file_name, |_, keys| keys.is_empty());
+    }
+}
+
+fn publish(entry: &CachedFile) {
+    let previous = SNAPSHOTS.insert(entry.cache_name.clone(), Arc::new(entry.clone()));
+    if let Some(previous) = previous {
+        if previous.file_name() != entry.file_name() {
+            forget_content_key(&previous);
+        }
+    }
+    if let Some(file_name) = entry.file_name() {
+        CONTENT_KEYS
+            .entry(file_name)
+            .or_default()
+            .insert(entry.cache_name.clone());
+    }
+}
+
+fn unpublish(cache_name: &str) {
+    if let Some((_, snapshot)) = SNAPSHOTS.remove(cache_name) {
+        forget_content_key(&snapshot);
+    }
+    HITS.remove(cache_name);
+}
+
+fn record_hit(cache_name: &str) {
+    *HITS.entry(cache_name.to_string()).or_insert(0) += 1;
+}
+
+pub fn hits(cache_name: &str) -> u64 {
+    HITS.get(cache_name).map(|h| *h.value()).unwrap_or(0)
+}
+
+/// Every entry that has content, as of when each was last published
+pub fn entries() -> Vec<Arc<CachedFile>> {
+    SNAPSHOTS.iter().map(|s| s.value().clone()).collect()
+}
+
+/// Set the `cache_keys` gauge to the number of distinct keys per kind
+pub fn record_cardinality() {
+    let mut counts = Kind::ALL
+        .iter()
+        .map(|k| (k.as_str().to_string(), 0))
+        .collect::<HashMap<_, _>>();
+    for s in SNAPSHOTS.iter() {
+        // entries restored from old indexes don't know their kind
+        let kind = if s.value().kind.is_empty() {
+            "unknown".to_string()
+        } else {
+            s.value().kind.clone()
+        };
+        *counts.entry(kind).or_insert(0) += 1;
+    }
+    for (kind, count) in counts.iter() {
+        crate::metrics::set_gauge("cache_keys", &[("kind", kind.as_str())], *count as f64);
+    }
+    crate::metrics::set_gauge("cache_index_bytes", &[], index_bytes() as f64);
+}
+
+fn record_not_found(cache_name: &str) {
+    let until = now_millis() + u128::from(CONFIG.negative_cache_seconds) * 1000;
+    NEGATIVE.insert(cache_name.to_string(), until);
+}
+
+// upper bound on how long an upstream's Retry-After is honored for
+const MAX_RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// How long upstream asked us to wait, either as seconds or an http date.
+/// Without a usable `Retry-After` we hold off as long as for a not-found badge.
+fn retry_after(resp: &reqwest::Response) -> std::time::Duration {
+    let value = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim());
+    let wait = value.and_then(|v| match v.parse::<u64>() {
+        Ok(secs) => Some(std::time::Duration::from_secs(secs)),
+        Err(_) => chrono::DateTime::parse_from_rfc2822(v)
+            .ok()
+            .map(|at| at.signed_duration_since(chrono::Utc::now()))
+            .and_then(|d| d.to_std().ok()),
+    });
+    wait.unwrap_or_else(|| std::time::Duration::from_secs(CONFIG.negative_cache_seconds))
+        .min(MAX_RETRY_AFTER)
+}
+
+fn record_rate_limited(cache_name: &str, wait: std::time::Duration) {
+    let until = now_millis() + wait.as_millis();
+    RATE_LIMITED.insert(cache_name.to_string(), until);
+}
+
+/// How much longer upstream asked us to leave this key alone, if at all
+fn rate_limited_for(cache_name: &str, now: u128) -> Option<std::time::Duration> {
+    RATE_LIMITED
+        .get(cache_name)
+        .map(|until| *until.value())
+        .filter(|until| *until > now)
+        .map(|until| std::time::Duration::from_millis((until - now) as u64))
+}
+
+fn is_known_not_found(cache_name: &str, now: u128) -> bool {
+    NEGATIVE
+        .get(cache_name)
+        .map(|until| *until.value() > now)
+        .unwrap_or(false)
+}
+
+pub fn is_expired(entry: &CachedFile, now: u128) -> bool {
+    now.saturating_sub(entry.created_millis) > entry.ttl_millis
+}
+
+/// Badge content as returned by a cache tier
+#[derive(Debug, Clone)]
+pub enum Content {
+    Bytes(Bytes),
+    File(PathBuf),
+}
+impl Content {
+    /// Where cached content was served from, files only come from disk
+    pub fn tier(&self) -> &'static str {
+        match self {
+            Content::Bytes(_) => "memory",
+            Content::File(_) => "disk",
+        }
+    }
+}
+
+/// A level of the badge cache. Lookups go through the enabled
+/// tiers in order (memory -> disk), falling back to upstream,
+/// and content found in a lower tier is written back to the
+/// tiers above it.
+#[async_trait::async_trait]
+pub trait CacheTier {
+    fn name(&self) -> &'static str;
+
+    async fn get(&self, entry: &CachedFile) -> errors::Result<Option<Content>>;
+
+    async fn put(&self, entry: &CachedFile, bytes: &Bytes) -> errors::Result<()>;
+
+    /// Drop any stored content that isn't in the `referenced` set of file names,
+    /// or only log what would be dropped when `dry_run` is set
+    async fn sweep(&self, referenced: &HashSet<String>, dry_run: bool) -> errors::Result<()>;
+}
+
+fn load_tiers() -> Vec<Box<dyn CacheTier + Send + Sync>> {
+    let mut tiers: Vec<Box<dyn CacheTier + Send + Sync>> = vec![];
+    for name in CONFIG.cache_tiers.iter() {
+        match name.as_str() {
+            "memory" => tiers.push(Box::new(MemoryTier::new())),
+            "disk" => tiers.push(Box::new(DiskTier::new())),
+            other => panic!("invalid cache tier: {}", other),
+        }
+    }
+    tiers
+}
+
+pub struct MemoryTier {
+    contents: std::sync::Mutex<HashMap<String, Bytes>>,
+}
+impl MemoryTier {
+    pub fn new() -> Self {
+        Self {
+            contents: std::sync::Mutex::new(HashMap::with_capacity(512)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheTier for MemoryTier {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn get(&self, entry: &CachedFile) -> errors::Result<Option<Content>> {
+        let file_name = match entry.file_name() {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        let contents = self.contents.lock().expect("memory tier lock poisoned");
+        Ok(contents.get(&file_name).cloned().map(Content::Bytes))
+    }
+
+    async fn put(&self, entry: &CachedFile, bytes: &Bytes) -> errors::Result<()> {
+        if let Some(file_name) = entry.file_name() {
+            let mut contents = self.contents.lock().expect("memory tier lock poisoned");
+            contents.insert(file_name, bytes.clone());
+        }
+        Ok(())
+    }
+
+    async fn sweep(&self, referenced: &HashSet<String>, dry_run: bool) -> errors::Result<()> {
+        let mut contents = self.contents.lock().expect("memory tier lock poisoned");
+        if dry_run {
+            let stale = contents.keys().filter(|k| !referenced.contains(*k)).count();
+            slog::info!(
+                LOG,
+                "dry run: would remove {} stale items from memory",
+                stale
+            );
+            return Ok(());
+        }
+        let before = contents.len();
+        contents.retain(|k, _| referenced.contains(k));
+        slog::info!(
+            LOG,
+            "removed {} stale items from memory",
+            before - contents.len()
+        );
+        Ok(())
+    }
+}
+
+/// The directory content is stored in, which can be switched at runtime.
+/// After a switch, content that's only in the previous directory is either
+/// copied over when it's next read or left behind and fetched again.
+struct CacheDirs {
+    current: PathBuf,
+    previous: Option<PathBuf>,
+    migrate: bool,
+}
+
+/// Where content and the index go under a cache dir. Deployments with
+/// different namespaces can share a volume without their entries, or
+/// their cleanup, touching each other's.
+fn namespaced(dir: PathBuf) -> PathBuf {
+    match &CONFIG.cache_namespace {
+        Some(namespace) => dir.join(namespace),
+        None => dir,
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref CACHE_DIRS: std::sync::RwLock<CacheDirs> = std::sync::RwLock::new(CacheDirs {
+        current: namespaced(PathBuf::from(&CONFIG.cache_dir)),
+        previous: None,
+        migrate: false,
+    });
+}
+
+/// The directory content and the index are currently stored in
+pub fn cache_dir() -> PathBuf {
+    CACHE_DIRS
+        .read()
+        .expect("cache dirs lock poisoned")
+        .current
+        .clone()
+}
+
+/// Start storing content in `dir`, e.g. one that was warmed up offline.
+/// Returns the directory that was in use before.
+pub async fn switch_cache_dir(dir: &str, migrate: bool) -> errors::Result<PathBuf> {
+    let dir = PathBuf::from(dir);
+    match tokio::fs::metadata(&dir).await {
+        Ok(m) if m.is_dir() => (),
+        _ => return Err(ServiceError::Parse(format!("not a directory: {:?}", dir))),
+    }
+    let dir = namespaced(dir);
+    tokio::fs::create_dir_all(&dir).await?;
+    let mut dirs = CACHE_DIRS.write().expect("cache dirs lock poisoned");
+    let previous = std::mem::replace(&mut dirs.current, dir);
+    slog::info!(
+        LOG,
+        "switched cache dir {:?} -> {:?}, migrate: {}",
+        previous,
+        dirs.current,
+        migrate
+    );
+    dirs.previous = Some(previous.clone());
+    dirs.migrate = migrate;
+    Ok(previous)
+}
+
+pub struct DiskTier;
+impl DiskTier {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheTier for DiskTier {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    async fn get(&self, entry: &CachedFile) -> errors::Result<Option<Content>> {
+        let file_name = match entry.file_name() {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        let (current, previous) = {
+            let dirs = CACHE_DIRS.read().expect("cache dirs lock poisoned");
+            let previous = if dirs.migrate {
+                dirs.previous.clone()
+            } else {
+                None
+            };
+            (dirs.current.clone(), previous)
+        };
+        // the file may have been swept out from under us
+        let path = current.join(&file_name);
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Ok(Some(Content::File(path)));
+        }
+        if let Some(previous) = previous {
+            let old_path = previous.join(&file_name);
+            if tokio::fs::metadata(&old_path).await.is_ok() {
+                if CONFIG.read_only {
+                    return Ok(Some(Content::File(old_path)));
+                }
+                let bytes = tokio::fs::read(&old_path).await?;
+                _write_content_file(&bytes, &path).await?;
+                crate::metrics::inc("cache_dir_migrated_total", &[]);
+                return Ok(Some(Content::File(path)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn put(&self, entry: &CachedFile, bytes: &Bytes) -> errors::Result<()> {
+        if let Some(file_name) = entry.file_name() {
+            _write_content_file(bytes, &cache_dir().join(file_name)).await?;
+        }
+        Ok(())
+    }
+
+    async fn sweep(&self, referenced: &HashSet<String>, dry_run: bool) -> errors::Result<()> {
+        // retired entries' files outlive them, only on disk
+        let mut referenced = referenced.clone();
+        referenced.extend(RETIRED.iter().filter_map(|r| r.value().file_name()));
+        // the previous dir, if any, is left for the operator to remove
+        cleanup_cache_dir(&cache_dir(), &referenced, dry_run).await
+    }
+}
+
+/// Write badge content to its content-addressed path, skipping
+/// the write entirely if another key already stored the same bytes.
+async fn _write_content_file(bytes: &[u8], file_path: &Path) -> errors::Result<()> {
+    if tokio::fs::metadata(file_path).await.is_ok() {
+        slog::debug!(LOG, "badge content already stored: {:?}", file_path);
+        return Ok(());
+    }
+
+    // write to a temp file and rename so concurrent readers of
+    // a shared content file never see a partial write. the temp name is
+    // unique since keys sharing content can write the same file at once.
+    let tmp_path = file_path.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+    use tokio::io::AsyncWriteExt;
+    let written = async {
+        crate::faults::disk()?;
+        let mut f = tokio::fs::File::create(&tmp_path).await?;
+        f.write_all(bytes).await?;
+        f.flush().await?;
+        tokio::fs::rename(&tmp_path, file_path).await
+    }
+    .await;
+    if let Err(e) = written {
+        // don't leave partial files around, especially if the disk is full
+        tokio::fs::remove_file(&tmp_path).await.ok();
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Content file names in use, read from the snapshots and
+/// pending writes so no cache or entry locks are needed.
+/// Snapshots of the `excluding` keys don't count.
+fn referenced_file_names(excluding: &HashSet<String>) -> HashSet<String> {
+    let mut names = SNAPSHOTS
+        .iter()
+        .filter(|s| !excluding.contains(s.key()))
+        .filter_map(|s| s.value().file_name())
+        .collect::<HashSet<_>>();
+    let pending = PENDING.lock().expect("pending lock poisoned");
+    names.extend(pending.keys().cloned());
+    names
+}
+
+const RECENT_WRITE_GRACE: std::time::Duration = std::time::Duration::from_secs(60);
+
+// where files that could never be content are moved when ORPHAN_QUARANTINE is set
+const ORPHAN_DIR: &str = "orphaned";
+
+/// Whether `file_name` is one content could be stored under,
+/// a sha256 hex digest and an extension
+fn is_content_file_name(file_name: &str) -> bool {
+    let mut parts = file_name.splitn(2, '.');
+    let hash = parts.next().unwrap_or("");
+    let ext = parts.next().unwrap_or("");
+    hash.len() == 64
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        && !ext.is_empty()
+        && ext.len() <= CONFIG.max_ext_length
+        && ext.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Move a file that doesn't belong in the cache dir into quarantine,
+/// or delete it outright if quarantine is off
+async fn handle_orphan(dir: &Path, path: &Path, file_name: &str, dry_run: bool) {
+    if dry_run {
+        let action = if CONFIG.orphan_quarantine {
+            "quarantine"
+        } else {
+            "remove"
+        };
+        slog::info!(LOG, "dry run: would {} orphaned file: {:?}", action, path);
+        return;
+    }
+    let result = if CONFIG.orphan_quarantine {
+        let quarantine = dir.join(ORPHAN_DIR);
+        slog::warn!(LOG, "quarantining orphaned file: {:?}", path);
+        match tokio::fs::create_dir_all(&quarantine).await {
+            Ok(_) => tokio::fs::rename(path, quarantine.join(file_name))
+                .await
+                .map(|_| "quarantined"),
+            Err(e) => Err(e),
+        }
+    } else {
+        slog::warn!(LOG, "removing orphaned file: {:?}", path);
+        tokio::fs::remove_file(path).await.map(|_| "removed")
+    };
+    match result {
+        Ok(action) => crate::metrics::inc("cache_orphans_total", &[("action", action)]),
+        Err(e) => slog::error!(LOG, "failed handling orphaned file: {:?}, {:?}", path, e),
+    }
+}
+
+/// Delete quarantined files once they've been there `ORPHAN_QUARANTINE_SECONDS`
+async fn purge_quarantine(dir: &Path, dry_run: bool) -> errors::Result<()> {
+    let quarantine = dir.join(ORPHAN_DIR);
+    let mut reader = match tokio::fs::read_dir(&quarantine).await {
+        Ok(reader) => reader,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let retention = std::time::Duration::from_secs(CONFIG.orphan_quarantine_seconds);
+    let mut remaining = 0;
+    while let Some(entry) = reader.next_entry().await? {
+        let expired = entry
+            .metadata()
+            .await
+            .ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.elapsed().ok())
+            .map(|age| age >= retention)
+            .unwrap_or(false);
+        if !expired {
+            remaining += 1;
+            continue;
+        }
+        if dry_run {
+            remaining += 1;
+            slog::info!(
+                LOG,
+                "dry run: would purge quarantined file: {:?}",
+                entry.path()
+            );
+            continue;
+        }
+        match tokio::fs::remove_file(entry.path()).await {
+            Ok(_) => crate::metrics::inc("cache_orphans_total", &[("action", "purged")]),
+            Err(e) => {
+                remaining += 1;
+                slog::error!(
+                    LOG,
+                    "failed purging quarantined file: {:?}, {:?}",
+                    entry.path(),
+                    e
+                );
+            }
+        }
+    }
+    crate::metrics::set_gauge("cache_orphans_quarantined", &[], remaining as f64);
+    Ok(())
+}
+
+async fn cleanup_cache_dir(
+    dir: &Path,
+    referenced: &HashSet<String>,
+    dry_run: bool,
+) -> errors::Result<()> {
+    use futures::stream::StreamExt;
+    slog::info!(LOG, "cleaning cache dir: {:?}", dir);
+    let reader = tokio::fs::read_dir(dir).await?;
+
+    reader
+        .for_each(|entry| async {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    slog::error!(LOG, "failed unwraping dir entry: {:?}", e);
+                    return;
+                }
+            };
+            let path = entry.path();
+            if entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false) {
+                return;
+            }
+            let file_name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(e) => {
+                    slog::error!(LOG, "failed converting filename to string: {:?}", e);
+                    return;
+                }
+            };
+            if file_name == ".gitkeep" || file_name == INDEX_FILE || file_name.ends_with(".tmp") {
+                return;
+            }
+
+            // give files that were just written a grace period, the entries
+            // referencing them may not have been published yet
+            let recently_written = entry
+                .metadata()
+                .await
+                .ok()
+                .and_then(|m| m.modified().ok())
+                .and_then(|t| t.elapsed().ok())
+                .map(|age| age < RECENT_WRITE_GRACE)
+                .unwrap_or(false);
+
+            if recently_written {
+                return;
+            }
+
+            // anything that isn't named like content was put here by hand or
+            // by an older version, and would otherwise only go if it happened
+            // to not match a reference
+            if !is_content_file_name(&file_name) {
+                handle_orphan(dir, &path, &file_name, dry_run).await;
+                return;
+            }
+
+            // file names are content hashes, shared by any number of cache entries
+            if !referenced.contains(&file_name) {
+                if dry_run {
+                    slog::info!(LOG, "dry run: would remove stale cached file: {:?}", path);
+                    return;
+                }
+                // If nothing in the cache references it, then delete the file.
+                // This means most things will be deleted on startup.
+                slog::info!(LOG, "removing stale cached file: {}, {:?}", file_name, path);
+                match tokio::fs::remove_file(&path).await {
+                    Ok(_) => (),
+                    Err(e) => {
+                        slog::error!(LOG, "failed removing stale file: {:?}, {:?}", path, e);
+                    }
+                }
+            }
+        })
+        .await;
+    purge_quarantine(dir, dry_run).await
+}
+
+// how many entries cleanup examines before yielding to other tasks
+const CLEANUP_BATCH_SIZE: usize = 256;
+
+/// Hold on to an expired entry's disk content for `DISK_RETENTION_MILLIS`
+fn retire(entry: &CachedFile) {
+    let on_disk = CONFIG.cache_tiers.iter().any(|t| t == "disk");
+    if CONFIG.disk_retention_millis == 0 || !on_disk || entry.content_hash.is_none() {
+        return;
+    }
+    RETIRED.insert(entry.cache_name.clone(), entry.clone());
+}
+
+fn retirement_over(entry: &CachedFile, now: u128) -> bool {
+    now.saturating_sub(entry.created_millis) > entry.ttl_millis + CONFIG.disk_retention_millis
+}
+
+/// Drop expired entries and sweep the tiers of content nothing references.
+/// With `dry_run`, only log what would be dropped.
+async fn cleanup_pass(dry_run: bool) {
+    slog::info!(LOG, "cleaning stale items"; "dry_run" => dry_run);
+
+    let now = now_millis();
+    let would_remove = invalidate_expired(now, dry_run).await;
+
+    // expired bookkeeping goes even on a dry run, it's never served
+    // and would otherwise grow for as long as dry runs are on
+    let before = NEGATIVE.len();
+    NEGATIVE.retain(|_, until| *until > now);
+    slog::info!(
+        LOG,
+        "removed {} expired not-found entries",
+        before - NEGATIVE.len()
+    );
+    RATE_LIMITED.retain(|_, until| *until > now);
+    RETIRED.retain(|_, entry| !retirement_over(entry, now));
+
+    let referenced = referenced_file_names(&would_remove);
+    for tier in TIERS.iter() {
+        tier.sweep(&referenced, dry_run)
+            .await
+            .map_err(|e| {
+                slog::error!(LOG, "error cleaning {} tier {:?}", tier.name(), e);
+            })
+            .ok();
+    }
+}
+
+/// Drop the entries expired as of `now` from the index. With `dry_run`,
+/// only log them and return their keys, so the sweep can act as
+/// though they're gone.
+async fn invalidate_expired(now: u128, dry_run: bool) -> HashSet<String> {
+    // snapshot the entries so the global lock is only held briefly
+    let entries = {
+        let cache = CACHE.lock().await;
+        cache
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>()
+    };
+
+    let mut removed_from_cache = 0;
+    let mut would_remove = HashSet::new();
+    for batch in entries.chunks(CLEANUP_BATCH_SIZE) {
+        // entries that are locked are in the middle of being
+        // fetched, so they're about to be fresh anyway
+        let expired = batch
+            .iter()
+            .filter(|(_, v)| v.try_lock().map(|v| is_expired(&v, now)).unwrap_or(false))
+            .collect::<Vec<_>>();
+        if dry_run {
+            for (k, _) in expired {
+                slog::info!(LOG, "dry run: would invalidate cached item: {}", k);
+                would_remove.insert(k.clone());
+            }
+        } else if !expired.is_empty() {
+            let mut cache = CACHE.lock().await;
+            for (k, v) in expired {
+                // make sure it's the same entry and that it wasn't
+                // refreshed since we looked at it
+                let same = cache.get(k).map(|c| Arc::ptr_eq(c, v)).unwrap_or(false);
+                let still_expired = v.try_lock().map(|v| is_expired(&v, now)).unwrap_or(false);
+                if same && still_expired {
+                    slog::info!(LOG, "invalidating cached item: {}", k);
+                    if let Some(entry) = v.try_lock() {
+                        retire(&entry);
+                    }
+                    index_remove(&mut cache, k);
+                    unpublish(k);
+                    removed_from_cache += 1;
+                }
+            }
+        }
+        tokio::task::yield_now().await;
+    }
+    if dry_run {
+        slog::info!(
+            LOG,
+            "dry run: would remove {} stale items from cache",
+            would_remove.len()
+        );
+    } else {
+        slog::info!(LOG, "removed {} stale items from cache", removed_from_cache);
+    }
+    would_remove
+}
+
+async fn cleanup() {
+    let start =
+        rt::time::Instant::now() + std::time::Duration::from_secs(CONFIG.cleanup_delay_seconds);
+    let mut interval = rt::time::interval_at(
+        start,
+        std::time::Duration::from_secs(CONFIG.cleanup_interval_seconds),
+    );
+    loop {
+        interval.tick().await;
+        cleanup_pass(CONFIG.cleanup_dry_run).await;
+        crate::health::record_cleanup();
+    }
+}
+
+/// Run the cleanup loop, restarting it with backoff if it ever panics
+pub async fn supervise_cleanup() {
+    use futures::FutureExt;
+    let min_backoff = std::time::Duration::from_secs(1);
+    let max_backoff = std::time::Duration::from_secs(5 * 60);
+    let mut backoff = min_backoff;
+    loop {
+        let started = crate::health::last_cleanup_millis();
+        let result = std::panic::AssertUnwindSafe(cleanup()).catch_unwind().await;
+        if let Err(e) = result {
+            let msg = e
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| e.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            slog::error!(LOG, "cleanup task panicked: {}", msg);
+        }
+        crate::health::record_cleanup_restart();
+        crate::metrics::inc("cleanup_restarts_total", &[]);
+
+        // only keep backing off if it's failing before completing a pass
+        if crate::health::last_cleanup_millis() != started {
+            backoff = min_backoff;
+        }
+        slog::info!(LOG, "restarting cleanup task in {:?}", backoff);
+        rt::time::delay_for(backoff).await;
+        backoff = std::cmp::min(backoff * 2, max_backoff);
+    }
+}
+
+/// The result of asking upstream for a badge
+pub enum Fetched {
+    NotModified,
+    Body { bytes: Bytes, etag: Option<String> },
+}
+
+/// Fetch a badge from upstream, conditionally if we have an etag for it
+async fn _fetch_badge(params: &Params, etag: Option<&str>) -> errors::Result<Fetched> {
+    let start = std::time::Instant::now();
+    let fetched = _fetch_url(
+        &params.redirect_url,
+        params.kind.as_str(),
+        &params.cache_name,
+        etag,
+    )
+    .await?;
+    // only full bodies can be compared, a 304 says nothing about the content
+    if let Fetched::Body { bytes, .. } = &fetched {
+        if let Some(url) = crate::shadow::sample(params) {
+            rt::spawn(crate::shadow::compare(
+                params.kind,
+                params.cache_name.clone(),
+                url,
+                content_hash(bytes),
+                start.elapsed(),
+            ));
+        }
+    }
+    Ok(fetched)
+}
+
+async fn _fetch_url(
+    url: &str,
+    kind: &str,
+    cache_name: &str,
+    etag: Option<&str>,
+) -> errors::Result<Fetched> {
+    slog::info!(LOG, "requesting fresh badge {}", url);
+    let start = std::time::Instant::now();
+    let result: errors::Result<(reqwest::StatusCode, Fetched)> = async {
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(etag) = etag {
+            headers.insert(
+                reqwest::header::IF_NONE_MATCH,
+                etag.parse().map_err(ServiceError::internal)?,
+            );
+        }
+        let resp = crate::upstream::get_with_headers(url, headers).await?;
+        let status = resp.status();
+        if status == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok((status, Fetched::NotModified));
+        }
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let wait = retry_after(&resp);
+            slog::warn!(
+                LOG,
+                "rate limited by upstream fetching {}, backing off for {:?}",
+                url,
+                wait
+            );
+            crate::metrics::inc("upstream_rate_limited_total", &[("kind", kind)]);
+            record_rate_limited(cache_name, wait);
+            crate::upstream::back_off(url, wait);
+            return Err(ServiceError::RateLimited(wait));
+        }
+        if !status.is_success() {
+            return Err(ServiceError::UpstreamStatus {
+                url: url.to_string(),
+                status: status.as_u16(),
+            });
+        }
+        let etag = resp
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let bytes = resp.bytes().await?;
+        Ok((status, Fetched::Body { bytes, etag }))
+    }
+    .await;
+    crate::metrics::observe(
+        "upstream_request_duration_seconds",
+        &[("kind", kind)],
+        start.elapsed().as_secs_f64(),
+    );
+    let (status, fetched) = result.map_err(|e| {
+        let status = match &e {
+            ServiceError::UpstreamStatus { status, .. } => status.to_string(),
+            ServiceError::RateLimited(_) => "429".to_string(),
+            _ => "error".to_string(),
+        };
+        crate::metrics::inc(
+            "upstream_requests_total",
+            &[("kind", kind), ("status", status.as_str())],
+        );
+        match e {
+            ServiceError::UpstreamStatus { status: 404, .. }
+            | ServiceError::UpstreamStatus { status: 422, .. } => (),
+            ref e => errors::record(cache_name, e),
+        }
+        match e {
+            // shields doesn't know the crate/badge, remember that for a
+            // little while so clients asking repeatedly don't each hit upstream
+            ServiceError::UpstreamStatus { status: 404, .. }
+            | ServiceError::UpstreamStatus { status: 422, .. } => {
+                record_not_found(cache_name);
+                ServiceError::NotFound(cache_name.to_string())
+            }
+            e => e,
+        }
+    })?;
+    crate::metrics::inc(
+        "upstream_requests_total",
+        &[("kind", kind), ("status", status.as_str())],
+    );
+    Ok(fetched)
+}
+
+async fn _request_badge_bytes(params: &Params) -> errors::Result<Bytes> {
+    match _fetch_badge(params, None).await? {
+        Fetched::Body { bytes, .. } => Ok(bytes),
+        Fetched::NotModified => Err(ServiceError::Internal(
+            "unexpected 304 for unconditional request".to_string(),
+        )),
+    }
+}
+
+pub fn content_hash(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+pub fn now_millis() -> u128 {
+    #[cfg(test)]
+    {
+        if let Some(clock) = crate::clock::installed() {
+            return clock.now_millis();
+        }
+    }
+    CLOCK.now_millis()
+}
+
+/// The kind's configured ttl, randomly spread by +/- `cache_ttl_jitter_percent`
+fn jittered_ttl_millis(kind: &str) -> u128 {
+    use rand::Rng;
+    // kinds can have their own ttl
+    let base = kind
+        .parse::<Kind>()
+        .map(|k| k.ttl_millis())
+        .unwrap_or(CONFIG.cache_ttl_millis);
+    let jitter = CONFIG.cache_ttl_jitter_percent.abs().min(100.) / 100.;
+    if jitter == 0. {
+        return base;
+    }
+    let factor = rand::thread_rng().gen_range((1. - jitter)..=(1. + jitter));
+    (base as f64 * factor) as u128
+}
+
+/// Look through the enabled tiers in order, writing content
+/// back to any tiers above the one it was found in.
+async fn _get_from_tiers(entry: &CachedFile) -> errors::Result<Option<Content>> {
+    if let Some(file_name) = entry.file_name() {
+        let pending = PENDING.lock().expect("pending lock poisoned");
+        if let Some(bytes) = pending.get(&file_name) {
+            slog::debug!(LOG, "pending write hit: {}", entry.cache_name);
+            return Ok(Some(Content::Bytes(bytes.clone())));
+        }
+    }
+    for (i, tier) in TIERS.iter().enumerate() {
+        let content = match tier.get(entry).await? {
+            Some(content) => content,
+            None => continue,
+        };
+        slog::debug!(LOG, "{} tier hit: {}", tier.name(), entry.cache_name);
+        if i > 0 && !CONFIG.read_only {
+            let bytes = match &content {
+                Content::Bytes(b) => b.clone(),
+                Content::File(p) => Bytes::from(tokio::fs::read(p).await?),
+            };
+            for upper in TIERS[..i].iter() {
+                upper.put(entry, &bytes).await?;
+            }
+        }
+        return Ok(Some(content));
+    }
+    Ok(None)
+}
+
+// errno for "no space left on device"
+const ENOSPC: i32 = 28;
+
+fn is_disk_full(e: &ServiceError) -> bool {
+    match e {
+        ServiceError::CacheIo(e) => e.raw_os_error() == Some(ENOSPC),
+        _ => false,
+    }
+}
+
+fn on_disk_full() {
+    if crate::health::record_disk_full() {
+        slog::warn!(
+            LOG,
+            "cache disk is full, skipping the cache for misses for {}s",
+            CONFIG.disk_full_backoff_seconds
+        );
+        crate::metrics::inc("disk_full_total", &[]);
+        rt::spawn(emergency_evict());
+    }
+}
+
+// percentage of entries dropped when the disk fills up
+const EMERGENCY_EVICT_PERCENT: usize = 25;
+
+/// Free up space by dropping the least frequently requested entries
+/// and then running a regular cleanup pass to sweep their content
+async fn emergency_evict() {
+    let mut cache = CACHE.lock().await;
+    let mut candidates = cache
+        .keys()
+        .map(|k| (crate::admission::estimate(k), k.clone()))
+        .collect::<Vec<_>>();
+    candidates.sort();
+    let count = candidates.len() * EMERGENCY_EVICT_PERCENT / 100;
+    for (_, k) in candidates.into_iter().take(count) {
+        index_remove(&mut cache, &k);
+        unpublish(&k);
+    }
+    std::mem::drop(cache);
+    slog::warn!(LOG, "emergency eviction dropped {} entries", count);
+    crate::metrics::inc_by(
+        "cache_evictions_total",
+        &[("reason", "disk_full")],
+        count as u64,
+    );
+    // the disk is full, this can't be a dry run
+    cleanup_pass(false).await;
+}
+
+/// Write fresh content out to all tiers. Until this completes the
+/// content is served from the `PENDING` map.
+async fn _store_in_tiers(entry: CachedFile, bytes: Bytes) {
+    let file_name = match entry.file_name() {
+        Some(n) => n,
+        None => return,
+    };
+    for tier in TIERS.iter() {
+        if let Err(e) = tier.put(&entry, &bytes).await {
+            if is_disk_full(&e) {
+                on_disk_full();
+            }
+            errors::record(&entry.cache_name, &e);
+            slog::error!(
+                LOG,
+                "error storing badge {} in {} tier: {:?}",
+                entry.cache_name,
+                tier.name(),
+                e
+            );
+        }
+    }
+    slog::info!(LOG, "stored badge {} -> {}", entry.cache_name, file_name);
+    PENDING
+        .lock()
+        .expect("pending lock poisoned")
+        .remove(&file_name);
+}
+
+/// What's actually stored for an upstream body, after any processing
+/// the entry asks for. Done before hashing so an unchanged upstream
+/// body still hashes the same as what's stored.
+fn _prepare(entry: &CachedFile, bytes: Bytes) -> Bytes {
+    let mut transforms = vec![];
+    if let Some(logo) = &entry.logo {
+        transforms.push(crate::processing::Transform::Logo(logo.clone()));
+    }
+    if let Some(theme) = entry.theme {
+        transforms.push(crate::processing::Transform::Theme(theme));
+    }
+    if CONFIG.minify_svg && entry.ext == "svg" {
+        transforms.push(crate::processing::Transform::Minify);
+    }
+    if transforms.is_empty() {
+        return bytes;
+    }
+    crate::processing::apply(&bytes, &transforms)
+}
+
+/// Update the entry for freshly fetched content, serving it straight
+/// from memory while it's written out to the tiers in the background
+fn _store_fetched(entry: &mut CachedFile, bytes: Bytes, etag: Option<String>) -> Content {
+    if entry.content_hash.is_some() && entry.content_hash != Some(content_hash(&bytes)) {
+        rt::spawn(_log_content_change(entry.clone(), bytes.clone()));
+    }
+    entry.upstream_etag = etag;
+    entry.created_millis = now_millis();
+    entry.ttl_millis = jittered_ttl_millis(&entry.kind);
+    _store_content(entry, bytes)
+}
+
+/// The message a badge shows, e.g. `v1.0.130` from a crate's
+/// `<title>crates.io: v1.0.130</title>`, or a build's `passing`
+pub fn badge_message(svg: &[u8]) -> Option<String> {
+    let svg = std::str::from_utf8(svg).ok()?;
+    let start = svg.find("<title>")? + "<title>".len();
+    let end = start + svg[start..].find("</title>")?;
+    let message = svg[start..end].rsplit(": ").next()?.trim();
+    if message.is_empty() {
+        None
+    } else {
+        Some(message.to_string())
+    }
+}
+
+/// Leave a trail in the logs of what a refresh changed. The previous
+/// content is still stored under its own hash, so it's read back to
+/// pull out the version it showed.
+async fn _log_content_change(previous: CachedFile, bytes: Bytes) {
+    let (old_version, new_version) = if previous.kind == "crate" && previous.ext == "svg" {
+        let old = stored_bytes(&previous)
+            .await
+            .and_then(|b| badge_message(&b));
+        (old, badge_message(&bytes))
+    } else {
+        (None, None)
+    };
+    slog::info!(
+        LOG, "badge content changed";
+        "key" => &previous.cache_name,
+        "kind" => &previous.kind,
+        "old_hash" => previous.content_hash.as_deref(),
+        "new_hash" => content_hash(&bytes),
+        "old_version" => old_version.as_deref(),
+        "new_version" => new_version.as_deref(),
+    );
+}
+
+/// Take on a peer's copy of an entry, keeping when it was fetched
+/// so it expires here when it does there
+fn _store_from_peer(entry: &mut CachedFile, peer: CachedFile, bytes: Bytes) -> Content {
+    entry.upstream_etag = peer.upstream_etag;
+    entry.created_millis = peer.created_millis;
+    entry.ttl_millis = peer.ttl_millis;
+    _store_content(entry, bytes)
+}
+
+fn _store_content(entry: &mut CachedFile, bytes: Bytes) -> Content {
+    crate::metrics::observe_with_buckets(
+        "cache_content_bytes",
+        &[("kind", entry.kind.as_str())],
+        bytes.len() as f64,
+        crate::metrics::SIZE_BUCKETS,
+    );
+    let previous_hash = entry.content_hash.replace(content_hash(&bytes));
+    // replicas holding the old content should fetch the new
+    if previous_hash.is_some() && previous_hash != entry.content_hash {
+        crate::peer::broadcast_invalidation(&entry.cache_name);
+    }
+    NEGATIVE.remove(&entry.cache_name);
+    if let Some(file_name) = entry.file_name() {
+        PENDING
+            .lock()
+            .expect("pending lock poisoned")
+            .insert(file_name, bytes.clone());
+    }
+    rt::spawn(_store_in_tiers(entry.clone(), bytes.clone()));
+    publish(entry);
+    Content::Bytes(bytes)
+}
+
+/// Mark an entry as fresh again after upstream confirmed it's unchanged
+fn _extend_freshness(entry: &mut CachedFile) {
+    entry.created_millis = now_millis();
+    entry.ttl_millis = jittered_ttl_millis(&entry.kind);
+    publish(entry);
+}
+
+pub enum Refreshed {
+    Unchanged,
+    Modified,
+    Skipped,
+}
+
+/// Revalidate an entry against upstream outside of any request. Entries
+/// that upstream says are unchanged have their freshness extended. Entries
+/// being fetched or without anything to revalidate are skipped.
+pub async fn refresh_entry(cache_name: &str) -> errors::Result<Refreshed> {
+    let inner = match CACHE.lock().await.get(cache_name).cloned() {
+        Some(inner) => inner,
+        None => return Ok(Refreshed::Skipped),
+    };
+    let mut entry = match inner.try_lock() {
+        Some(entry) => entry,
+        None => return Ok(Refreshed::Skipped),
+    };
+    if entry.content_hash.is_none() || entry.upstream_url.is_empty() {
+        return Ok(Refreshed::Skipped);
+    }
+    let url = entry.upstream_url.clone();
+    let kind = entry.kind.clone();
+    let etag = entry.upstream_etag.clone();
+    match _fetch_url(&url, &kind, cache_name, etag.as_deref()).await? {
+        Fetched::NotModified => {
+            _extend_freshness(&mut entry);
+            Ok(Refreshed::Unchanged)
+        }
+        Fetched::Body { bytes, etag } => {
+            let bytes = _prepare(&entry, bytes);
+            if Some(content_hash(&bytes)) == entry.content_hash {
+                entry.upstream_etag = etag;
+                _extend_freshness(&mut entry);
+                Ok(Refreshed::Unchanged)
+            } else {
+                _store_fetched(&mut entry, bytes, etag);
+                Ok(Refreshed::Modified)
+            }
+        }
+    }
+}
+
+/// Drop every cached variant (ext, query params) of a crate's badge,
+/// returning the keys that were dropped
+pub async fn purge_crate(name: &str) -> Vec<String> {
+    let keys = entries()
+        .into_iter()
+        .filter(|e| e.kind == "crate" && e.name == name)
+        .map(|e| e.cache_name.clone())
+        .collect::<Vec<_>>();
+    for key in keys.iter() {
+        invalidate(key).await;
+    }
+    keys
+}
+
+/// Keys of everything in the cache
+pub async fn keys() -> Vec<String> {
+    CACHE.lock().await.keys().cloned().collect()
+}
+
+/// Fetch fresh content from upstream
+async fn _get_from_upstream(params: &Params, entry: &mut CachedFile) -> errors::Result<Content> {
+    match _fetch_badge(params, None).await? {
+        Fetched::Body { bytes, etag } => {
+            let bytes = _prepare(entry, bytes);
+            Ok(_store_fetched(entry, bytes, etag))
+        }
+        Fetched::NotModified => Err(ServiceError::Internal(
+            "unexpected 304 for unconditional request".to_string(),
+        )),
+    }
+}
+
+/// Refill a new entry from a retired one's disk content, revalidating it
+/// with upstream instead of fetching the whole badge. Returns `None` when
+/// there's nothing retired for the key, or its content is gone.
+async fn _warm_start(params: &Params, entry: &mut CachedFile) -> errors::Result<Option<Content>> {
+    let retired = match RETIRED.remove(&params.cache_name) {
+        Some((_, retired)) => retired,
+        None => return Ok(None),
+    };
+    if retirement_over(&retired, now_millis()) {
+        return Ok(None);
+    }
+    entry.content_hash = retired.content_hash;
+    entry.upstream_etag = retired.upstream_etag;
+    let cached = match _get_from_tiers(entry).await? {
+        Some(content) => content,
+        None => {
+            entry.content_hash = None;
+            entry.upstream_etag = None;
+            return Ok(None);
+        }
+    };
+    let etag = entry.upstream_etag.clone();
+    match _fetch_badge(params, etag.as_deref()).await? {
+        Fetched::NotModified => {
+            crate::metrics::inc("warm_starts_total", &[("result", "not_modified")]);
+            publish(entry);
+            Ok(Some(cached))
+        }
+        Fetched::Body { bytes, etag } => {
+            let bytes = _prepare(entry, bytes);
+            if Some(content_hash(&bytes)) == entry.content_hash {
+                crate::metrics::inc("warm_starts_total", &[("result", "unchanged")]);
+                entry.upstream_etag = etag;
+                publish(entry);
+                Ok(Some(cached))
+            } else {
+                crate::metrics::inc("warm_starts_total", &[("result", "modified")]);
+                Ok(Some(_store_fetched(entry, bytes, etag)))
+            }
+        }
+    }
+}
+
+/// Check a cached badge against upstream before serving it. The cached
+/// copy is served if upstream says it's unchanged or can't be reached.
+/// Returns `None` when there's no cached copy to fall back on.
+async fn _revalidate(
+    params: &Params,
+    entry: &mut CachedFile,
+) -> errors::Result<Option<(bool, Content)>> {
+    let cached = match _get_from_tiers(entry).await? {
+        Some(content) => content,
+        None => return Ok(None),
+    };
+    let etag = entry.upstream_etag.clone();
+    match _fetch_badge(params, etag.as_deref()).await {
+        Ok(Fetched::NotModified) => {
+            crate::metrics::inc("revalidations_total", &[("result", "not_modified")]);
+            Ok(Some((true, cached)))
+        }
+        Ok(Fetched::Body { bytes, etag }) => {
+            let bytes = _prepare(entry, bytes);
+            if Some(content_hash(&bytes)) == entry.content_hash {
+                crate::metrics::inc("revalidations_total", &[("result", "unchanged")]);
+                entry.upstream_etag = etag;
+                publish(entry);
+                Ok(Some((true, cached)))
+            } else {
+                crate::metrics::inc("revalidations_total", &[("result", "modified")]);
+                Ok(Some((false, _store_fetched(entry, bytes, etag))))
+            }
+        }
+        Err(e) => {
+            crate::metrics::inc("revalidations_total", &[("result", "error")]);
+            slog::error!(
+                LOG,
+                "error revalidating {}, serving cached copy: {:?}",
+                params.cache_name,
+                e
+            );
+            Ok(Some((true, cached)))
+        }
+    }
+}
+
+// how many entries to consider when looking for one to evict
+const EVICTION_SAMPLE_SIZE: usize = 8;
+
+// rough size of an index entry besides its key: the map slot, the
+// Arc/Mutex/CachedFile, and the hash, url, and etag strings it holds
+const INDEX_ENTRY_OVERHEAD: usize = 512;
+
+// approximate bytes held by CACHE, kept up to date by
+// `index_insert`/`index_remove`
+static INDEX_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Approximate memory an entry takes in the index. The key is counted
+/// twice since entries hold their own copy of it.
+fn index_entry_bytes(cache_name: &str) -> usize {
+    cache_name.len() * 2 + INDEX_ENTRY_OVERHEAD
+}
+
+/// Approximate memory held by the in-memory index
+pub fn index_bytes() -> usize {
+    INDEX_BYTES.load(Ordering::SeqCst)
+}
+
+fn index_insert(
+    cache: &mut HashMap<String, Arc<Mutex<CachedFile>>>,
+    cache_name: String,
+    inner: Arc<Mutex<CachedFile>>,
+) {
+    let size = index_entry_bytes(&cache_name);
+    if cache.insert(cache_name, inner).is_none() {
+        INDEX_BYTES.fetch_add(size, Ordering::SeqCst);
+    }
+}
+
+fn index_remove(cache: &mut HashMap<String, Arc<Mutex<CachedFile>>>, cache_name: &str) {
+    if cache.remove(cache_name).is_some() {
+        INDEX_BYTES.fetch_sub(index_entry_bytes(cache_name), Ordering::SeqCst);
+    }
+}
+
+fn is_full(cache: &HashMap<String, Arc<Mutex<CachedFile>>>) -> bool {
+    (CONFIG.max_cache_entries > 0 && cache.len() >= CONFIG.max_cache_entries)
+        || (CONFIG.max_index_bytes > 0 && index_bytes() >= CONFIG.max_index_bytes)
+}
+
+/// Decide whether a new key should be let into a full cache. A sample of
+/// existing entries is checked and the least frequently requested one is
+/// evicted, but only if the new key has been requested more often than it.
+/// This keeps one-off keys (scanners, typos) from pushing out hot badges.
+fn admit(cache: &mut HashMap<String, Arc<Mutex<CachedFile>>>, frequency: u8) -> bool {
+    if !is_full(cache) || cache.is_empty() {
+        return true;
+    }
+    use rand::Rng;
+    let skip = rand::thread_rng().gen_range(0..cache.len());
+    let victim = cache
+        .keys()
+        .cycle()
+        .skip(skip)
+        .take(EVICTION_SAMPLE_SIZE.min(cache.len()))
+        .map(|k| (crate::admission::estimate(k), k))
+        .min_by_key(|(f, _)| *f)
+        .map(|(f, k)| (f, k.clone()));
+    match victim {
+        Some((victim_frequency, victim)) if frequency > victim_frequency => {
+            slog::debug!(
+                LOG,
+                "evicting {} (frequency {}) for a key with frequency {}",
+                victim,
+                victim_frequency,
+                frequency
+            );
+            crate::metrics::inc("cache_evictions_total", &[("reason", "capacity")]);
+            index_remove(cache, &victim);
+            unpublish(&victim);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Serve a fresh entry from its snapshot, without taking any locks
+async fn _get_from_snapshot(params: &Params) -> errors::Result<Option<Content>> {
+    if CONFIG.strict_freshness {
+        return Ok(None);
+    }
+    let snapshot = match SNAPSHOTS.get(&params.cache_name) {
+        Some(s) => s.value().clone(),
+        None => return Ok(None),
+    };
+    if is_expired(&snapshot, now_millis()) {
+        return Ok(None);
+    }
+    crate::admission::record(&params.cache_name);
+    _get_from_tiers(&snapshot).await
+}
+
+static PENDING_MISSES: AtomicUsize = AtomicUsize::new(0);
+
+/// A miss being filled, counted until it's dropped
+struct PendingMiss;
+impl PendingMiss {
+    /// `None` when there are already `max_pending_misses` in progress
+    fn begin() -> Option<Self> {
+        let pending = PENDING_MISSES.fetch_add(1, Ordering::SeqCst) + 1;
+        if CONFIG.max_pending_misses > 0 && pending > CONFIG.max_pending_misses {
+            PENDING_MISSES.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        crate::metrics::set_gauge("pending_misses", &[], pending as f64);
+        Some(PendingMiss)
+    }
+}
+impl Drop for PendingMiss {
+    fn drop(&mut self) {
+        let pending = PENDING_MISSES.fetch_sub(1, Ordering::SeqCst) - 1;
+        crate::metrics::set_gauge("pending_misses", &[], pending as f64);
+    }
+}
+
+/// Serve a recently expired entry while some other request is refreshing
+/// it, rather than having everyone queue up behind that refresh. The first
+/// request after expiry finds the entry unlocked and does the refresh itself.
+async fn _get_in_grace(params: &Params) -> errors::Result<Option<Content>> {
+    if CONFIG.grace_period_millis == 0 || CONFIG.strict_freshness {
+        return Ok(None);
+    }
+    let snapshot = match SNAPSHOTS.get(&params.cache_name) {
+        Some(s) => s.value().clone(),
+        None => return Ok(None),
+    };
+    let age = now_millis().saturating_sub(snapshot.created_millis);
+    if age > snapshot.ttl_millis + CONFIG.grace_period_millis {
+        return Ok(None);
+    }
+    let refreshing = CACHE
+        .lock()
+        .await
+        .get(&params.cache_name)
+        .map(|inner| inner.try_lock().is_none())
+        .unwrap_or(false);
+    if !refreshing {
+        return Ok(None);
+    }
+    let content = _get_from_tiers(&snapshot).await?;
+    if content.is_some() {
+        crate::metrics::inc("cache_grace_hits_total", &[("kind", params.kind.as_str())]);
+    }
+    Ok(content)
+}
+
+/// Serve whatever's on hand for a key, expired or not, since it can't be refreshed
+async fn _get_read_only(params: &Params, err: ServiceError) -> errors::Result<(bool, Content)> {
+    match get_stale(&params.cache_name).await {
+        Some(content) => Ok((true, content)),
+        None => Err(err),
+    }
+}
+
+/// While a new key format is rolled out, fall back to a fresh entry under
+/// the badge's legacy key. The entry is copied over to the new key so it's
+/// found there from then on, the content itself is shared.
+async fn _get_from_legacy(params: &Params) -> errors::Result<Option<Content>> {
+    let legacy_cache_name = match &params.legacy_cache_name {
+        Some(n) => n,
+        None => return Ok(None),
+    };
+    let snapshot = match SNAPSHOTS.get(legacy_cache_name) {
+        Some(s) => s.value().clone(),
+        None => return Ok(None),
+    };
+    if is_expired(&snapshot, now_millis()) {
+        return Ok(None);
+    }
+    let content = match _get_from_tiers(&snapshot).await? {
+        Some(content) => content,
+        None => return Ok(None),
+    };
+    crate::metrics::inc("cache_legacy_hits_total", &[("kind", params.kind.as_str())]);
+
+    let mut entry = (*snapshot).clone();
+    entry.cache_name = params.cache_name.clone();
+    let mut cache = CACHE.lock().await;
+    // someone may already be fetching it under the new key
+    if !cache.contains_key(&entry.cache_name) {
+        slog::debug!(
+            LOG,
+            "migrating {} -> {}",
+            legacy_cache_name,
+            entry.cache_name
+        );
+        publish(&entry);
+        index_insert(
+            &mut cache,
+            entry.cache_name.clone(),
+            Arc::new(Mutex::new(entry)),
+        );
+    }
+    Ok(Some(content))
+}
+
+pub async fn get_cached(params: &Params) -> errors::Result<(bool, Content)> {
+    let result = _get_cached(params).await;
+    if let Ok((true, _)) = &result {
+        record_hit(&params.cache_name);
+    }
+    result
+}
+
+async fn _get_cached(params: &Params) -> errors::Result<(bool, Content)> {
+    if let Some(content) = _get_from_snapshot(params).await? {
+        return Ok((true, content));
+    }
+    if let Some(content) = _get_from_legacy(params).await? {
+        return Ok((true, content));
+    }
+    if CONFIG.read_only {
+        return _get_read_only(params, ServiceError::ReadOnly).await;
+    }
+    if crate::health::in_maintenance() {
+        return _get_read_only(params, ServiceError::Maintenance).await;
+    }
+    // the prober says upstream is down, don't pile more requests on it
+    if crate::health::upstream_down() {
+        crate::metrics::inc("upstream_down_skipped_total", &[]);
+        let err = ServiceError::UpstreamIo("upstream probe failing".to_string());
+        return _get_read_only(params, err).await;
+    }
+    if is_known_not_found(&params.cache_name, now_millis()) {
+        return Err(ServiceError::NotFound(params.cache_name.clone()));
+    }
+    if let Some(wait) = rate_limited_for(&params.cache_name, now_millis()) {
+        return _get_read_only(params, ServiceError::RateLimited(wait)).await;
+    }
+    if let Some(content) = _get_in_grace(params).await? {
+        return Ok((true, content));
+    }
+    let _pending = match PendingMiss::begin() {
+        Some(pending) => pending,
+        None => {
+            crate::metrics::inc(
+                "overload_rejections_total",
+                &[("kind", params.kind.as_str())],
+            );
+            return _get_read_only(params, ServiceError::Overloaded).await;
+        }
+    };
+
+    //  generate new cache values, the content hash is
+    //  only known once the content has been fetched
+    let new_created_millis = now_millis();
+    let new_inner = Arc::new(Mutex::new(CachedFile {
+        cache_name: params.cache_name.clone(),
+        kind: params.kind.as_str().to_string(),
+        name: params.name.clone(),
+        upstream_url: params.redirect_url.clone(),
+        created_millis: new_created_millis,
+        ttl_millis: jittered_ttl_millis(params.kind.as_str()),
+        ext: params.ext.clone(),
+        content_hash: None,
+        upstream_etag: None,
+        theme: params.theme,
+        logo: params.logo.clone(),
+    }));
+
+    // lock the cache and get or insert
+    let mut cache = CACHE.lock().await;
+    let frequency = crate::admission::record(&params.cache_name);
+    if !cache.contains_key(&params.cache_name) {
+        let rejected = if !crate::admission::passes_doorkeeper(&params.cache_name) {
+            Some("doorkeeper")
+        } else if !admit(&mut cache, frequency) {
+            Some("capacity")
+        } else {
+            None
+        };
+        if let Some(reason) = rejected {
+            std::mem::drop(cache);
+            slog::debug!(
+                LOG,
+                "not admitting badge to cache ({}): {}",
+                reason,
+                params.cache_name
+            );
+            crate::metrics::inc("cache_admission_rejected_total", &[("reason", reason)]);
+            let bytes = _request_badge_bytes(params).await?;
+            let bytes = _prepare(&*new_inner.lock().await, bytes);
+            return Ok((false, Content::Bytes(bytes)));
+        }
+        index_insert(&mut cache, params.cache_name.clone(), new_inner.clone());
+    }
+    let inner = cache
+        .get(&params.cache_name)
+        .expect("entry was just checked or inserted");
+
+    // clone the inner pointer and lock the individual entry
+    // while we're still holding the cache lock.
+    let owned_inner = inner.clone();
+    let mut locked_inner = owned_inner.lock().await;
+
+    // drop the lock on the cache as a whole - we've still got the
+    // lock on the individual entry so no one else can be retrieving
+    // and saving this badge at the same time.
+    std::mem::drop(cache);
+
+    // we've got a cached value if it doesn't match our new insertion timestamp
+    let is_cached = locked_inner.created_millis != new_created_millis;
+    if is_cached {
+        // and if it hasn't expired
+        let now = now_millis();
+        let diff = now.saturating_sub(locked_inner.created_millis);
+        if diff > locked_inner.ttl_millis {
+            // if it did expire, the entry is refreshed in place
+            // once the new content is successfully fetched
+            slog::info!(LOG, "cached badge expired: {}", params.cache_name);
+        } else if CONFIG.strict_freshness {
+            if let Some(result) = _revalidate(params, &mut locked_inner).await? {
+                return Ok(result);
+            }
+        } else if let Some(content) = _get_from_tiers(&locked_inner).await? {
+            return Ok((true, content));
+        }
+        // otherwise a previous fetch failed, or the content was
+        // swept out from under us, either way it needs to be fetched again
+    }
+
+    // nowhere to put it, leave it to the failover
+    if crate::health::disk_full() {
+        return Err(ServiceError::DiskFull);
+    }
+    if let Some((peer_entry, bytes)) = crate::peer::fetch(&params.cache_name).await {
+        return Ok((
+            false,
+            _store_from_peer(&mut locked_inner, peer_entry, bytes),
+        ));
+    }
+    if !is_cached {
+        if let Some(content) = _warm_start(params, &mut locked_inner).await? {
+            return Ok((false, content));
+        }
+    }
+    let content = _get_from_upstream(params, &mut locked_inner).await?;
+    Ok((false, content))
+}
+
+/// Everything we know about a key, without fetching or serving anything
+pub async fn inspect(cache_name: &str) -> serde_json::Value {
+    let now = now_millis();
+    let inner = CACHE.lock().await.get(cache_name).cloned();
+    // an entry that's locked is being fetched, don't wait on it
+    let (entry, fetching) = match &inner {
+        Some(inner) => match inner.try_lock() {
+            Some(entry) => (Some(entry.clone()), false),
+            None => (None, true),
+        },
+        None => (None, false),
+    };
+    let snapshot = SNAPSHOTS.get(cache_name).map(|s| (**s.value()).clone());
+    let describe = |entry: &CachedFile| {
+        serde_json::json!({
+            "entry": entry,
+            "file_name": entry.file_name(),
+            "age_millis": now.saturating_sub(entry.created_millis) as u64,
+            "expired": is_expired(entry, now),
+        })
+    };
+    serde_json::json!({
+        "cached": inner.is_some(),
+        "fetching": fetching,
+        "entry": entry.as_ref().map(describe),
+        "snapshot": snapshot.as_ref().map(describe),
+        "known_not_found": is_known_not_found(cache_name, now),
+    })
+}
+
+/// The content stored under a content-addressed file name, for peers
+pub async fn content_bytes(file_name: &str) -> errors::Result<Option<Bytes>> {
+    let keys = CONTENT_KEYS
+        .get(file_name)
+        .map(|keys| keys.value().iter().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let entry = keys.iter().find_map(|key| {
+        SNAPSHOTS
+            .get(key)
+            .filter(|s| s.value().file_name().as_deref() == Some(file_name))
+            .map(|s| s.value().clone())
+    });
+    let entry = match entry {
+        Some(e) => e,
+        None => return Ok(None),
+    };
+    match _get_from_tiers(&entry).await? {
+        Some(Content::Bytes(b)) => Ok(Some(b)),
+        Some(Content::File(p)) => Ok(Some(Bytes::from(tokio::fs::read(p).await?))),
+        None => Ok(None),
+    }
+}
+
+/// When the content currently published for a key goes stale
+pub fn expires_millis(cache_name: &str) -> Option<u128> {
+    SNAPSHOTS
+        .get(cache_name)
+        .map(|s| s.value().created_millis + s.value().ttl_millis)
+}
+
+/// An entry's stored content, wherever it's stored
+pub async fn stored_bytes(entry: &CachedFile) -> Option<Bytes> {
+    match _get_from_tiers(entry).await {
+        Ok(Some(Content::Bytes(b))) => Some(b),
+        Ok(Some(Content::File(p))) => tokio::fs::read(p).await.ok().map(Bytes::from),
+        _ => None,
+    }
+}
+
+/// The version shown by the most recently fetched svg badge cached for
+/// a crate, and when it was fetched
+pub async fn crate_version(name: &str) -> Option<(String, u128)> {
+    let mut entries = SNAPSHOTS
+        .iter()
+        .filter(|s| {
+            let e = s.value();
+            e.kind == "crate" && e.name == name && e.ext == "svg" && e.content_hash.is_some()
+        })
+        .map(|s| s.value().clone())
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|e| std::cmp::Reverse(e.created_millis));
+    for entry in entries {
+        let bytes = match stored_bytes(&entry).await {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        if let Some(version) = badge_message(&bytes) {
+            return Some((version, entry.created_millis));
+        }
+    }
+    None
+}
+
+/// The content-addressed file name currently published for a key
+pub fn published_file_name(cache_name: &str) -> Option<String> {
+    SNAPSHOTS
+        .get(cache_name)
+        .and_then(|s| s.value().file_name())
+}
+
+/// When the content currently published for a key was fetched from upstream
+pub fn refreshed_millis(cache_name: &str) -> Option<u128> {
+    SNAPSHOTS.get(cache_name).map(|s| s.value().created_millis)
+}
+
+/// Whatever content is on hand for a key, expired or not
+pub async fn get_stale(cache_name: &str) -> Option<Content> {
+    let inner = CACHE.lock().await.get(cache_name).cloned()?;
+    let entry = inner.lock().await;
+    _get_from_tiers(&entry).await.ok().flatten()
+}
+
+pub async fn remove(cache_name: &str) {
+    let mut guard = CACHE.lock().await;
+    index_remove(&mut guard, cache_name);
+    unpublish(cache_name);
+    NEGATIVE.remove(cache_name);
+    RETIRED.remove(cache_name);
+}
+
+/// Remove a key here and on every peer
+pub async fn invalidate(cache_name: &str) {
+    remove(cache_name).await;
+    crate::peer::broadcast_invalidation(cache_name);
+}
+
+/// Sizes of the in-memory indexes
+pub async fn stats() -> serde_json::Value {
+    let entries = CACHE.lock().await.len();
+    serde_json::json!({
+        "entries": entries,
+        "index_bytes": index_bytes(),
+        "snapshots": SNAPSHOTS.len(),
+        "known_not_found": NEGATIVE.len(),
+        "rate_limited": RATE_LIMITED.len(),
+        "retired": RETIRED.len(),
+        "pending_misses": PENDING_MISSES.load(Ordering::SeqCst),
+        "pending_writes": PENDING.lock().expect("pending lock poisoned").len(),
+    })
+}
+
+/// The number of files in the cache dir and their total size
+pub async fn disk_usage() -> std::io::Result<(usize, u64)> {
+    let mut reader = tokio::fs::read_dir(cache_dir()).await?;
+    let (mut files, mut bytes) = (0, 0);
+    while let Some(entry) = reader.next_entry().await? {
+        let meta = entry.metadata().await?;
+        if meta.is_file() {
+            files += 1;
+            bytes += meta.len();
+        }
+    }
+    Ok((files, bytes))
+}
+
+#[derive(Default, Debug)]
+pub struct RecoverySummary {
+    pub scanned: usize,
+    pub empty: usize,
+    pub corrupt: usize,
+    pub partial: usize,
+    // files that couldn't be checked, left in place
+    pub unreadable: usize,
+}
+
+/// The size and sha256 of a file, read a chunk at a time
+async fn file_digest(path: &Path) -> std::io::Result<(u64, String)> {
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncReadExt;
+    let mut f = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        len += n as u64;
+    }
+    Ok((len, format!("{:x}", hasher.finalize())))
+}
+
+/// Find content files a crash left behind half written: leftover temp
+/// files, and files that are empty or don't hash to their name. They're
+/// removed before the index is restored, so entries pointing at them are
+/// dropped and refetched rather than served as broken badges. Files that
+/// could never be content are left to the cleanup's orphan handling.
+pub async fn recover_cache_dir() -> anyhow::Result<RecoverySummary> {
+    let mut summary = RecoverySummary::default();
+    let dir = cache_dir();
+    let mut reader = match tokio::fs::read_dir(&dir).await {
+        Ok(r) => r,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(summary),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = reader.next_entry().await? {
+        let path = entry.path();
+        match entry.file_type().await {
+            Ok(t) if t.is_dir() => continue,
+            Ok(_) => (),
+            Err(e) => {
+                slog::error!(LOG, "unable to check cache file {:?}: {:?}", path, e);
+                summary.unreadable += 1;
+                continue;
+            }
+        }
+        let file_name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let partial = file_name.ends_with(".tmp");
+        if !partial && !is_content_file_name(&file_name) {
+            continue;
+        }
+        summary.scanned += 1;
+        let problem = if partial {
+            summary.partial += 1;
+            "partial"
+        } else {
+            let (len, hash) = match file_digest(&path).await {
+                Ok(digest) => digest,
+                Err(e) => {
+                    slog::error!(LOG, "unable to check content file {:?}: {:?}", path, e);
+                    summary.unreadable += 1;
+                    continue;
+                }
+            };
+            if len == 0 {
+                summary.empty += 1;
+                "empty"
+            } else if !file_name.starts_with(&hash) {
+                summary.corrupt += 1;
+                "corrupt"
+            } else {
+                continue;
+            }
+        };
+        slog::warn!(LOG, "removing {} content file: {:?}", problem, path);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            slog::error!(LOG, "unable to remove content file {:?}: {:?}", path, e);
+        }
+    }
+    Ok(summary)
+}
+
+#[derive(Default, Debug)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    pub expired: usize,
+    pub missing: usize,
+}
+
+/// Write out the index so the next startup can pick up where we left off
+pub async fn persist_index() -> anyhow::Result<usize> {
+    // snapshot the entries so the global lock isn't held while
+    // waiting on each entry's lock
+    let handles = {
+        let cache = CACHE.lock().await;
+        cache.values().cloned().collect::<Vec<_>>()
+    };
+    let mut entries = Vec::with_capacity(handles.len());
+    for v in handles {
+        let v = v.lock().await;
+        if v.content_hash.is_some() {
+            entries.push(v.clone());
+        }
+    }
+    let path = cache_dir().join(INDEX_FILE);
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec(&entries)?).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(entries.len())
+}
+
+/// Run a single cleanup pass against the persisted index, for the `clean`
+/// command. Refuses to run without an index, since every file would look
+/// unreferenced.
+pub async fn clean(dry_run: bool) -> anyhow::Result<RestoreSummary> {
+    let path = cache_dir().join(INDEX_FILE);
+    if tokio::fs::metadata(&path).await.is_err() {
+        anyhow::bail!("no cache index at {:?}, refusing to clean", path);
+    }
+    let summary = restore_index().await?;
+    cleanup_pass(dry_run).await;
+    if !dry_run {
+        // expired entries aren't restored, so this drops them from the index
+        persist_index().await?;
+    }
+    Ok(summary)
+}
+
+/// Load a previously persisted index, discarding anything
+/// that's expired or whose content is no longer available
+pub async fn restore_index() -> anyhow::Result<RestoreSummary> {
+    let mut summary = RestoreSummary::default();
+    let path = cache_dir().join(INDEX_FILE);
+    let bytes = match tokio::fs::read(&path).await {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(summary),
+        Err(e) => return Err(e.into()),
+    };
+    let entries: Vec<CachedFile> = match serde_json::from_slice(&bytes) {
+        Ok(entries) => entries,
+        Err(e) => {
+            slog::error!(LOG, "discarding unreadable cache index: {:?}", e);
+            return Ok(summary);
+        }
+    };
+
+    let now = now_millis();
+    let mut cache = CACHE.lock().await;
+    for entry in entries {
+        if now.saturating_sub(entry.created_millis) > entry.ttl_millis {
+            summary.expired += 1;
+            continue;
+        }
+        let mut available = false;
+        for tier in TIERS.iter() {
+            if tier.get(&entry).await?.is_some() {
+                available = true;
+                break;
+            }
+        }
+        if !available {
+            summary.missing += 1;
+            continue;
+        }
+        summary.restored += 1;
+        publish(&entry);
+        index_insert(
+            &mut cache,
+            entry.cache_name.clone(),
+            Arc::new(Mutex::new(entry)),
+        );
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, ManualClock};
+
+    fn entry(cache_name: &str, ttl_millis: u128) -> CachedFile {
+        CachedFile {
+            cache_name: cache_name.to_string(),
+            kind: "crate".to_string(),
+            name: "test".to_string(),
+            upstream_url: String::new(),
+            created_millis: now_millis(),
+            ttl_millis,
+            ext: "svg".to_string(),
+            content_hash: None,
+            upstream_etag: None,
+            theme: None,
+            logo: None,
+        }
+    }
+
+    #[test]
+    fn expiry_follows_the_clock() {
+        let clock = ManualClock::new(1_000_000);
+        clock::install(clock.clone());
+        let e = entry("test/expiry", 60_000);
+        assert!(!is_expired(&e, now_millis()));
+        clock.advance(60_000);
+        assert!(!is_expired(&e, now_millis()));
+        clock.advance(1);
+        assert!(is_expired(&e, now_millis()));
+    }
+
+    #[test]
+    fn jittered_ttls_stay_in_bounds() {
+        let clock = ManualClock::new(1_000_000);
+        clock::install(clock.clone());
+        let base = CONFIG.cache_ttl_millis as f64;
+        let jitter = CONFIG.cache_ttl_jitter_percent.abs().min(100.) / 100.;
+        let (min, max) = (
+            (base * (1. - jitter)) as u128,
+            (base * (1. + jitter)) as u128,
+        );
+        for _ in 0..1000 {
+            let ttl = jittered_ttl_millis("crate");
+            assert!(
+                (min..=max).contains(&ttl),
+                "{} outside {}..={}",
+                ttl,
+                min,
+                max
+            );
+
+            // each entry expires on its own jittered ttl
+            let e = entry("test/jitter", ttl);
+            clock.advance(ttl as u64);
+            assert!(!is_expired(&e, now_millis()));
+            clock.advance(1);
+            assert!(is_expired(&e, now_millis()));
+        }
+    }
+
+    #[tokio::test]
+    async fn cleanup_drops_only_expired_entries() {
+        let clock = ManualClock::new(1_000_000);
+        clock::install(clock.clone());
+        let (expired, fresh) = ("test/cleanup-expired", "test/cleanup-fresh");
+        {
+            let mut cache = CACHE.lock().await;
+            for (name, ttl) in &[(expired, 1_000), (fresh, 1_000_000)] {
+                let inner = Arc::new(Mutex::new(entry(name, *ttl)));
+                index_insert(&mut cache, name.to_string(), inner);
+            }
+        }
+        clock.advance(2_000);
+
+        let would_remove = invalidate_expired(now_millis(), true).await;
+        assert!(would_remove.contains(expired));
+        assert!(!would_remove.contains(fresh));
+        assert!(CACHE.lock().await.contains_key(expired));
+
+        invalidate_expired(now_millis(), false).await;
+        let mut cache = CACHE.lock().await;
+        assert!(!cache.contains_key(expired));
+        assert!(cache.contains_key(fresh));
+        index_remove(&mut cache, fresh);
+    }
+}
@@ -0,0 +1,60 @@
+/// Where the cache gets the current time from. Everything time-based
+/// (ttls, expiry, cleanup, negative caching) goes through `cache::now_millis`,
+/// which reads `cache::CLOCK`, so expiry can be driven by something other
+/// than the system clock.
+pub trait Clock {
+    /// Milliseconds since the unix epoch
+    fn now_millis(&self) -> u128;
+}
+
+/// The real time
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u128 {
+        let now = std::time::SystemTime::now();
+        now.duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .map(|dur| dur.as_millis())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    // stands in for the system clock on the current test's thread
+    static MANUAL: std::cell::RefCell<Option<std::sync::Arc<ManualClock>>> =
+        std::cell::RefCell::new(None);
+}
+
+/// A clock that only moves when told to, for tests
+#[cfg(test)]
+pub struct ManualClock(std::sync::atomic::AtomicU64);
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(start_millis: u64) -> std::sync::Arc<Self> {
+        std::sync::Arc::new(Self(std::sync::atomic::AtomicU64::new(start_millis)))
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.0
+            .fetch_add(millis, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u128 {
+        self.0.load(std::sync::atomic::Ordering::SeqCst) as u128
+    }
+}
+
+/// Drive `cache::now_millis` from `clock` on this thread. Tests run on
+/// their own threads, so each can keep its own time.
+#[cfg(test)]
+pub fn install(clock: std::sync::Arc<ManualClock>) {
+    MANUAL.with(|m| *m.borrow_mut() = Some(clock));
+}
+
+/// The manual clock installed on this thread, if any
+#[cfg(test)]
+pub fn installed() -> Option<std::sync::Arc<ManualClock>> {
+    MANUAL.with(|m| m.borrow().clone())
+}
@@ -0,0 +1,73 @@
+use crate::CONFIG;
+
+// points on the ring per node, so keys spread evenly and only
+// about 1/n of them move when a node is added or removed
+const VIRTUAL_NODES: usize = 64;
+
+lazy_static::lazy_static! {
+    pub static ref RING: Option<Ring> = if CONFIG.cluster_nodes.is_empty() {
+        None
+    } else {
+        let is_member = CONFIG
+            .cluster_self
+            .as_ref()
+            .map(|s| CONFIG.cluster_nodes.contains(s))
+            .unwrap_or(false);
+        if !is_member {
+            panic!("invalid cluster_self, it must be one of cluster_nodes");
+        }
+        Some(Ring::new(&CONFIG.cluster_nodes))
+    };
+}
+
+fn hash(s: &str) -> u64 {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::digest(s.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// A consistent hash ring over the cluster's nodes
+pub struct Ring {
+    // sorted (point, node index)
+    points: Vec<(u64, usize)>,
+    nodes: Vec<String>,
+}
+impl Ring {
+    pub fn new(nodes: &[String]) -> Self {
+        let mut points = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, node)| {
+                (0..VIRTUAL_NODES).map(move |v| (hash(&format!("{}#{}", node, v)), i))
+            })
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+        Self {
+            points,
+            nodes: nodes.to_vec(),
+        }
+    }
+
+    /// The node responsible for `key`, the first one clockwise from its hash
+    pub fn owner(&self, key: &str) -> &str {
+        let h = hash(key);
+        let ind = match self.points.binary_search_by_key(&h, |(p, _)| *p) {
+            Ok(i) => i,
+            Err(i) => i % self.points.len(),
+        };
+        &self.nodes[self.points[ind].1]
+    }
+}
+
+/// The node that owns `key`, when it's some other node
+pub fn remote_owner(key: &str) -> Option<&'static str> {
+    let ring = RING.as_ref()?;
+    let owner = ring.owner(key);
+    if Some(owner) == CONFIG.cluster_self.as_deref() {
+        None
+    } else {
+        Some(owner)
+    }
+}
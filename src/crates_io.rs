@@ -0,0 +1,127 @@
+use crate::errors::{self, ServiceError};
+use crate::CONFIG;
+
+// crates.io rejects requests without a user agent
+const USER_AGENT: &str = "badge-cache (https://github.com/jaemk/badge-cache)";
+
+pub enum Ownership {
+    Owner(String),
+    NotOwner(String),
+    InvalidToken,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct User {
+    login: String,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct Me {
+    user: User,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct Owners {
+    users: Vec<Owner>,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct Owner {
+    login: String,
+    kind: String,
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(
+    path: &str,
+    token: Option<&str>,
+) -> errors::Result<(reqwest::StatusCode, Option<T>)> {
+    let url = format!("{}{}", CONFIG.crates_io_api_url.trim_end_matches('/'), path);
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::USER_AGENT,
+        reqwest::header::HeaderValue::from_static(USER_AGENT),
+    );
+    if let Some(token) = token {
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            token
+                .parse()
+                .map_err(|_| ServiceError::Parse("invalid token".into()))?,
+        );
+    }
+    let resp = crate::upstream::get_with_headers(&url, headers).await?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Ok((status, None));
+    }
+    let body = resp.json::<T>().await?;
+    Ok((status, Some(body)))
+}
+
+#[derive(serde_derive::Deserialize)]
+struct CrateInfo {
+    #[serde(rename = "crate")]
+    krate: Crate,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct Crate {
+    max_version: String,
+    max_stable_version: Option<String>,
+}
+
+/// The version a crate's badge would show, the latest stable
+/// release, or the latest prerelease when there's nothing stable
+pub async fn latest_version(crate_name: &str) -> errors::Result<String> {
+    let path = format!("/crates/{}", crate_name);
+    match get_json::<CrateInfo>(&path, None).await? {
+        (_, Some(info)) => Ok(info
+            .krate
+            .max_stable_version
+            .unwrap_or(info.krate.max_version)),
+        (status, None) if status == reqwest::StatusCode::NOT_FOUND => {
+            Err(ServiceError::NotFound(crate_name.to_string()))
+        }
+        (status, None) => Err(ServiceError::UpstreamStatus {
+            url: path,
+            status: status.as_u16(),
+        }),
+    }
+}
+
+/// Check that the holder of a crates.io api `token` is one of the
+/// crate's (individual) owners. Team owners can't be verified this way.
+pub async fn check_ownership(token: &str, crate_name: &str) -> errors::Result<Ownership> {
+    let login = match get_json::<Me>("/me", Some(token)).await? {
+        (_, Some(me)) => me.user.login,
+        (status, None) if status.is_client_error() => return Ok(Ownership::InvalidToken),
+        (status, None) => {
+            return Err(ServiceError::UpstreamStatus {
+                url: "/me".into(),
+                status: status.as_u16(),
+            })
+        }
+    };
+    let path = format!("/crates/{}/owners", crate_name);
+    let owners = match get_json::<Owners>(&path, None).await? {
+        (_, Some(owners)) => owners,
+        (status, None) if status == reqwest::StatusCode::NOT_FOUND => {
+            return Err(ServiceError::NotFound(crate_name.to_string()))
+        }
+        (status, None) => {
+            return Err(ServiceError::UpstreamStatus {
+                url: path,
+                status: status.as_u16(),
+            })
+        }
+    };
+    let owner = owners
+        .users
+        .iter()
+        .any(|o| o.kind == "user" && o.login == login);
+    if owner {
+        Ok(Ownership::Owner(login))
+    } else {
+        Ok(Ownership::NotOwner(login))
+    }
+}
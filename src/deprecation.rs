@@ -0,0 +1,147 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, http, Error};
+use futures::future::{ok, Ready};
+use futures::Future;
+
+use crate::CONFIG;
+
+/// A route prefix slated for removal, and the prefix replacing it
+#[derive(Debug, Clone)]
+pub struct DeprecatedRoute {
+    pub prefix: String,
+    pub successor: String,
+    // when the route stops working, if that's been decided
+    pub sunset: Option<chrono::NaiveDate>,
+}
+impl DeprecatedRoute {
+    fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(self.prefix.as_str())
+            .map(|rest| rest.starts_with('/'))
+            .unwrap_or(false)
+    }
+
+    /// The `Sunset` header value, an http-date
+    fn sunset_header(&self) -> Option<String> {
+        self.sunset
+            .map(|d| d.format("%a, %d %b %Y 00:00:00 GMT").to_string())
+    }
+}
+impl std::fmt::Display for DeprecatedRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.prefix, self.successor)?;
+        if let Some(sunset) = self.sunset {
+            write!(f, "@{}", sunset)?;
+        }
+        Ok(())
+    }
+}
+impl std::str::FromStr for DeprecatedRoute {
+    type Err = anyhow::Error;
+    /// `/old/prefix=/new/prefix`, optionally with `@yyyy-mm-dd` sunset date
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (routes, sunset) = match s.trim().splitn(2, '@').collect::<Vec<_>>()[..] {
+            [routes, sunset] => (routes, Some(sunset.trim().parse()?)),
+            [routes] => (routes, None),
+            _ => anyhow::bail!("invalid deprecated route: {}", s),
+        };
+        let mut parts = routes.splitn(2, '=');
+        let prefix = parts.next().unwrap_or("").trim().trim_end_matches('/');
+        let successor = parts.next().unwrap_or("").trim().trim_end_matches('/');
+        if !prefix.starts_with('/') || !successor.starts_with('/') {
+            anyhow::bail!("invalid deprecated route, expected /old=/new: {}", s);
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            successor: successor.to_string(),
+            sunset,
+        })
+    }
+}
+
+/// Mark responses from `DEPRECATED_ROUTES` with `Deprecation`, `Sunset`,
+/// and a `Link` to the same request on the successor route
+pub struct Deprecation;
+impl Deprecation {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl<S, B> Transform<S> for Deprecation
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = DeprecationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(DeprecationMiddleware { service })
+    }
+}
+
+pub struct DeprecationMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for DeprecationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let route = CONFIG
+            .deprecated_routes
+            .iter()
+            .find(|r| r.matches(req.path()));
+        let route = match route {
+            Some(route) => route,
+            None => return Box::pin(self.service.call(req)),
+        };
+        crate::metrics::inc(
+            "deprecated_route_requests_total",
+            &[("route", route.prefix.as_str())],
+        );
+        let mut successor = format!("{}{}", route.successor, &req.path()[route.prefix.len()..]);
+        if !req.query_string().is_empty() {
+            successor = format!("{}?{}", successor, req.query_string());
+        }
+        let link = format!("<{}>; rel=\"successor-version\"", successor);
+        let sunset = route.sunset_header();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let headers = res.headers_mut();
+            headers.insert(
+                http::HeaderName::from_static("deprecation"),
+                http::HeaderValue::from_static("true"),
+            );
+            if let Some(sunset) = sunset.and_then(|s| http::HeaderValue::from_str(&s).ok()) {
+                headers.insert(http::HeaderName::from_static("sunset"), sunset);
+            }
+            if let Ok(link) = http::HeaderValue::from_str(&link) {
+                headers.append(http::header::LINK, link);
+            }
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,20 @@
+/// Everything worth knowing during an incident, in one place, so triage
+/// doesn't need a shell on the box
+pub async fn report() -> serde_json::Value {
+    let (_, health) = crate::health::report();
+    let disk_usage = match crate::cache::disk_usage().await {
+        Ok((files, bytes)) => serde_json::json!({
+            "files": files,
+            "bytes": bytes,
+        }),
+        Err(e) => serde_json::json!({ "error": e.to_string() }),
+    };
+    serde_json::json!({
+        "config": crate::config_snapshot(),
+        "health": health,
+        "cache": crate::cache::stats().await,
+        "disk_usage": disk_usage,
+        "upstream": crate::upstream::SCHEDULER.stats(),
+        "recent_errors": crate::errors::recent(),
+    })
+}
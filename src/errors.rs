@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+
+pub type Result<T> = std::result::Result<T, ServiceError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ServiceError {
+    #[error("invalid badge: {0}")]
+    Parse(String),
+
+    #[error("unknown badge: {0}")]
+    NotFound(String),
+
+    #[error("upstream returned {status} for {url}")]
+    UpstreamStatus { url: String, status: u16 },
+
+    #[error("upstream request failed: {0}")]
+    UpstreamIo(String),
+
+    #[error("cache io error: {0}")]
+    CacheIo(#[from] std::io::Error),
+
+    #[error("template error: {0}")]
+    Template(#[from] tera::Error),
+
+    #[error("deadline exceeded")]
+    Timeout,
+
+    #[error("cache disk is full")]
+    DiskFull,
+
+    #[error("cache is read-only")]
+    ReadOnly,
+
+    #[error("in maintenance mode")]
+    Maintenance,
+
+    #[error("too many pending misses")]
+    Overloaded,
+
+    #[error("rate limited by upstream, retry in {0:?}")]
+    RateLimited(std::time::Duration),
+
+    #[error("outbound host not allowed: {0}")]
+    HostNotAllowed(String),
+
+    #[error("missing or invalid credentials")]
+    Unauthenticated,
+
+    #[error("api key quota of {limit} requests a day exceeded")]
+    QuotaExceeded { limit: u64, reset_seconds: u64 },
+
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+impl ServiceError {
+    pub fn internal<T: std::fmt::Display>(e: T) -> Self {
+        ServiceError::Internal(e.to_string())
+    }
+
+    /// Where the error came from, for grouping recent errors
+    pub fn source_name(&self) -> &'static str {
+        match self {
+            ServiceError::UpstreamStatus { .. }
+            | ServiceError::UpstreamIo(_)
+            | ServiceError::RateLimited(_)
+            | ServiceError::HostNotAllowed(_) => "upstream",
+            ServiceError::CacheIo(_) | ServiceError::DiskFull => "io",
+            ServiceError::Template(_) => "template",
+            _ => "internal",
+        }
+    }
+}
+
+// how many errors to hold on to
+const RECENT_ERRORS_LEN: usize = 100;
+
+lazy_static::lazy_static! {
+    // the most recent errors, newest first. logs have often been
+    // rotated away by the time someone looks into a flaky badge
+    static ref RECENT_ERRORS: Mutex<VecDeque<RecentError>> =
+        Mutex::new(VecDeque::with_capacity(RECENT_ERRORS_LEN));
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RecentError {
+    pub source: &'static str,
+    // the cache key, template, or url the error was for
+    pub key: String,
+    pub error: String,
+    pub at: String,
+}
+
+/// Remember an error for `/admin/errors`
+pub fn record(key: &str, error: &ServiceError) {
+    let mut recent = RECENT_ERRORS.lock().expect("recent errors lock poisoned");
+    if recent.len() >= RECENT_ERRORS_LEN {
+        recent.pop_back();
+    }
+    recent.push_front(RecentError {
+        source: error.source_name(),
+        key: key.to_string(),
+        error: error.to_string(),
+        at: chrono::Utc::now().to_rfc3339(),
+    });
+}
+
+pub fn recent() -> Vec<RecentError> {
+    let recent = RECENT_ERRORS.lock().expect("recent errors lock poisoned");
+    recent.iter().cloned().collect()
+}
+
+impl From<reqwest::Error> for ServiceError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) => ServiceError::UpstreamStatus {
+                url: e.url().map(|u| u.to_string()).unwrap_or_default(),
+                status: status.as_u16(),
+            },
+            None => ServiceError::UpstreamIo(e.to_string()),
+        }
+    }
+}
+
+impl ResponseError for ServiceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ServiceError::Parse(_) => StatusCode::BAD_REQUEST,
+            ServiceError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            ServiceError::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ServiceError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServiceError::UpstreamStatus { .. }
+            | ServiceError::UpstreamIo(_)
+            | ServiceError::HostNotAllowed(_) => StatusCode::BAD_GATEWAY,
+            ServiceError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ServiceError::DiskFull
+            | ServiceError::ReadOnly
+            | ServiceError::Maintenance
+            | ServiceError::Overloaded
+            | ServiceError::RateLimited(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ServiceError::CacheIo(_) | ServiceError::Template(_) | ServiceError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // internals stay in the logs
+        let msg = match self {
+            ServiceError::Parse(_) | ServiceError::NotFound(_) => self.to_string(),
+            ServiceError::UpstreamStatus { .. } | ServiceError::UpstreamIo(_) => {
+                "error retrieving badge".to_string()
+            }
+            ServiceError::Timeout => "timed out retrieving badge".to_string(),
+            ServiceError::Unauthenticated => "unauthorized".to_string(),
+            ServiceError::QuotaExceeded { .. } => self.to_string(),
+            _ => "internal error".to_string(),
+        };
+        let mut resp = HttpResponse::build(self.status_code());
+        match self {
+            ServiceError::Unauthenticated if !crate::CONFIG.basic_auth_users.is_empty() => {
+                resp.header(
+                    actix_web::http::header::WWW_AUTHENTICATE,
+                    r#"Basic realm="badge-cache""#,
+                );
+            }
+            ServiceError::QuotaExceeded {
+                limit,
+                reset_seconds,
+            } => {
+                resp.header("x-ratelimit-limit", *limit)
+                    .header("x-ratelimit-remaining", 0u64)
+                    .header("x-ratelimit-reset", *reset_seconds)
+                    .header(actix_web::http::header::RETRY_AFTER, *reset_seconds);
+            }
+            _ => (),
+        }
+        resp.body(msg)
+    }
+}
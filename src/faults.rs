@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use actix_web::rt;
+use rand::Rng;
+
+use crate::errors::{self, ServiceError};
+use crate::{CONFIG, LOG};
+
+/// Whether faults can be injected at all. Release builds never inject,
+/// whatever the `FAULT_*` config says.
+pub fn enabled() -> bool {
+    cfg!(debug_assertions)
+        && (CONFIG.fault_upstream_error_rate > 0.
+            || CONFIG.fault_disk_error_rate > 0.
+            || CONFIG.fault_latency_ms > 0)
+}
+
+fn roll(rate: f64) -> bool {
+    rate > 0. && rand::thread_rng().gen::<f64>() < rate
+}
+
+/// Delay an upstream request by `FAULT_LATENCY_MS`, then fail it
+/// `FAULT_UPSTREAM_ERROR_RATE` of the time
+pub async fn upstream(host: &str) -> errors::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    if CONFIG.fault_latency_ms > 0 {
+        crate::metrics::inc("faults_injected_total", &[("fault", "latency")]);
+        rt::time::delay_for(Duration::from_millis(CONFIG.fault_latency_ms)).await;
+    }
+    if roll(CONFIG.fault_upstream_error_rate) {
+        crate::metrics::inc("faults_injected_total", &[("fault", "upstream")]);
+        slog::debug!(LOG, "injecting upstream fault for {}", host);
+        return Err(ServiceError::UpstreamIo(format!(
+            "injected fault requesting {}",
+            host
+        )));
+    }
+    Ok(())
+}
+
+/// Fail a cache write `FAULT_DISK_ERROR_RATE` of the time
+pub fn disk() -> std::io::Result<()> {
+    if !enabled() || !roll(CONFIG.fault_disk_error_rate) {
+        return Ok(());
+    }
+    crate::metrics::inc("faults_injected_total", &[("fault", "disk")]);
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "injected disk fault",
+    ))
+}
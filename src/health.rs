@@ -0,0 +1,182 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::CONFIG;
+
+lazy_static::lazy_static! {
+    static ref STARTED_MILLIS: u64 = crate::cache::now_millis() as u64;
+
+    // the banner message while in maintenance mode, which serves
+    // only what's already cached and makes no upstream requests
+    static ref MAINTENANCE: std::sync::RwLock<Option<String>> = std::sync::RwLock::new(None);
+
+    // what the upstream prober saw last
+    static ref PROBE: std::sync::Mutex<ProbeState> = std::sync::Mutex::new(ProbeState::default());
+}
+
+#[derive(Default, Debug, Clone, serde::Serialize)]
+struct ProbeState {
+    last_probe_millis: Option<u64>,
+    last_status: Option<u16>,
+    last_error: Option<String>,
+    last_latency_millis: Option<u64>,
+    consecutive_failures: u64,
+}
+
+// zero until the first successful cleanup pass
+static LAST_CLEANUP_MILLIS: AtomicU64 = AtomicU64::new(0);
+static CLEANUP_RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+// while the disk is full, misses skip the cache until this passes
+static DISK_FULL_UNTIL_MILLIS: AtomicU64 = AtomicU64::new(0);
+static DISK_FULL_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn initialize() {
+    lazy_static::initialize(&STARTED_MILLIS);
+}
+
+pub fn record_cleanup() {
+    LAST_CLEANUP_MILLIS.store(crate::cache::now_millis() as u64, Ordering::SeqCst);
+}
+
+pub fn record_cleanup_restart() {
+    CLEANUP_RESTARTS.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn last_cleanup_millis() -> u64 {
+    LAST_CLEANUP_MILLIS.load(Ordering::SeqCst)
+}
+
+/// Note that a write failed because the disk is full, extending the
+/// degraded period. Returns `true` if this starts a new degraded period.
+pub fn record_disk_full() -> bool {
+    let now = crate::cache::now_millis() as u64;
+    let until = now + CONFIG.disk_full_backoff_seconds * 1000;
+    let previous = DISK_FULL_UNTIL_MILLIS.swap(until, Ordering::SeqCst);
+    DISK_FULL_EVENTS.fetch_add(1, Ordering::SeqCst);
+    previous <= now
+}
+
+pub fn disk_full() -> bool {
+    DISK_FULL_UNTIL_MILLIS.load(Ordering::SeqCst) > crate::cache::now_millis() as u64
+}
+
+/// The cleaner is considered stuck if it's missed a few of its intervals
+fn cleanup_is_healthy(now: u64) -> bool {
+    // nothing is cleaned up when the cache can't be written to
+    if CONFIG.read_only {
+        return true;
+    }
+    let allowed_millis =
+        (CONFIG.cleanup_delay_seconds + CONFIG.cleanup_interval_seconds * 3) * 1000;
+    let last = match last_cleanup_millis() {
+        0 => *STARTED_MILLIS,
+        n => n,
+    };
+    now.saturating_sub(last) <= allowed_millis
+}
+
+pub fn set_maintenance(message: Option<String>) {
+    *MAINTENANCE.write().expect("maintenance lock poisoned") = message;
+}
+
+/// The maintenance message, when in maintenance mode
+pub fn maintenance() -> Option<String> {
+    MAINTENANCE
+        .read()
+        .expect("maintenance lock poisoned")
+        .clone()
+}
+
+pub fn in_maintenance() -> bool {
+    MAINTENANCE
+        .read()
+        .expect("maintenance lock poisoned")
+        .is_some()
+}
+
+/// Record the outcome of an upstream probe: the status it got back,
+/// or why it didn't get one
+pub fn record_probe(result: Result<u16, String>, latency: std::time::Duration) {
+    let mut probe = PROBE.lock().expect("probe lock poisoned");
+    probe.last_probe_millis = Some(crate::cache::now_millis() as u64);
+    probe.last_latency_millis = Some(latency.as_millis() as u64);
+    match result {
+        Ok(status) if (200..400).contains(&status) => {
+            probe.last_status = Some(status);
+            probe.last_error = None;
+            probe.consecutive_failures = 0;
+        }
+        Ok(status) => {
+            probe.last_status = Some(status);
+            probe.last_error = None;
+            probe.consecutive_failures += 1;
+        }
+        Err(e) => {
+            probe.last_status = None;
+            probe.last_error = Some(e);
+            probe.consecutive_failures += 1;
+        }
+    }
+}
+
+/// Whether the prober has seen `UPSTREAM_PROBE_FAILURE_THRESHOLD` failures
+/// in a row. Misses without a stale copy aren't sent upstream while it is.
+pub fn upstream_down() -> bool {
+    CONFIG.upstream_probe_url.is_some()
+        && CONFIG.upstream_probe_failure_threshold > 0
+        && PROBE
+            .lock()
+            .expect("probe lock poisoned")
+            .consecutive_failures
+            >= CONFIG.upstream_probe_failure_threshold
+}
+
+/// The prober's view of upstream, `None` when it isn't probed
+pub fn upstream_report() -> Option<serde_json::Value> {
+    CONFIG.upstream_probe_url.as_ref()?;
+    let up = !upstream_down();
+    let probe = PROBE.lock().expect("probe lock poisoned").clone();
+    Some(serde_json::json!({
+        "up": up,
+        "probe": probe,
+    }))
+}
+
+pub fn report() -> (bool, serde_json::Value) {
+    let now = crate::cache::now_millis() as u64;
+    let last_cleanup = last_cleanup_millis();
+    let cleanup_ok = cleanup_is_healthy(now);
+    let healthy = cleanup_ok;
+    let disk_full_until = DISK_FULL_UNTIL_MILLIS.load(Ordering::SeqCst);
+    let disk_full = disk_full_until > now;
+    // still serving, just not caching anything new
+    let status = if !healthy {
+        "unhealthy"
+    } else if disk_full || upstream_down() {
+        "degraded"
+    } else {
+        "ok"
+    };
+    let report = serde_json::json!({
+        "status": status,
+        "version": CONFIG.version,
+        "cleanup": {
+            "ok": cleanup_ok,
+            "last_success_millis": if last_cleanup == 0 { None } else { Some(last_cleanup) },
+            "last_success_age_seconds": if last_cleanup == 0 {
+                None
+            } else {
+                Some(now.saturating_sub(last_cleanup) / 1000)
+            },
+            "restarts": CLEANUP_RESTARTS.load(Ordering::SeqCst),
+        },
+        "disk": {
+            "full": disk_full,
+            "full_until_millis": if disk_full { Some(disk_full_until) } else { None },
+            "full_events": DISK_FULL_EVENTS.load(Ordering::SeqCst),
+        },
+        "upstream": upstream_report(),
+        "maintenance": maintenance(),
+    });
+    (healthy, report)
+}
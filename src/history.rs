@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::rt;
+
+use crate::cache;
+use crate::{CONFIG, LOG};
+
+// how often to check whether today's snapshots have been taken
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+lazy_static::lazy_static! {
+    // cache key -> snapshots, oldest first
+    static ref HISTORY: Mutex<HashMap<String, Vec<Snapshot>>> = Mutex::new(load());
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    // the UTC day this was taken on, yyyy-mm-dd
+    pub day: String,
+    pub taken_at: String,
+    pub content_hash: String,
+    // what the badge said, e.g. a version or build status
+    pub value: Option<String>,
+}
+
+fn load() -> HashMap<String, Vec<Snapshot>> {
+    let path = match &CONFIG.history_file {
+        Some(path) => path,
+        None => return HashMap::new(),
+    };
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            slog::error!(LOG, "unable to read history file {}: {:?}", path, e);
+            return HashMap::new();
+        }
+    };
+    serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+        slog::error!(LOG, "discarding unreadable history file {}: {:?}", path, e);
+        HashMap::new()
+    })
+}
+
+async fn persist(path: &str) -> anyhow::Result<()> {
+    let bytes = {
+        let history = HISTORY.lock().expect("history lock poisoned");
+        serde_json::to_vec(&*history)?
+    };
+    let tmp_path = format!("{}.tmp", path);
+    tokio::fs::write(&tmp_path, bytes).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// A key's snapshots, oldest first
+pub fn timeline(cache_name: &str) -> Option<Vec<Snapshot>> {
+    HISTORY
+        .lock()
+        .expect("history lock poisoned")
+        .get(cache_name)
+        .cloned()
+}
+
+/// Snapshot every cached badge that hasn't been snapshotted today,
+/// dropping the oldest snapshots of a key past `HISTORY_MAX_SNAPSHOTS`
+async fn snapshot_pass(path: &str) {
+    let now = chrono::Utc::now();
+    let day = now.format("%Y-%m-%d").to_string();
+    let mut taken = 0;
+    for entry in cache::entries() {
+        let hash = match &entry.content_hash {
+            Some(hash) => hash.clone(),
+            None => continue,
+        };
+        let done_today = HISTORY
+            .lock()
+            .expect("history lock poisoned")
+            .get(&entry.cache_name)
+            .and_then(|s| s.last())
+            .map(|s| s.day == day)
+            .unwrap_or(false);
+        if done_today {
+            continue;
+        }
+        let value = if entry.ext == "svg" {
+            cache::stored_bytes(&entry)
+                .await
+                .and_then(|b| cache::badge_message(&b))
+        } else {
+            None
+        };
+        let mut history = HISTORY.lock().expect("history lock poisoned");
+        let snapshots = history.entry(entry.cache_name.clone()).or_default();
+        snapshots.push(Snapshot {
+            day: day.clone(),
+            taken_at: now.to_rfc3339(),
+            content_hash: hash,
+            value,
+        });
+        if snapshots.len() > CONFIG.history_max_snapshots {
+            let excess = snapshots.len() - CONFIG.history_max_snapshots;
+            snapshots.drain(..excess);
+        }
+        taken += 1;
+    }
+    crate::metrics::inc_by("history_snapshots_total", &[], taken);
+    if taken == 0 {
+        return;
+    }
+    slog::info!(LOG, "took badge history snapshots"; "day" => &day, "count" => taken);
+    if let Err(e) = persist(path).await {
+        slog::error!(LOG, "unable to persist history to {}: {:?}", path, e);
+    }
+}
+
+/// Take a snapshot of each badge once a day, while `HISTORY_FILE` is set
+pub async fn run() {
+    let path = match &CONFIG.history_file {
+        Some(path) => path,
+        None => return,
+    };
+    lazy_static::initialize(&HISTORY);
+    let mut interval = rt::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        snapshot_pass(path).await;
+    }
+}
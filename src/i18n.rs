@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use actix_web::{http, HttpRequest};
+
+use crate::{CONFIG, LOG};
+
+/// What pages are shown in when nothing better matches, and what
+/// every other locale falls back to for strings it doesn't have
+pub const DEFAULT_LOCALE: &str = "en";
+
+lazy_static::lazy_static! {
+    // locale -> its strings, already filled in from the default locale
+    pub static ref LOCALES: HashMap<String, serde_json::Value> = load();
+}
+
+/// Fill in anything `value` is missing from `fallback`
+fn merge(value: &mut serde_json::Value, fallback: &serde_json::Value) {
+    if let (serde_json::Value::Object(value), serde_json::Value::Object(fallback)) =
+        (value, fallback)
+    {
+        for (k, v) in fallback {
+            match value.get_mut(k) {
+                Some(existing) => merge(existing, v),
+                None => {
+                    value.insert(k.clone(), v.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Read `<locale>.json` translation files from `LOCALE_DIR`
+fn load() -> HashMap<String, serde_json::Value> {
+    let mut locales = HashMap::new();
+    let entries = match std::fs::read_dir(&CONFIG.locale_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            slog::error!(
+                LOG,
+                "unable to read locale dir {}: {:?}",
+                CONFIG.locale_dir,
+                e
+            );
+            return locales;
+        }
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let locale = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) => stem.to_lowercase(),
+            None => continue,
+        };
+        let strings = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|b| serde_json::from_slice::<serde_json::Value>(&b).map_err(Into::into));
+        match strings {
+            Ok(strings) => {
+                locales.insert(locale, strings);
+            }
+            Err(e) => slog::error!(LOG, "skipping unreadable locale file {:?}: {:?}", path, e),
+        }
+    }
+    match locales.get(DEFAULT_LOCALE).cloned() {
+        Some(default) => {
+            for strings in locales.values_mut() {
+                merge(strings, &default);
+            }
+        }
+        None => slog::error!(
+            LOG,
+            "no {}.json in locale dir {}, pages will fail to render",
+            DEFAULT_LOCALE,
+            CONFIG.locale_dir
+        ),
+    }
+    slog::info!(LOG, "loaded locales"; "count" => locales.len());
+    locales
+}
+
+/// Pick the locale to show a page in from the request's `Accept-Language`,
+/// by preference, matching a full tag (`pt-br`) or just its language (`pt`)
+pub fn negotiate(request: &HttpRequest) -> String {
+    let header = request
+        .headers()
+        .get(http::header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let mut ranges = header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim().to_lowercase();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.);
+            if tag.is_empty() || q <= 0. {
+                None
+            } else {
+                Some((tag, q))
+            }
+        })
+        .collect::<Vec<_>>();
+    // stable, so equally preferred ranges keep the client's order
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    for (tag, _) in ranges {
+        if LOCALES.contains_key(&tag) {
+            return tag;
+        }
+        let language = tag.split('-').next().unwrap_or("");
+        if LOCALES.contains_key(language) {
+            return language.to_string();
+        }
+    }
+    DEFAULT_LOCALE.to_string()
+}
+
+/// The strings to render a page with in `locale`
+pub fn strings(locale: &str) -> serde_json::Value {
+    LOCALES
+        .get(locale)
+        .or_else(|| LOCALES.get(DEFAULT_LOCALE))
+        .cloned()
+        .unwrap_or_else(|| serde_json::json!({}))
+}
@@ -1,7 +1,38 @@
 #![recursion_limit = "1024"]
 
+mod admission;
+mod audit;
+mod auth;
+mod blocking;
+mod bots;
+mod bundle;
+mod cache;
+mod clock;
+mod cluster;
+mod crates_io;
+mod deprecation;
+mod diagnostics;
+mod errors;
+mod faults;
+mod health;
+mod history;
+mod i18n;
 mod logger;
+mod metrics;
+mod openapi;
+mod peer;
+mod probe;
+mod processing;
+mod quota;
+mod refresh;
 mod service;
+mod session;
+mod shadow;
+mod signing;
+mod smoke;
+mod templates;
+mod text;
+mod upstream;
 
 use std::env;
 use std::fs;
@@ -9,11 +40,64 @@ use std::io::Read;
 
 use slog::{o, Drain};
 
+// stands in for secret values in the override log and diagnostics
+const REDACTED: &str = "<redacted>";
+
+fn record_override(k: &str, default: &str, value: &str) {
+    CONFIG_OVERRIDES
+        .lock()
+        .expect("config overrides lock poisoned")
+        .push((k.to_string(), default.to_string(), value.to_string()));
+}
+
 fn env_or(k: &str, default: &str) -> String {
-    env::var(k).unwrap_or_else(|_| default.to_string())
+    match env::var(k) {
+        Ok(v) => {
+            if v != default {
+                record_override(k, default, &v);
+            }
+            v
+        }
+        Err(_) => default.to_string(),
+    }
+}
+
+/// Like `env_or` with an empty default, for tokens, keys and passwords.
+/// That it's set is recorded as an override, but never its value.
+fn env_secret(k: &str) -> String {
+    let v = env::var(k).unwrap_or_default();
+    if !v.is_empty() {
+        record_override(k, "", REDACTED);
+    }
+    v
+}
+
+/// Per-kind url templates from `KIND_<KIND>_<suffix>`, checked with
+/// `validate_url_template`
+fn kind_url_templates(suffix: &str) -> Vec<(service::Kind, String)> {
+    service::Kind::ALL
+        .iter()
+        .filter_map(|kind| {
+            let key = format!(
+                "KIND_{}_{}",
+                kind.as_str().to_uppercase().replace('-', "_"),
+                suffix
+            );
+            let template = env::var(&key).ok().filter(|s| !s.trim().is_empty())?;
+            if let Err(e) = service::validate_url_template(&template) {
+                panic!("invalid {}: {}", key, e);
+            }
+            Some((*kind, template))
+        })
+        .collect()
 }
 
 lazy_static::lazy_static! {
+    // (key, default, value) of every config value that differs from its default
+    static ref CONFIG_OVERRIDES: std::sync::Mutex<Vec<(String, String, String)>> = {
+        std::sync::Mutex::new(vec![])
+    };
+
     pub static ref CONFIG: Config = Config::load();
 
     // The "base" logger that all crates should branch off of
@@ -49,12 +133,89 @@ pub struct Config {
     pub max_name_length: usize,
     pub max_ext_length: usize,
     pub max_qs_length: usize,
+    pub query_param_allowlist: Vec<String>,
     pub cache_ttl_millis: u128,
+    pub docker_cache_ttl_millis: u128,
+    pub cache_ttl_jitter_percent: f64,
+    pub disk_retention_millis: u128,
     pub cache_dir: String,
+    pub cache_namespace: Option<String>,
+    pub cache_tiers: Vec<String>,
+    pub cache_key_format: service::KeyFormat,
+    pub cache_key_dual_read: bool,
+    pub max_cache_entries: usize,
+    pub max_index_bytes: usize,
+    pub orphan_quarantine: bool,
+    pub cleanup_dry_run: bool,
+    pub orphan_quarantine_seconds: u64,
+    pub strict_freshness: bool,
+    pub grace_period_millis: u128,
+    pub admission_min_requests: u32,
+    pub admission_window_seconds: u64,
+    pub negative_cache_seconds: u64,
     pub http_expiry_seconds: i64,
+    pub request_deadline_ms: u64,
     pub default_file_ext: String,
     pub cleanup_delay_seconds: u64,
     pub cleanup_interval_seconds: u64,
+    pub blocking_io_threads: usize,
+    pub stream_threshold_bytes: u64,
+    pub minify_svg: bool,
+    pub refresh_window: Option<String>,
+    pub refresh_window_rps: f64,
+    pub disk_full_backoff_seconds: u64,
+    pub failover_mode: service::FailoverMode,
+    pub proxy_only: bool,
+    pub route_aliases: Vec<(String, service::Kind)>,
+    pub deprecated_routes: Vec<deprecation::DeprecatedRoute>,
+    pub canonical_redirects: bool,
+    pub kind_url_templates: Vec<(service::Kind, String)>,
+    pub kind_shadow_url_templates: Vec<(service::Kind, String)>,
+    pub shadow_sample_percent: f64,
+    pub read_only: bool,
+    pub dev_mode: bool,
+    pub persist_index: bool,
+    pub robots_disallow_all: bool,
+    pub robots_disallow: Vec<String>,
+    pub sitemap_base_url: Option<String>,
+    pub logo_dir: Option<String>,
+    pub locale_dir: String,
+    pub debug_token: Option<String>,
+    pub admin_token: Option<String>,
+    pub admin_token_previous: Vec<String>,
+    pub admin_session_seconds: u64,
+    pub api_keys: Vec<String>,
+    pub api_key_daily_quota: u64,
+    pub api_key_quotas: Vec<(String, u64)>,
+    pub basic_auth_users: Vec<String>,
+    pub auth_route_groups: Vec<(auth::Group, auth::Requirement)>,
+    pub reset_signing_key: Option<String>,
+    pub reset_signing_key_previous: Vec<String>,
+    pub reset_link_ttl_seconds: u64,
+    pub public_purge: bool,
+    pub crates_io_api_url: String,
+    pub peer_sync_url: Option<String>,
+    pub peer_urls: Vec<String>,
+    pub cluster_nodes: Vec<String>,
+    pub cluster_self: Option<String>,
+    pub audit_log_file: Option<String>,
+    pub history_file: Option<String>,
+    pub history_max_snapshots: usize,
+    pub statsd_addr: Option<String>,
+    pub statsd_prefix: String,
+    pub upstream_max_rps: f64,
+    pub upstream_probe_url: Option<String>,
+    pub upstream_probe_interval_seconds: u64,
+    pub upstream_probe_failure_threshold: u64,
+    pub max_pending_misses: usize,
+    pub upstream_dns_cache: bool,
+    pub outbound_host_allowlist: Vec<String>,
+    pub bot_deny_patterns: Vec<String>,
+    pub bot_throttle_patterns: Vec<String>,
+    pub bot_throttle_rps: f64,
+    pub fault_upstream_error_rate: f64,
+    pub fault_disk_error_rate: f64,
+    pub fault_latency_ms: u64,
 }
 impl Config {
     pub fn load() -> Self {
@@ -83,16 +244,94 @@ impl Config {
             max_qs_length: env_or("MAX_QS_LENGTH", "512")
                 .parse()
                 .expect("invalid max_qs_length"),
+            query_param_allowlist: env_or(
+                "QUERY_PARAM_ALLOWLIST",
+                "label,color,style,logo,logoColor,labelColor,cacheSeconds",
+            )
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
             cache_ttl_millis: env_or(
                 "CACHE_TTL_MILLIS",
                 (60 * 60 * 24 * 1000).to_string().as_str(),
             )
             .parse()
             .expect("invalid cache_ttl_millis"),
+            docker_cache_ttl_millis: env_or(
+                "DOCKER_CACHE_TTL_MILLIS",
+                (60 * 60 * 1000).to_string().as_str(),
+            )
+            .parse()
+            .expect("invalid docker_cache_ttl_millis"),
+            disk_retention_millis: env_or("DISK_RETENTION_MILLIS", "0")
+                .parse()
+                .expect("invalid disk_retention_millis"),
+            cache_ttl_jitter_percent: env_or("CACHE_TTL_JITTER_PERCENT", "10")
+                .parse()
+                .expect("invalid cache_ttl_jitter_percent"),
             cache_dir: env_or("CACHE_DIR", "cache_dir"),
+            cache_namespace: env::var("CACHE_NAMESPACE")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    let valid = s
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                    if !valid {
+                        panic!("invalid cache_namespace: {}", s);
+                    }
+                    s
+                }),
+            cache_tiers: env_or("CACHE_TIERS", "disk")
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            cache_key_format: env_or("CACHE_KEY_FORMAT", "v1")
+                .parse()
+                .expect("invalid cache_key_format"),
+            cache_key_dual_read: env_or("CACHE_KEY_DUAL_READ", "true")
+                .parse()
+                .expect("invalid cache_key_dual_read"),
+            max_cache_entries: env_or("MAX_CACHE_ENTRIES", "0")
+                .parse()
+                .expect("invalid max_cache_entries"),
+            max_index_bytes: env_or("MAX_INDEX_BYTES", "0")
+                .parse()
+                .expect("invalid max_index_bytes"),
+            orphan_quarantine: env_or("ORPHAN_QUARANTINE", "false")
+                .parse()
+                .expect("invalid orphan_quarantine"),
+            cleanup_dry_run: env_or("CLEANUP_DRY_RUN", "false")
+                .parse()
+                .expect("invalid cleanup_dry_run"),
+            orphan_quarantine_seconds: env_or("ORPHAN_QUARANTINE_SECONDS", "604800")
+                .parse()
+                .expect("invalid orphan_quarantine_seconds"),
+            strict_freshness: env_or("STRICT_FRESHNESS", "false")
+                .parse()
+                .expect("invalid strict_freshness"),
+            grace_period_millis: env_or("GRACE_PERIOD_MILLIS", "30000")
+                .parse()
+                .expect("invalid grace_period_millis"),
+            admission_min_requests: env_or("ADMISSION_MIN_REQUESTS", "1")
+                .parse()
+                .ok()
+                .filter(|n| *n <= admission::MAX_MIN_REQUESTS)
+                .expect("invalid admission_min_requests, expected at most 30"),
+            admission_window_seconds: env_or("ADMISSION_WINDOW_SECONDS", "3600")
+                .parse()
+                .expect("invalid admission_window_seconds"),
+            negative_cache_seconds: env_or("NEGATIVE_CACHE_SECONDS", "60")
+                .parse()
+                .expect("invalid negative_cache_seconds"),
             http_expiry_seconds: env_or("HTTP_EXPIRY_SECONDS", (60 * 60).to_string().as_str())
                 .parse()
                 .expect("invalid http_expiry_seconds"),
+            request_deadline_ms: env_or("REQUEST_DEADLINE_MS", "5000")
+                .parse()
+                .expect("invalid request_deadline_ms"),
             default_file_ext: env_or("DEFAULT_FILE_EXT", "svg"),
             cleanup_delay_seconds: env_or("CLEANUP_DELAY_SECONDS", "5")
                 .parse()
@@ -103,6 +342,230 @@ impl Config {
             )
             .parse()
             .expect("invalid cleanup_interval_seconds"),
+            blocking_io_threads: env_or("BLOCKING_IO_THREADS", "0")
+                .parse()
+                .expect("invalid blocking_io_threads"),
+            stream_threshold_bytes: env_or("STREAM_THRESHOLD_BYTES", "0")
+                .parse()
+                .expect("invalid stream_threshold_bytes"),
+            minify_svg: env_or("MINIFY_SVG", "false")
+                .parse()
+                .expect("invalid minify_svg"),
+            refresh_window: env::var("REFRESH_WINDOW")
+                .ok()
+                .filter(|s| !s.trim().is_empty()),
+            refresh_window_rps: env_or("REFRESH_WINDOW_RPS", "1")
+                .parse()
+                .expect("invalid refresh_window_rps"),
+            disk_full_backoff_seconds: env_or("DISK_FULL_BACKOFF_SECONDS", "60")
+                .parse()
+                .expect("invalid disk_full_backoff_seconds"),
+            failover_mode: env_or("FAILOVER_MODE", "redirect")
+                .parse()
+                .expect("invalid failover_mode"),
+            proxy_only: env_or("PROXY_ONLY", "false")
+                .parse()
+                .expect("invalid proxy_only"),
+            route_aliases: env_or("ROUTE_ALIASES", "")
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    let mut parts = s.splitn(2, '=');
+                    let path = parts.next().unwrap_or("").trim().to_string();
+                    let kind = parts
+                        .next()
+                        .unwrap_or("")
+                        .parse()
+                        .expect("invalid route_aliases kind");
+                    if !path.starts_with('/') || !path.contains("{name}") {
+                        panic!("invalid route_aliases path: {}", path);
+                    }
+                    (path, kind)
+                })
+                .collect(),
+            canonical_redirects: env_or("CANONICAL_REDIRECTS", "false")
+                .parse()
+                .expect("invalid canonical_redirects"),
+            deprecated_routes: env_or("DEPRECATED_ROUTES", "")
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse().expect("invalid deprecated_routes entry"))
+                .collect(),
+            kind_url_templates: kind_url_templates("URL"),
+            kind_shadow_url_templates: kind_url_templates("SHADOW_URL"),
+            shadow_sample_percent: env_or("SHADOW_SAMPLE_PERCENT", "10")
+                .parse()
+                .expect("invalid shadow_sample_percent"),
+            read_only: env_or("READ_ONLY", "false")
+                .parse()
+                .expect("invalid read_only"),
+            dev_mode: env_or("DEV_MODE", "false")
+                .parse()
+                .expect("invalid dev_mode"),
+            persist_index: env_or("PERSIST_INDEX", "true")
+                .parse()
+                .expect("invalid persist_index"),
+            robots_disallow_all: env_or("ROBOTS_DISALLOW_ALL", "false")
+                .parse()
+                .expect("invalid robots_disallow_all"),
+            robots_disallow: env_or(
+                "ROBOTS_DISALLOW",
+                "/crate,/crates,/badge,/proxy,/preview,/reset,/purge,/debug,/admin,/api,/stats,/metrics",
+            )
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+            sitemap_base_url: env::var("SITEMAP_BASE_URL").ok(),
+            logo_dir: env::var("LOGO_DIR").ok().filter(|s| !s.is_empty()),
+            locale_dir: env_or("LOCALE_DIR", "locales"),
+            debug_token: env::var("DEBUG_TOKEN").ok().filter(|s| !s.is_empty()),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|s| !s.is_empty()),
+            admin_token_previous: env_secret("ADMIN_TOKEN_PREVIOUS")
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            admin_session_seconds: env_or("ADMIN_SESSION_SECONDS", "900")
+                .parse()
+                .expect("invalid admin_session_seconds"),
+            api_keys: env_secret("API_KEYS")
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            api_key_daily_quota: env_or("API_KEY_DAILY_QUOTA", "0")
+                .parse()
+                .expect("invalid api_key_daily_quota"),
+            api_key_quotas: env_or("API_KEY_QUOTAS", "")
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    let mut parts = s.splitn(2, '=');
+                    let fingerprint = parts.next().unwrap_or("").to_lowercase();
+                    let quota = parts
+                        .next()
+                        .and_then(|q| q.parse().ok())
+                        .expect("invalid api_key_quotas entry, expected fingerprint=quota");
+                    (fingerprint, quota)
+                })
+                .collect(),
+            basic_auth_users: env_secret("BASIC_AUTH_USERS")
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    if !s.contains(':') {
+                        panic!("invalid basic_auth_users entry, expected user:password");
+                    }
+                    s
+                })
+                .collect(),
+            auth_route_groups: env_or("AUTH_ROUTE_GROUPS", "")
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    let mut parts = s.splitn(2, '=');
+                    let group = parts
+                        .next()
+                        .unwrap_or("")
+                        .parse()
+                        .expect("invalid auth_route_groups group");
+                    let requirement = parts
+                        .next()
+                        .unwrap_or("")
+                        .parse()
+                        .expect("invalid auth_route_groups requirement");
+                    (group, requirement)
+                })
+                .collect(),
+            reset_signing_key: env::var("RESET_SIGNING_KEY").ok().filter(|s| !s.is_empty()),
+            reset_signing_key_previous: env_secret("RESET_SIGNING_KEY_PREVIOUS")
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            reset_link_ttl_seconds: env_or(
+                "RESET_LINK_TTL_SECONDS",
+                (60 * 60 * 24 * 7).to_string().as_str(),
+            )
+            .parse()
+            .expect("invalid reset_link_ttl_seconds"),
+            public_purge: env_or("PUBLIC_PURGE", "false")
+                .parse()
+                .expect("invalid public_purge"),
+            crates_io_api_url: env_or("CRATES_IO_API_URL", "https://crates.io/api/v1"),
+            peer_sync_url: env::var("PEER_SYNC_URL").ok().filter(|s| !s.is_empty()),
+            peer_urls: env_or("PEER_URLS", "")
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            cluster_nodes: env_or("CLUSTER_NODES", "")
+                .split(',')
+                .map(|s| s.trim().trim_end_matches('/').to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            cluster_self: env::var("CLUSTER_SELF")
+                .ok()
+                .map(|s| s.trim().trim_end_matches('/').to_string())
+                .filter(|s| !s.is_empty()),
+            audit_log_file: env::var("AUDIT_LOG_FILE").ok().filter(|s| !s.is_empty()),
+            history_file: env::var("HISTORY_FILE").ok().filter(|s| !s.is_empty()),
+            history_max_snapshots: env_or("HISTORY_MAX_SNAPSHOTS", "90")
+                .parse()
+                .expect("invalid history_max_snapshots"),
+            statsd_addr: env::var("STATSD_ADDR").ok().filter(|s| !s.is_empty()),
+            statsd_prefix: env_or("STATSD_PREFIX", "badge_cache"),
+            upstream_max_rps: env_or("UPSTREAM_MAX_RPS", "10")
+                .parse()
+                .expect("invalid upstream_max_rps"),
+            upstream_probe_url: env::var("UPSTREAM_PROBE_URL")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            upstream_probe_interval_seconds: env_or("UPSTREAM_PROBE_INTERVAL_SECONDS", "30")
+                .parse()
+                .expect("invalid upstream_probe_interval_seconds"),
+            upstream_probe_failure_threshold: env_or("UPSTREAM_PROBE_FAILURE_THRESHOLD", "3")
+                .parse()
+                .expect("invalid upstream_probe_failure_threshold"),
+            max_pending_misses: env_or("MAX_PENDING_MISSES", "0")
+                .parse()
+                .expect("invalid max_pending_misses"),
+            upstream_dns_cache: env_or("UPSTREAM_DNS_CACHE", "true")
+                .parse()
+                .expect("invalid upstream_dns_cache"),
+            outbound_host_allowlist: env_or("OUTBOUND_HOST_ALLOWLIST", "img.shields.io")
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            bot_deny_patterns: env_or("BOT_DENY_PATTERNS", "")
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            bot_throttle_patterns: env_or("BOT_THROTTLE_PATTERNS", "")
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            bot_throttle_rps: env_or("BOT_THROTTLE_RPS", "1")
+                .parse()
+                .expect("invalid bot_throttle_rps"),
+            fault_upstream_error_rate: env_or("FAULT_UPSTREAM_ERROR_RATE", "0")
+                .parse()
+                .expect("invalid fault_upstream_error_rate"),
+            fault_disk_error_rate: env_or("FAULT_DISK_ERROR_RATE", "0")
+                .parse()
+                .expect("invalid fault_disk_error_rate"),
+            fault_latency_ms: env_or("FAULT_LATENCY_MS", "0")
+                .parse()
+                .expect("invalid fault_latency_ms"),
         }
     }
     pub fn initialize(&self) -> anyhow::Result<()> {
@@ -116,25 +579,326 @@ impl Config {
             "max_name_length" => &CONFIG.max_name_length,
             "max_ext_length" => &CONFIG.max_ext_length,
             "max_qs_length" => &CONFIG.max_qs_length,
+            "query_param_allowlist" => &CONFIG.query_param_allowlist.join(","),
             "cache_ttl_millis" => &CONFIG.cache_ttl_millis,
+            "docker_cache_ttl_millis" => &CONFIG.docker_cache_ttl_millis,
+            "cache_ttl_jitter_percent" => &CONFIG.cache_ttl_jitter_percent,
+            "disk_retention_millis" => &CONFIG.disk_retention_millis,
             "cache_dir" => &CONFIG.cache_dir,
+            "cache_namespace" => &CONFIG.cache_namespace,
+            "cache_tiers" => &CONFIG.cache_tiers.join(","),
+            "cache_key_format" => CONFIG.cache_key_format.as_str(),
+            "cache_key_dual_read" => &CONFIG.cache_key_dual_read,
+            "max_cache_entries" => &CONFIG.max_cache_entries,
+            "max_index_bytes" => &CONFIG.max_index_bytes,
+            "orphan_quarantine" => &CONFIG.orphan_quarantine,
+            "cleanup_dry_run" => &CONFIG.cleanup_dry_run,
+            "orphan_quarantine_seconds" => &CONFIG.orphan_quarantine_seconds,
+            "strict_freshness" => &CONFIG.strict_freshness,
+            "grace_period_millis" => &CONFIG.grace_period_millis,
+            "admission_min_requests" => &CONFIG.admission_min_requests,
+            "admission_window_seconds" => &CONFIG.admission_window_seconds,
+            "negative_cache_seconds" => &CONFIG.negative_cache_seconds,
             "http_expiry_seconds" => &CONFIG.http_expiry_seconds,
+            "request_deadline_ms" => &CONFIG.request_deadline_ms,
             "default_file_ext" => &CONFIG.default_file_ext,
             "cleanup_delay_seconds" => &CONFIG.cleanup_delay_seconds,
             "cleanup_interval_seconds" => &CONFIG.cleanup_interval_seconds,
+            "blocking_io_threads" => &CONFIG.blocking_io_threads,
+            "stream_threshold_bytes" => &CONFIG.stream_threshold_bytes,
+            "minify_svg" => &CONFIG.minify_svg,
+            "refresh_window" => &CONFIG.refresh_window,
+            "refresh_window_rps" => &CONFIG.refresh_window_rps,
+            "disk_full_backoff_seconds" => &CONFIG.disk_full_backoff_seconds,
+            "failover_mode" => CONFIG.failover_mode.as_str(),
+            "proxy_only" => &CONFIG.proxy_only,
+            "route_aliases" => &CONFIG
+                .route_aliases
+                .iter()
+                .map(|(path, kind)| format!("{}={}", path, kind.as_str()))
+                .collect::<Vec<_>>()
+                .join(","),
+            "canonical_redirects" => &CONFIG.canonical_redirects,
+            "deprecated_routes" => &CONFIG
+                .deprecated_routes
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            "kind_url_templates" => &CONFIG
+                .kind_url_templates
+                .iter()
+                .map(|(kind, template)| format!("{}={}", kind.as_str(), template))
+                .collect::<Vec<_>>()
+                .join(","),
+            "kind_shadow_url_templates" => &CONFIG
+                .kind_shadow_url_templates
+                .iter()
+                .map(|(kind, template)| format!("{}={}", kind.as_str(), template))
+                .collect::<Vec<_>>()
+                .join(","),
+            "shadow_sample_percent" => &CONFIG.shadow_sample_percent,
+            "read_only" => &CONFIG.read_only,
+            "dev_mode" => &CONFIG.dev_mode,
+            "persist_index" => &CONFIG.persist_index,
+            "robots_disallow_all" => &CONFIG.robots_disallow_all,
+            "robots_disallow" => &CONFIG.robots_disallow.join(","),
+            "sitemap_base_url" => &CONFIG.sitemap_base_url,
+            "logo_dir" => &CONFIG.logo_dir,
+            "locale_dir" => &CONFIG.locale_dir,
+            "debug_token_set" => CONFIG.debug_token.is_some(),
+            "admin_token_set" => CONFIG.admin_token.is_some(),
+            "admin_token_previous_count" => CONFIG.admin_token_previous.len(),
+            "admin_session_seconds" => &CONFIG.admin_session_seconds,
+            "api_keys_count" => CONFIG.api_keys.len(),
+            "api_key_daily_quota" => &CONFIG.api_key_daily_quota,
+            "api_key_quotas" => &CONFIG
+                .api_key_quotas
+                .iter()
+                .map(|(fingerprint, quota)| format!("{}={}", fingerprint, quota))
+                .collect::<Vec<_>>()
+                .join(","),
+            "basic_auth_users_count" => CONFIG.basic_auth_users.len(),
+            "auth_route_groups" => &CONFIG
+                .auth_route_groups
+                .iter()
+                .map(|(g, r)| format!("{}={:?}", g.as_str(), r).to_lowercase())
+                .collect::<Vec<_>>()
+                .join(","),
+            "reset_signing_key_set" => CONFIG.reset_signing_key.is_some(),
+            "reset_signing_key_previous_count" => CONFIG.reset_signing_key_previous.len(),
+            "reset_link_ttl_seconds" => &CONFIG.reset_link_ttl_seconds,
+            "public_purge" => &CONFIG.public_purge,
+            "crates_io_api_url" => &CONFIG.crates_io_api_url,
+            "peer_sync_url" => &CONFIG.peer_sync_url,
+            "peer_urls" => &CONFIG.peer_urls.join(","),
+            "cluster_nodes" => &CONFIG.cluster_nodes.join(","),
+            "cluster_self" => &CONFIG.cluster_self,
+            "audit_log_file" => &CONFIG.audit_log_file,
+            "history_file" => &CONFIG.history_file,
+            "history_max_snapshots" => &CONFIG.history_max_snapshots,
+            "statsd_addr" => &CONFIG.statsd_addr,
+            "statsd_prefix" => &CONFIG.statsd_prefix,
+            "upstream_max_rps" => &CONFIG.upstream_max_rps,
+            "upstream_probe_url" => &CONFIG.upstream_probe_url,
+            "upstream_probe_interval_seconds" => &CONFIG.upstream_probe_interval_seconds,
+            "upstream_probe_failure_threshold" => &CONFIG.upstream_probe_failure_threshold,
+            "max_pending_misses" => &CONFIG.max_pending_misses,
+            "upstream_dns_cache" => &CONFIG.upstream_dns_cache,
+            "outbound_host_allowlist" => &CONFIG.outbound_host_allowlist.join(","),
+            "bot_deny_patterns" => &CONFIG.bot_deny_patterns.join(","),
+            "bot_throttle_patterns" => &CONFIG.bot_throttle_patterns.join(","),
+            "bot_throttle_rps" => &CONFIG.bot_throttle_rps,
+            "fault_upstream_error_rate" => &CONFIG.fault_upstream_error_rate,
+            "fault_disk_error_rate" => &CONFIG.fault_disk_error_rate,
+            "fault_latency_ms" => &CONFIG.fault_latency_ms,
+        );
+        let overrides = CONFIG_OVERRIDES
+            .lock()
+            .expect("config overrides lock poisoned");
+        for (key, default, value) in overrides.iter() {
+            slog::info!(
+                LOG, "config override";
+                "key" => key,
+                "default" => default,
+                "value" => value,
+            );
+        }
+        slog::info!(
+            LOG,
+            "{} config values differ from defaults",
+            overrides.len()
         );
         Ok(())
     }
 }
 
+/// Whether a config key names a secret, going by its name, so one
+/// read without `env_secret` still isn't shared
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_uppercase();
+    key.contains("PASSWORD")
+        || key.contains("SECRET")
+        || [
+            "_TOKEN",
+            "_TOKEN_PREVIOUS",
+            "_KEY",
+            "_KEYS",
+            "_KEY_PREVIOUS",
+            "_USERS",
+        ]
+        .iter()
+        .any(|suffix| key.ends_with(suffix))
+}
+
+/// The config as far as it's safe to share: every value that differs from
+/// its default (with secrets redacted), the optional values, and whether
+/// each secret is set
+pub fn config_snapshot() -> serde_json::Value {
+    let overrides = CONFIG_OVERRIDES
+        .lock()
+        .expect("config overrides lock poisoned")
+        .iter()
+        .map(|(key, default, value)| {
+            let value = if is_secret_key(key) {
+                REDACTED
+            } else {
+                value.as_str()
+            };
+            serde_json::json!({
+                "key": key,
+                "default": default,
+                "value": value,
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "version": CONFIG.version,
+        "overrides": overrides,
+        "cache_namespace": CONFIG.cache_namespace,
+        "refresh_window": CONFIG.refresh_window,
+        "sitemap_base_url": CONFIG.sitemap_base_url,
+        "logo_dir": CONFIG.logo_dir,
+        "peer_sync_url": CONFIG.peer_sync_url,
+        "cluster_self": CONFIG.cluster_self,
+        "audit_log_file": CONFIG.audit_log_file,
+        "history_file": CONFIG.history_file,
+        "statsd_addr": CONFIG.statsd_addr,
+        "upstream_probe_url": CONFIG.upstream_probe_url,
+        "debug_token_set": CONFIG.debug_token.is_some(),
+        "admin_token_set": CONFIG.admin_token.is_some(),
+        "admin_token_previous_count": CONFIG.admin_token_previous.len(),
+        "api_keys_count": CONFIG.api_keys.len(),
+        "basic_auth_users_count": CONFIG.basic_auth_users.len(),
+        "reset_signing_key_set": CONFIG.reset_signing_key.is_some(),
+        "reset_signing_key_previous_count": CONFIG.reset_signing_key_previous.len(),
+        "faults_enabled": faults::enabled(),
+    })
+}
+
 async fn run() -> anyhow::Result<()> {
     CONFIG.initialize()?;
+    blocking::initialize();
+    lazy_static::initialize(&cache::TIERS);
+    if CONFIG.cache_namespace.is_some() && !CONFIG.read_only {
+        std::fs::create_dir_all(cache::cache_dir())?;
+    }
+    health::initialize();
+    lazy_static::initialize(&refresh::WINDOW);
+    lazy_static::initialize(&processing::LOGOS);
+    lazy_static::initialize(&i18n::LOCALES);
+    lazy_static::initialize(&cluster::RING);
+    lazy_static::initialize(&metrics::BACKENDS);
+    if faults::enabled() {
+        slog::warn!(
+            LOG,
+            "fault injection is enabled, don't run this build in production"
+        );
+    }
+    if !CONFIG.read_only {
+        let recovered = cache::recover_cache_dir().await?;
+        slog::info!(
+            LOG, "checked cache dir for broken content";
+            "scanned" => recovered.scanned,
+            "empty" => recovered.empty,
+            "corrupt" => recovered.corrupt,
+            "partial" => recovered.partial,
+            "unreadable" => recovered.unreadable,
+        );
+    }
+    if CONFIG.persist_index {
+        let restored = cache::restore_index().await?;
+        slog::info!(
+            LOG, "restored cache index";
+            "restored" => restored.restored,
+            "expired" => restored.expired,
+            "missing" => restored.missing,
+        );
+    }
+    if CONFIG.peer_sync_url.is_some() && !CONFIG.read_only {
+        // a peer that can't be reached just means a cold start
+        match peer::sync_index().await {
+            Ok(available) => slog::info!(LOG, "synced peer cache index"; "available" => available),
+            Err(e) => slog::error!(LOG, "unable to sync peer cache index: {:?}", e),
+        }
+    }
     service::start().await?;
+
+    slog::info!(LOG, "server stopped, shutting down");
+    if CONFIG.persist_index && !CONFIG.read_only {
+        let persisted = cache::persist_index().await?;
+        slog::info!(LOG, "persisted cache index"; "entries" => persisted);
+    }
+    slog::info!(LOG, "shutdown complete"; "version" => &CONFIG.version);
+    Ok(())
+}
+
+/// Run a one-off command against the cache dir instead of starting the server
+async fn run_command(args: &[String]) -> anyhow::Result<()> {
+    let flag = |name: &str| {
+        args.iter()
+            .position(|a| a == name)
+            .and_then(|i| args.get(i + 1))
+            .ok_or_else(|| anyhow::anyhow!("missing value for {}", name))
+    };
+    match args[0].as_str() {
+        "export" => {
+            let out = flag("--out")?;
+            let count = bundle::export(std::path::Path::new(out))?;
+            println!("exported {} entries to {}", count, out);
+        }
+        "import" => {
+            let input = flag("--in")?;
+            let count = bundle::import(std::path::Path::new(input))?;
+            println!("imported {} entries from {}", count, input);
+        }
+        "smoke" => {
+            let base_url = flag("--base-url")?;
+            smoke::run(base_url).await?;
+        }
+        "clean" => {
+            let dry_run = args.iter().any(|a| a == "--dry-run");
+            lazy_static::initialize(&cache::TIERS);
+            let summary = cache::clean(dry_run).await?;
+            println!(
+                "cleaned cache dir{}: {} entries kept, {} expired, {} missing content",
+                if dry_run { " (dry run)" } else { "" },
+                summary.restored,
+                summary.expired,
+                summary.missing
+            );
+        }
+        "routes" => {
+            for route in service::route_table() {
+                println!(
+                    "{:<7} {:<48} {:<16} {:<28} {}",
+                    route.method,
+                    route.path,
+                    route.kind.map(|k| k.as_str()).unwrap_or("-"),
+                    route.handler,
+                    service::route_auth(&route.path),
+                );
+            }
+        }
+        other => anyhow::bail!(
+            "unknown command: {}, expected export, import, smoke, clean, or routes",
+            other
+        ),
+    }
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        if let Err(e) = run_command(&args).await {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // need to run with tokio's runtime so we can use tokio libs
     let local = tokio::task::LocalSet::new();
     let sys = actix_web::rt::System::run_in_tokio("server", &local);
@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+// upper bounds, in seconds, for latency histograms
+pub const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.];
+
+// upper bounds, in bytes, for content size histograms
+pub const SIZE_BUCKETS: &[f64] = &[256., 512., 1024., 2048., 4096., 8192., 16384., 65536.];
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    // non-cumulative counts per bucket, with a trailing +Inf bucket
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let ind = self
+            .bounds
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(self.bounds.len());
+        self.counts[ind] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Estimate the `q` quantile by interpolating within the bucket it falls in
+    pub fn quantile(&self, q: f64) -> f64 {
+        if self.count == 0 {
+            return 0.;
+        }
+        let rank = q * self.count as f64;
+        let mut seen = 0.;
+        for (i, count) in self.counts.iter().enumerate() {
+            let count = *count as f64;
+            if seen + count >= rank && count > 0. {
+                let lower = if i == 0 { 0. } else { self.bounds[i - 1] };
+                let upper = match self.bounds.get(i) {
+                    Some(b) => *b,
+                    // nothing to interpolate towards in the +Inf bucket
+                    None => return lower,
+                };
+                return lower + (upper - lower) * ((rank - seen) / count);
+            }
+            seen += count;
+        }
+        *self.bounds.last().unwrap_or(&0.)
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "count": self.count,
+            "sum": self.sum,
+            "p50": self.quantile(0.5),
+            "p90": self.quantile(0.9),
+            "p99": self.quantile(0.99),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<String, BTreeMap<Labels, u64>>,
+    gauges: BTreeMap<String, BTreeMap<Labels, f64>>,
+    histograms: BTreeMap<String, BTreeMap<Labels, Histogram>>,
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+
+    pub static ref BACKENDS: Vec<Box<dyn Backend>> = load_backends();
+}
+
+/// Somewhere metrics are sent. Every instrumentation point
+/// records to all of the configured backends.
+pub trait Backend: Send + Sync {
+    fn inc_by(&self, name: &str, labels: &[(&str, &str)], n: u64);
+
+    fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64);
+
+    fn observe(&self, name: &str, labels: &[(&str, &str)], value: f64, buckets: &[f64]);
+}
+
+fn load_backends() -> Vec<Box<dyn Backend>> {
+    // the registry backs /metrics and /stats, so it's always there
+    let mut backends: Vec<Box<dyn Backend>> = vec![Box::new(Prometheus)];
+    if let Some(addr) = &crate::CONFIG.statsd_addr {
+        let statsd = Statsd::new(addr, &crate::CONFIG.statsd_prefix).expect("invalid statsd_addr");
+        backends.push(Box::new(statsd));
+    }
+    backends
+}
+
+fn to_labels(labels: &[(&str, &str)]) -> Labels {
+    let mut labels = labels
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Vec<_>>();
+    labels.sort();
+    labels
+}
+
+pub fn inc(name: &str, labels: &[(&str, &str)]) {
+    inc_by(name, labels, 1);
+}
+
+pub fn inc_by(name: &str, labels: &[(&str, &str)], n: u64) {
+    for backend in BACKENDS.iter() {
+        backend.inc_by(name, labels, n);
+    }
+}
+
+pub fn set_gauge(name: &str, labels: &[(&str, &str)], value: f64) {
+    for backend in BACKENDS.iter() {
+        backend.set_gauge(name, labels, value);
+    }
+}
+
+pub fn observe(name: &str, labels: &[(&str, &str)], value: f64) {
+    observe_with_buckets(name, labels, value, LATENCY_BUCKETS);
+}
+
+pub fn observe_with_buckets(name: &str, labels: &[(&str, &str)], value: f64, buckets: &[f64]) {
+    for backend in BACKENDS.iter() {
+        backend.observe(name, labels, value, buckets);
+    }
+}
+
+/// The in-process registry, scraped in the prometheus format from /metrics
+pub struct Prometheus;
+impl Backend for Prometheus {
+    fn inc_by(&self, name: &str, labels: &[(&str, &str)], n: u64) {
+        let mut reg = REGISTRY.lock().expect("metrics lock poisoned");
+        *reg.counters
+            .entry(name.to_string())
+            .or_default()
+            .entry(to_labels(labels))
+            .or_insert(0) += n;
+    }
+
+    fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut reg = REGISTRY.lock().expect("metrics lock poisoned");
+        reg.gauges
+            .entry(name.to_string())
+            .or_default()
+            .insert(to_labels(labels), value);
+    }
+
+    fn observe(&self, name: &str, labels: &[(&str, &str)], value: f64, buckets: &[f64]) {
+        let mut reg = REGISTRY.lock().expect("metrics lock poisoned");
+        reg.histograms
+            .entry(name.to_string())
+            .or_default()
+            .entry(to_labels(labels))
+            .or_insert_with(|| Histogram::new(buckets))
+            .observe(value);
+    }
+}
+
+/// Pushes each metric as it's recorded to a statsd agent over udp, in
+/// the dogstatsd format with labels as tags. Sends are fire and forget.
+pub struct Statsd {
+    socket: std::net::UdpSocket,
+    prefix: String,
+}
+impl Statsd {
+    pub fn new(addr: &str, prefix: &str) -> std::io::Result<Self> {
+        use std::net::ToSocketAddrs;
+        let target = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "statsd address didn't resolve",
+            )
+        })?;
+        let bind = if target.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = std::net::UdpSocket::bind(bind)?;
+        socket.connect(target)?;
+        socket.set_nonblocking(true)?;
+        let prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}.", prefix.trim_end_matches('.'))
+        };
+        Ok(Self { socket, prefix })
+    }
+
+    fn send(&self, name: &str, value: String, kind: &str, labels: &[(&str, &str)]) {
+        let mut line = format!("{}{}:{}|{}", self.prefix, name, value, kind);
+        if !labels.is_empty() {
+            let tags = labels
+                .iter()
+                .map(|(k, v)| format!("{}:{}", k, v))
+                .collect::<Vec<_>>()
+                .join(",");
+            line.push_str("|#");
+            line.push_str(&tags);
+        }
+        // a dropped metric isn't worth holding anything up for
+        self.socket.send(line.as_bytes()).ok();
+    }
+}
+impl Backend for Statsd {
+    fn inc_by(&self, name: &str, labels: &[(&str, &str)], n: u64) {
+        self.send(name, n.to_string(), "c", labels);
+    }
+
+    fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.send(name, value.to_string(), "g", labels);
+    }
+
+    fn observe(&self, name: &str, labels: &[(&str, &str)], value: f64, _buckets: &[f64]) {
+        // the agent does its own aggregation
+        self.send(name, value.to_string(), "h", labels);
+    }
+}
+
+fn labels_key(labels: &[(String, String)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Counters, gauges, and histogram summaries as json for `/stats`
+pub fn snapshot() -> serde_json::Value {
+    let reg = REGISTRY.lock().expect("metrics lock poisoned");
+    let mut counters = serde_json::Map::new();
+    for (name, series) in reg.counters.iter() {
+        let series = series
+            .iter()
+            .map(|(labels, v)| (labels_key(labels), serde_json::json!(v)))
+            .collect::<serde_json::Map<_, _>>();
+        counters.insert(name.clone(), serde_json::Value::Object(series));
+    }
+    let mut gauges = serde_json::Map::new();
+    for (name, series) in reg.gauges.iter() {
+        let series = series
+            .iter()
+            .map(|(labels, v)| (labels_key(labels), serde_json::json!(v)))
+            .collect::<serde_json::Map<_, _>>();
+        gauges.insert(name.clone(), serde_json::Value::Object(series));
+    }
+    let mut histograms = serde_json::Map::new();
+    for (name, series) in reg.histograms.iter() {
+        let series = series
+            .iter()
+            .map(|(labels, h)| (labels_key(labels), h.summary()))
+            .collect::<serde_json::Map<_, _>>();
+        histograms.insert(name.clone(), serde_json::Value::Object(series));
+    }
+    serde_json::json!({
+        "counters": counters,
+        "gauges": gauges,
+        "histograms": histograms,
+    })
+}
+
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_labels(labels: &[(String, String)], extra: Option<(&str, &str)>) -> String {
+    let mut parts = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+        .collect::<Vec<_>>();
+    if let Some((k, v)) = extra {
+        parts.push(format!("{}=\"{}\"", k, escape_label_value(v)));
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+/// Render everything in the prometheus text exposition format
+pub fn render() -> String {
+    let reg = REGISTRY.lock().expect("metrics lock poisoned");
+    let mut out = String::new();
+    for (name, series) in reg.counters.iter() {
+        writeln!(out, "# TYPE {} counter", name).ok();
+        for (labels, v) in series.iter() {
+            writeln!(out, "{}{} {}", name, format_labels(labels, None), v).ok();
+        }
+    }
+    for (name, series) in reg.gauges.iter() {
+        writeln!(out, "# TYPE {} gauge", name).ok();
+        for (labels, v) in series.iter() {
+            writeln!(out, "{}{} {}", name, format_labels(labels, None), v).ok();
+        }
+    }
+    for (name, series) in reg.histograms.iter() {
+        writeln!(out, "# TYPE {} histogram", name).ok();
+        for (labels, h) in series.iter() {
+            let mut cumulative = 0;
+            for (i, count) in h.counts.iter().enumerate() {
+                cumulative += count;
+                let le = match h.bounds.get(i) {
+                    Some(b) => b.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    name,
+                    format_labels(labels, Some(("le", &le))),
+                    cumulative
+                )
+                .ok();
+            }
+            writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), h.sum).ok();
+            writeln!(
+                out,
+                "{}_count{} {}",
+                name,
+                format_labels(labels, None),
+                h.count
+            )
+            .ok();
+        }
+    }
+    out
+}
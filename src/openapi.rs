@@ -0,0 +1,448 @@
+use serde_json::{json, Map, Value};
+
+use crate::service::Kind;
+use crate::CONFIG;
+
+fn path_param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "description": description,
+        "schema": {"type": "string"},
+    })
+}
+
+fn badge_params(kind: Kind) -> Value {
+    let (min, max) = kind.name_segments();
+    let name = if max > 1 {
+        format!("{} to {} `/` separated segments, e.g. owner/repo", min, max)
+    } else {
+        "badge name".to_string()
+    };
+    json!([
+        path_param("name", &name),
+        {
+            "name": "ext",
+            "in": "path",
+            "required": true,
+            "schema": {"type": "string", "enum": ["svg", "png", "json"]},
+        },
+    ])
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({"$ref": format!("#/components/schemas/{}", name)})
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": {"application/json": {"schema": schema}},
+    })
+}
+
+fn admin_operation(summary: &str, request_body: Option<&str>, responses: Value) -> Value {
+    let mut op = json!({
+        "tags": ["admin"],
+        "summary": summary,
+        "security": [{"admin": []}],
+        "responses": responses,
+    });
+    op["responses"]["401"] = json!({"description": "missing or wrong admin token"});
+    op["responses"]["404"] = json!({"description": "admin api disabled, ADMIN_TOKEN isn't set"});
+    if let Some(schema) = request_body {
+        op["requestBody"] = json!({
+            "required": true,
+            "content": {"application/json": {"schema": schema_ref(schema)}},
+        });
+    }
+    op
+}
+
+/// Paths for every kind's badge, proxy, and reset routes
+fn badge_paths(paths: &mut Map<String, Value>) {
+    for kind in Kind::ALL.iter().copied() {
+        let path = format!("{}/{{name}}.{{ext}}", kind.route());
+        let params = badge_params(kind);
+        paths.insert(
+            path.clone(),
+            json!({
+                "parameters": params,
+                "get": {
+                    "tags": ["badges"],
+                    "summary": format!("{} badge, from the cache when possible", kind.as_str()),
+                    "responses": {
+                        "200": {
+                            "description": "the badge",
+                            "content": {
+                                "image/svg+xml": {},
+                                "image/png": {},
+                                "application/json": {},
+                            },
+                        },
+                        "304": {"description": "unchanged since If-Modified-Since"},
+                        "404": {"description": "upstream doesn't know this badge"},
+                    },
+                },
+            }),
+        );
+        paths.insert(
+            format!("/proxy{}", path),
+            json!({
+                "parameters": params,
+                "get": {
+                    "tags": ["badges"],
+                    "summary": format!("{} badge, straight from upstream", kind.as_str()),
+                    "responses": {"200": {"description": "the upstream response"}},
+                },
+            }),
+        );
+        paths.insert(
+            format!("/reset{}", path),
+            json!({
+                "parameters": params,
+                "delete": {
+                    "tags": ["reset"],
+                    "summary": format!("drop the cached {} badge", kind.as_str()),
+                    "responses": {"200": {"description": "reset"}},
+                },
+            }),
+        );
+    }
+}
+
+fn insert(map: &mut Map<String, Value>, key: &str, value: Value) {
+    map.insert(key.to_string(), value);
+}
+
+/// Paths for resets, stats, and the rest of the public api
+fn api_paths(paths: &mut Map<String, Value>) {
+    insert(
+        paths,
+        "/reset/recent",
+        json!({"get": {
+            "tags": ["reset"],
+            "summary": "recently reset badges",
+            "responses": {"200": json_response("recent resets", schema_ref("RecentResets"))},
+        }}),
+    );
+    insert(
+        paths,
+        "/reset/signed",
+        json!({"post": {
+            "tags": ["reset"],
+            "summary": "reset a badge with a signed link",
+            "parameters": [
+                {"name": "key", "in": "query", "required": true, "schema": {"type": "string"}},
+                {"name": "expires", "in": "query", "required": true, "schema": {"type": "integer"}},
+                {"name": "sig", "in": "query", "required": true, "schema": {"type": "string"}},
+            ],
+            "responses": {
+                "200": {"description": "reset"},
+                "403": {"description": "bad or expired signature"},
+            },
+        }}),
+    );
+    insert(
+        paths,
+        "/purge/crate/{name}",
+        json!({"post": {
+            "tags": ["reset"],
+            "summary": "drop every cached badge for a crate",
+            "parameters": [path_param("name", "crate name")],
+            "responses": {"200": {"description": "purged"}},
+        }}),
+    );
+    insert(
+        paths,
+        "/status",
+        json!({"get": {
+            "tags": ["stats"],
+            "summary": "liveness and version",
+            "responses": {"200": json_response("ok", schema_ref("Status"))},
+        }}),
+    );
+    insert(
+        paths,
+        "/health",
+        json!({"get": {
+            "tags": ["stats"],
+            "summary": "cache dir, upstream, and maintenance health",
+            "responses": {
+                "200": json_response("healthy", json!({"type": "object"})),
+                "503": json_response("unhealthy", json!({"type": "object"})),
+            },
+        }}),
+    );
+    insert(
+        paths,
+        "/stats",
+        json!({"get": {
+            "tags": ["stats"],
+            "summary": "cache size, upstream scheduling, and metric values",
+            "responses": {"200": json_response("stats", schema_ref("Stats"))},
+        }}),
+    );
+    insert(
+        paths,
+        "/metrics",
+        json!({"get": {
+            "tags": ["stats"],
+            "summary": "prometheus metrics",
+            "responses": {"200": {"description": "metrics", "content": {"text/plain": {}}}},
+        }}),
+    );
+    insert(
+        paths,
+        "/api",
+        json!({"get": {
+            "tags": ["stats"],
+            "summary": "index of badge routes",
+            "responses": {"200": json_response("routes", json!({"type": "object"}))},
+        }}),
+    );
+    insert(
+        paths,
+        "/api/crate/{name}/version",
+        json!({"get": {
+            "tags": ["stats"],
+            "summary": "a crate's version, from its cached badge or crates.io",
+            "parameters": [path_param("name", "crate name")],
+            "responses": {
+                "200": json_response("the version", schema_ref("CrateVersion")),
+                "404": {"description": "no such crate"},
+            },
+        }}),
+    );
+    insert(
+        paths,
+        "/api/history/{key}",
+        json!({"get": {
+            "tags": ["stats"],
+            "summary": "daily snapshots of a badge, when HISTORY_FILE is set",
+            "parameters": [path_param("key", "cache key, url-encoded")],
+            "responses": {
+                "200": json_response("the timeline", json!({"type": "object"})),
+                "404": {"description": "no history for the key, or history disabled"},
+            },
+        }}),
+    );
+    insert(
+        paths,
+        "/api/v1/badges",
+        json!({"get": {
+            "tags": ["stats"],
+            "summary": "cached badges, filtered and paginated",
+            "parameters": [
+                {"name": "kind", "in": "query", "schema": {"type": "string"}},
+                {"name": "prefix", "in": "query", "schema": {"type": "string"}},
+                {"name": "sort", "in": "query", "schema": {"type": "string", "enum": ["name", "hits", "age"]}},
+                {"name": "limit", "in": "query", "schema": {"type": "integer"}},
+                {"name": "offset", "in": "query", "schema": {"type": "integer"}},
+            ],
+            "responses": {"200": json_response("badges", json!({"type": "object"}))},
+        }}),
+    );
+}
+
+/// Paths for the bearer token protected admin api
+fn admin_paths(paths: &mut Map<String, Value>) {
+    insert(
+        paths,
+        "/admin/index",
+        json!({"get": admin_operation(
+            "every cache entry, for syncing a peer",
+            None,
+            json!({"200": json_response(
+                "entries",
+                json!({"type": "array", "items": schema_ref("CachedFile")}),
+            )}),
+        )}),
+    );
+    insert(
+        paths,
+        "/admin/content/{file_name}",
+        json!({
+            "parameters": [path_param("file_name", "content-addressed file name")],
+            "get": admin_operation(
+                "a cached badge body",
+                None,
+                json!({"200": {"description": "the body", "content": {"application/octet-stream": {}}}}),
+            ),
+        }),
+    );
+    insert(
+        paths,
+        "/admin/invalidate",
+        json!({"post": admin_operation(
+            "drop a cache entry, sent by peers",
+            Some("Invalidation"),
+            json!({"200": {"description": "invalidated"}}),
+        )}),
+    );
+    insert(
+        paths,
+        "/admin/maintenance",
+        json!({"put": admin_operation(
+            "turn maintenance mode on or off",
+            Some("Maintenance"),
+            json!({"200": {"description": "updated"}}),
+        )}),
+    );
+    insert(
+        paths,
+        "/admin/cache-dir",
+        json!({"post": admin_operation(
+            "switch the cache dir, optionally moving cached content over",
+            Some("CacheDirSwitch"),
+            json!({"200": {"description": "switched"}}),
+        )}),
+    );
+    for kind in Kind::ALL.iter().copied() {
+        insert(
+            paths,
+            &format!("/admin/reset-link{}/{{name}}", kind.route()),
+            json!({
+                "parameters": [path_param("name", "badge name, with its ext and query")],
+                "post": admin_operation(
+                    "a signed link that resets the badge",
+                    None,
+                    json!({"200": json_response("the link", json!({"type": "object"}))}),
+                ),
+            }),
+        );
+    }
+}
+
+fn schemas() -> Map<String, Value> {
+    let mut schemas = Map::new();
+    insert(
+        &mut schemas,
+        "Status",
+        json!({
+            "type": "object",
+            "properties": {
+                "status": {"type": "string"},
+                "version": {"type": "string"},
+                "upstream": {"type": "object", "nullable": true},
+            },
+        }),
+    );
+    insert(
+        &mut schemas,
+        "Stats",
+        json!({
+            "type": "object",
+            "properties": {
+                "cache_entries": {"type": "integer"},
+                "cache_index_bytes": {"type": "integer"},
+                "upstream": {"type": "object"},
+                "metrics": {"type": "object"},
+            },
+        }),
+    );
+    let reset = json!({
+        "type": "object",
+        "properties": {
+            "path": {"type": "string"},
+            "cache_name": {"type": "string"},
+            "reset_at": {"type": "string", "format": "date-time"},
+        },
+    });
+    insert(
+        &mut schemas,
+        "RecentResets",
+        json!({
+            "type": "object",
+            "properties": {"resets": {"type": "array", "items": reset}},
+        }),
+    );
+    insert(
+        &mut schemas,
+        "CrateVersion",
+        json!({
+            "type": "object",
+            "properties": {
+                "version": {"type": "string"},
+                "as_of": {"type": "string", "format": "date-time"},
+                "source": {"type": "string", "enum": ["cache", "crates.io"]},
+            },
+        }),
+    );
+    insert(
+        &mut schemas,
+        "CachedFile",
+        json!({
+            "type": "object",
+            "required": ["cache_name", "created_millis", "ttl_millis", "ext"],
+            "properties": {
+                "cache_name": {"type": "string"},
+                "kind": {"type": "string"},
+                "name": {"type": "string"},
+                "upstream_url": {"type": "string"},
+                "created_millis": {"type": "integer"},
+                "ttl_millis": {"type": "integer"},
+                "ext": {"type": "string"},
+                "content_hash": {"type": "string", "nullable": true},
+                "upstream_etag": {"type": "string", "nullable": true},
+            },
+        }),
+    );
+    insert(
+        &mut schemas,
+        "Invalidation",
+        json!({
+            "type": "object",
+            "required": ["cache_name"],
+            "properties": {"cache_name": {"type": "string"}},
+        }),
+    );
+    insert(
+        &mut schemas,
+        "Maintenance",
+        json!({
+            "type": "object",
+            "required": ["enabled"],
+            "properties": {
+                "enabled": {"type": "boolean"},
+                "message": {"type": "string"},
+            },
+        }),
+    );
+    insert(
+        &mut schemas,
+        "CacheDirSwitch",
+        json!({
+            "type": "object",
+            "required": ["dir"],
+            "properties": {
+                "dir": {"type": "string"},
+                "migrate": {"type": "boolean"},
+            },
+        }),
+    );
+    schemas
+}
+
+/// An OpenAPI 3 description of the service, built from the same kind
+/// registry the routes are
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    badge_paths(&mut paths);
+    api_paths(&mut paths);
+    admin_paths(&mut paths);
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "badge-cache",
+            "version": CONFIG.version,
+            "description": "An img.shields.io cache",
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {"admin": {"type": "http", "scheme": "bearer"}},
+            "schemas": schemas(),
+        },
+    })
+}
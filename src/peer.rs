@@ -0,0 +1,142 @@
+use actix_web::rt;
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use crate::cache::{self, CachedFile};
+use crate::errors::{self, ServiceError};
+use crate::{CONFIG, LOG};
+
+lazy_static::lazy_static! {
+    // what the peer had cached at startup, by cache key. entries are
+    // taken out the first time they're missed here, whether or not
+    // the peer still has them, so each is only ever tried once
+    static ref PEER_INDEX: DashMap<String, CachedFile> = DashMap::new();
+}
+
+/// Peers' admin apis are called with our own admin token
+fn admin_headers() -> errors::Result<reqwest::header::HeaderMap> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(token) = &CONFIG.admin_token {
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            format!("Bearer {}", token)
+                .parse()
+                .map_err(ServiceError::internal)?,
+        );
+    }
+    Ok(headers)
+}
+
+/// Make a request to the sync peer's admin api
+async fn get(path: &str) -> errors::Result<reqwest::Response> {
+    let base = match &CONFIG.peer_sync_url {
+        Some(url) => url.trim_end_matches('/'),
+        None => return Err(ServiceError::internal("no peer configured")),
+    };
+    let url = format!("{}{}", base, path);
+    let resp = crate::upstream::get_with_headers(&url, admin_headers()?).await?;
+    if !resp.status().is_success() {
+        return Err(ServiceError::UpstreamStatus {
+            url,
+            status: resp.status().as_u16(),
+        });
+    }
+    Ok(resp)
+}
+
+/// Load the peer's cache index so misses here can be filled from the
+/// peer instead of upstream. Entries that are expired or already cached
+/// here are skipped. Returns the number of entries that can be pulled.
+pub async fn sync_index() -> errors::Result<usize> {
+    let entries = get("/admin/index").await?.json::<Vec<CachedFile>>().await?;
+    let now = cache::now_millis();
+    for entry in entries {
+        if cache::is_expired(&entry, now) || cache::refreshed_millis(&entry.cache_name).is_some() {
+            continue;
+        }
+        PEER_INDEX.insert(entry.cache_name.clone(), entry);
+    }
+    Ok(PEER_INDEX.len())
+}
+
+/// Pull a missing entry and its content from the peer, if the peer had it.
+/// Anything going wrong is logged and left for upstream to fill.
+pub async fn fetch(cache_name: &str) -> Option<(CachedFile, Bytes)> {
+    let (_, entry) = PEER_INDEX.remove(cache_name)?;
+    if cache::is_expired(&entry, cache::now_millis()) {
+        return None;
+    }
+    let (file_name, hash) = match (entry.file_name(), &entry.content_hash) {
+        (Some(f), Some(h)) => (f, h),
+        _ => return None,
+    };
+    let bytes = match get(&format!("/admin/content/{}", file_name)).await {
+        Ok(resp) => resp.bytes().await.map_err(ServiceError::from),
+        Err(e) => Err(e),
+    };
+    let result = match bytes {
+        Ok(bytes) if &cache::content_hash(&bytes) == hash => Ok(bytes),
+        Ok(_) => Err(ServiceError::internal("content hash mismatch")),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(bytes) => {
+            crate::metrics::inc("peer_sync_total", &[("result", "hit")]);
+            Some((entry, bytes))
+        }
+        Err(e) => {
+            crate::metrics::inc("peer_sync_total", &[("result", "error")]);
+            slog::warn!(LOG, "unable to pull {} from peer: {:?}", cache_name, e);
+            None
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Invalidation {
+    pub cache_name: String,
+}
+
+async fn send_invalidation(peer: String, cache_name: String) {
+    let url = format!("{}/admin/invalidate", peer.trim_end_matches('/'));
+    let body = Invalidation {
+        cache_name: cache_name.clone(),
+    };
+    let result = match admin_headers() {
+        Ok(headers) => crate::upstream::post_json(&url, headers, &body).await,
+        Err(e) => Err(e),
+    };
+    let result = match result {
+        Ok(resp) if resp.status().is_success() => "sent",
+        Ok(resp) => {
+            slog::warn!(
+                LOG,
+                "peer {} rejected invalidation of {}: {}",
+                peer,
+                cache_name,
+                resp.status()
+            );
+            "error"
+        }
+        Err(e) => {
+            slog::warn!(
+                LOG,
+                "unable to send invalidation of {} to {}: {:?}",
+                cache_name,
+                peer,
+                e
+            );
+            "error"
+        }
+    };
+    crate::metrics::inc("peer_invalidations_sent_total", &[("result", result)]);
+}
+
+/// Tell every peer to drop their copy of a key, in the background.
+/// Peers don't pass invalidations on, so this is only called for
+/// changes made here.
+pub fn broadcast_invalidation(cache_name: &str) {
+    for peer in CONFIG.peer_urls.iter() {
+        rt::spawn(send_invalidation(peer.clone(), cache_name.to_string()));
+    }
+}
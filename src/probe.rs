@@ -0,0 +1,46 @@
+use actix_web::rt;
+
+use crate::{CONFIG, LOG};
+
+/// Request `UPSTREAM_PROBE_URL` every `UPSTREAM_PROBE_INTERVAL_SECONDS`,
+/// recording how it went so upstream trouble shows up before user
+/// requests run into it
+pub async fn run() {
+    let url = match &CONFIG.upstream_probe_url {
+        Some(url) => url,
+        None => return,
+    };
+    let mut interval = rt::time::interval(std::time::Duration::from_secs(
+        CONFIG.upstream_probe_interval_seconds,
+    ));
+    loop {
+        interval.tick().await;
+        let start = std::time::Instant::now();
+        let result = crate::upstream::get(url)
+            .await
+            .map(|resp| resp.status().as_u16())
+            .map_err(|e| e.to_string());
+        let latency = start.elapsed();
+        crate::metrics::observe(
+            "upstream_probe_duration_seconds",
+            &[],
+            latency.as_secs_f64(),
+        );
+        let label = match &result {
+            Ok(status) => status.to_string(),
+            Err(_) => "error".to_string(),
+        };
+        crate::metrics::inc("upstream_probes_total", &[("status", label.as_str())]);
+        let was_down = crate::health::upstream_down();
+        crate::health::record_probe(result, latency);
+        match (was_down, crate::health::upstream_down()) {
+            (false, true) => {
+                slog::warn!(LOG, "upstream probe failing, marking upstream down"; "url" => url)
+            }
+            (true, false) => {
+                slog::info!(LOG, "upstream probe recovered, marking upstream up"; "url" => url)
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::{CONFIG, LOG};
+
+lazy_static::lazy_static! {
+    // logo name (file stem) -> data uri, from `LOGO_DIR`
+    pub static ref LOGOS: HashMap<String, String> = load_logos();
+}
+
+// logos are drawn this size, left of the label, like shields does
+const LOGO_SIZE: f64 = 14.;
+const LOGO_PADDING: f64 = 3.;
+
+// shields' named colors, and their dark theme replacements
+const DARK_PALETTE: &[(&str, &str)] = &[
+    // label background
+    ("#555", "#2f2f2f"),
+    ("#4c1", "#2f7a0b"),
+    ("#97ca00", "#5f7f00"),
+    ("#a4a61d", "#6a6b13"),
+    ("#dfb317", "#8f730f"),
+    ("#fe7d37", "#a84f1f"),
+    ("#e05d44", "#9a3a28"),
+    ("#007ec6", "#005283"),
+    ("#9f9f9f", "#5c5c5c"),
+    // text
+    ("#fff", "#e6e6e6"),
+];
+
+/// A server-side color scheme for badges, for sites whose theme
+/// upstream doesn't have a match for
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Dark,
+}
+impl Theme {
+    pub fn parse(s: &str) -> Option<Theme> {
+        match s {
+            "dark" => Some(Theme::Dark),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Theme::Dark => "dark",
+        }
+    }
+
+    pub fn query_param(&self) -> String {
+        format!("theme={}", self.as_str())
+    }
+
+    fn palette(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Theme::Dark => DARK_PALETTE,
+        }
+    }
+}
+
+/// A change made to a badge's svg
+pub enum Transform {
+    /// Tack a "cached 3h ago" section on to the right of the badge,
+    /// done on the way out, the cached copy is left as is
+    ShowAge { age_millis: u128 },
+    /// Swap shields' colors for the theme's, done before storing
+    Theme(Theme),
+    /// Drop what doesn't change how the badge renders, done before storing
+    Minify,
+    /// Embed a logo from `LOGO_DIR`, done before storing
+    Logo(String),
+}
+impl Transform {
+    fn name(&self) -> &'static str {
+        match self {
+            Transform::ShowAge { .. } => "show_age",
+            Transform::Theme(_) => "theme",
+            Transform::Minify => "minify",
+            Transform::Logo(_) => "logo",
+        }
+    }
+
+    fn apply(&self, svg: &str) -> Option<String> {
+        match self {
+            Transform::ShowAge { age_millis } => append_section(
+                svg,
+                &format!("cached {}", format_age(*age_millis)),
+                "#9f9f9f",
+            ),
+            Transform::Theme(theme) => recolor(svg, theme.palette()),
+            Transform::Logo(name) => prepend_logo(svg, logo(name)?),
+            Transform::Minify => {
+                let minified = minify(svg)?;
+                crate::metrics::inc_by(
+                    "svg_minify_bytes_total",
+                    &[("stage", "before")],
+                    svg.len() as u64,
+                );
+                crate::metrics::inc_by(
+                    "svg_minify_bytes_total",
+                    &[("stage", "after")],
+                    minified.len() as u64,
+                );
+                Some(minified)
+            }
+        }
+    }
+}
+
+/// Read every `.svg` and `.png` in `LOGO_DIR`. Names are limited to what
+/// can go in a query string and a cache key without escaping.
+fn load_logos() -> HashMap<String, String> {
+    let mut logos = HashMap::new();
+    let dir = match &CONFIG.logo_dir {
+        Some(dir) => dir,
+        None => return logos,
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            slog::error!(LOG, "unable to read logo dir {}: {:?}", dir, e);
+            return logos;
+        }
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let mime = match path.extension().and_then(|e| e.to_str()) {
+            Some("svg") => "image/svg+xml",
+            Some("png") => "image/png",
+            _ => continue,
+        };
+        let name = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(name)
+                if name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                name.to_string()
+            }
+            _ => {
+                slog::warn!(LOG, "skipping logo with unusable name: {:?}", path);
+                continue;
+            }
+        };
+        match std::fs::read(&path) {
+            Ok(bytes) => {
+                let uri = format!("data:{};base64,{}", mime, crate::text::base64(&bytes));
+                logos.insert(name, uri);
+            }
+            Err(e) => slog::error!(LOG, "unable to read logo {:?}: {:?}", path, e),
+        }
+    }
+    slog::info!(LOG, "loaded logos"; "dir" => dir, "count" => logos.len());
+    logos
+}
+
+/// The data uri of a logo in `LOGO_DIR`
+pub fn logo(name: &str) -> Option<&'static str> {
+    LOGOS.get(name).map(|uri| uri.as_str())
+}
+
+/// Run each transform over an svg in turn. A transform that can't make
+/// sense of the svg is skipped rather than failing the request.
+pub fn apply(svg: &[u8], transforms: &[Transform]) -> Bytes {
+    let mut svg = match std::str::from_utf8(svg) {
+        Ok(s) => s.to_string(),
+        Err(_) => return Bytes::copy_from_slice(svg),
+    };
+    for transform in transforms {
+        match transform.apply(&svg) {
+            Some(transformed) => {
+                crate::metrics::inc(
+                    "svg_transforms_total",
+                    &[("transform", transform.name()), ("result", "ok")],
+                );
+                svg = transformed;
+            }
+            None => crate::metrics::inc(
+                "svg_transforms_total",
+                &[("transform", transform.name()), ("result", "skipped")],
+            ),
+        }
+    }
+    Bytes::from(svg)
+}
+
+/// Replace `fill` and `stop-color` values, each color is swapped
+/// at most once so palettes don't have to avoid chains
+fn recolor(svg: &str, palette: &[(&str, &str)]) -> Option<String> {
+    root_tag(svg)?;
+    let mut out = String::with_capacity(svg.len());
+    let mut rest = svg;
+    while let Some(i) = rest.find("=\"#") {
+        let attr_start = rest[..i].rfind(' ').map(|s| s + 1).unwrap_or(0);
+        let attr = &rest[attr_start..i];
+        let value_start = i + 2;
+        let value_end = match rest[value_start..].find('"') {
+            Some(e) => value_start + e,
+            None => break,
+        };
+        let value = &rest[value_start..value_end];
+        out.push_str(&rest[..value_start]);
+        let replacement = match attr {
+            "fill" | "stop-color" => palette
+                .iter()
+                .find(|(from, _)| from.eq_ignore_ascii_case(value))
+                .map(|(_, to)| *to),
+            _ => None,
+        };
+        out.push_str(replacement.unwrap_or(value));
+        rest = &rest[value_end..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+// attributes holding only coordinates and lengths, whose precision
+// can be cut. anything else (labels, urls) is left alone.
+const NUMERIC_ATTRS: &[&str] = &[
+    "x",
+    "y",
+    "x1",
+    "x2",
+    "y1",
+    "y2",
+    "cx",
+    "cy",
+    "r",
+    "rx",
+    "ry",
+    "width",
+    "height",
+    "d",
+    "transform",
+    "viewBox",
+    "textLength",
+    "font-size",
+    "stroke-width",
+    "points",
+];
+// decimal places kept in coordinates
+const MINIFY_PRECISION: usize = 2;
+
+/// Strip comments, whitespace between tags, and runs of whitespace,
+/// and cut the precision of numbers in coordinate attributes
+fn minify(svg: &str) -> Option<String> {
+    root_tag(svg)?;
+    let mut out = String::with_capacity(svg.len());
+    let mut rest = svg;
+    while let Some(i) = rest.find("<!--") {
+        out.push_str(&rest[..i]);
+        let end = rest[i..].find("-->")?;
+        rest = &rest[i + end + "-->".len()..];
+    }
+    out.push_str(rest);
+
+    let mut collapsed = String::with_capacity(out.len());
+    let mut in_tag = false;
+    let mut chars = out.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+                chars.next();
+            }
+            // whitespace between tags is dropped, anywhere else it's one space
+            let between_tags = !in_tag && collapsed.ends_with('>');
+            if !between_tags && chars.peek() != Some(&'<') && !collapsed.is_empty() {
+                collapsed.push(' ');
+            }
+            continue;
+        }
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ => (),
+        }
+        collapsed.push(c);
+    }
+    Some(shorten_numbers(&collapsed))
+}
+
+/// Round the numbers in `NUMERIC_ATTRS` values to `MINIFY_PRECISION`
+fn shorten_numbers(svg: &str) -> String {
+    let mut out = String::with_capacity(svg.len());
+    let mut rest = svg;
+    while let Some(i) = rest.find("=\"") {
+        let attr_start = rest[..i].rfind(' ').map(|s| s + 1).unwrap_or(0);
+        let attr = &rest[attr_start..i];
+        let value_start = i + 2;
+        let value_end = match rest[value_start..].find('"') {
+            Some(e) => value_start + e,
+            None => break,
+        };
+        out.push_str(&rest[..value_start]);
+        let value = &rest[value_start..value_end];
+        if NUMERIC_ATTRS.contains(&attr) {
+            out.push_str(&round_numbers(value));
+        } else {
+            out.push_str(value);
+        }
+        rest = &rest[value_end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn round_numbers(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut number = String::new();
+    let flush = |number: &mut String, out: &mut String| {
+        let decimals = number.split('.').nth(1).map(|d| d.len()).unwrap_or(0);
+        match number.parse::<f64>() {
+            Ok(n) if decimals > MINIFY_PRECISION => {
+                let rounded = format!("{:.*}", MINIFY_PRECISION, n);
+                out.push_str(rounded.trim_end_matches('0').trim_end_matches('.'));
+            }
+            _ => out.push_str(number),
+        }
+        number.clear();
+    };
+    for c in value.chars() {
+        if c.is_ascii_digit() || (c == '.' && !number.contains('.')) {
+            number.push(c);
+        } else {
+            flush(&mut number, &mut out);
+            out.push(c);
+        }
+    }
+    flush(&mut number, &mut out);
+    out
+}
+
+fn format_age(age_millis: u128) -> String {
+    let minutes = age_millis / 1000 / 60;
+    match minutes {
+        0 => "just now".to_string(),
+        m if m < 60 => format!("{}m ago", m),
+        m if m < 60 * 24 => format!("{}h ago", m / 60),
+        m => format!("{}d ago", m / 60 / 24),
+    }
+}
+
+/// The byte range of the root `<svg ...>` tag
+fn root_tag(svg: &str) -> Option<(usize, usize)> {
+    let start = svg.find("<svg")?;
+    let end = start + svg[start..].find('>')?;
+    Some((start, end))
+}
+
+/// A numeric attribute of a tag, `name` is matched with its leading
+/// space so `width` doesn't pick up `stroke-width`
+fn numeric_attr(tag: &str, name: &str) -> Option<f64> {
+    let needle = format!(" {}=\"", name);
+    let start = tag.find(&needle)? + needle.len();
+    let end = start + tag[start..].find('"')?;
+    tag[start..end].trim_end_matches("px").parse().ok()
+}
+
+fn set_attr(tag: &str, name: &str, value: &str) -> String {
+    let needle = format!(" {}=\"", name);
+    match tag.find(&needle) {
+        Some(i) => {
+            let start = i + needle.len();
+            let end = tag[start..]
+                .find('"')
+                .map(|e| start + e)
+                .unwrap_or(tag.len());
+            format!("{}{}{}", &tag[..start], value, &tag[end..])
+        }
+        None => tag.to_string(),
+    }
+}
+
+/// Widen a badge, shifting what's there to the right, and draw a logo
+/// in the space made, on the label's background
+fn prepend_logo(svg: &str, uri: &str) -> Option<String> {
+    let (start, end) = root_tag(svg)?;
+    let tag = &svg[start..end];
+    let width = numeric_attr(tag, "width")?;
+    let height = numeric_attr(tag, "height")?;
+    let shift = LOGO_SIZE + LOGO_PADDING * 2.;
+    let mut new_tag = set_attr(tag, "width", &(width + shift).to_string());
+    if tag.contains(" viewBox=\"0 0 ") {
+        new_tag = set_attr(
+            &new_tag,
+            "viewBox",
+            &format!("0 0 {} {}", width + shift, height),
+        );
+    }
+    if !new_tag.contains("xmlns:xlink") {
+        new_tag.push_str(r#" xmlns:xlink="http://www.w3.org/1999/xlink""#);
+    }
+    let close = svg.rfind("</svg>")?;
+    let logo = format!(
+        r##"<rect width="{w}" height="{h}" fill="#555"/><image x="{pad}" y="{y}" width="{size}" height="{size}" xlink:href="{uri}"/>"##,
+        w = shift,
+        h = height,
+        pad = LOGO_PADDING,
+        y = (height - LOGO_SIZE) / 2.,
+        size = LOGO_SIZE,
+        uri = uri,
+    );
+    Some(format!(
+        "{}{}>{}<g transform=\"translate({} 0)\">{}</g>{}",
+        &svg[..start],
+        new_tag,
+        logo,
+        shift,
+        &svg[end + 1..close],
+        &svg[close..]
+    ))
+}
+
+/// Widen a badge and draw a new section with `text` in the space made
+fn append_section(svg: &str, text: &str, fill: &str) -> Option<String> {
+    let (start, end) = root_tag(svg)?;
+    let tag = &svg[start..end];
+    let width = numeric_attr(tag, "width")?;
+    let height = numeric_attr(tag, "height")?;
+    // roughly the width of verdana 10px
+    let section_width = (text.chars().count() * 6 + 10) as f64;
+    let mut new_tag = set_attr(tag, "width", &(width + section_width).to_string());
+    if tag.contains(" viewBox=\"0 0 ") {
+        new_tag = set_attr(
+            &new_tag,
+            "viewBox",
+            &format!("0 0 {} {}", width + section_width, height),
+        );
+    }
+    let close = svg.rfind("</svg>")?;
+    let section = format!(
+        r##"<g><rect x="{x}" width="{w}" height="{h}" fill="{fill}"/><text x="{text_x}" y="{text_y}" fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="10">{text}</text></g>"##,
+        x = width,
+        w = section_width,
+        h = height,
+        fill = fill,
+        text_x = width + section_width / 2.,
+        text_y = height * 0.7,
+        text = text,
+    );
+    Some(format!(
+        "{}{}{}{}{}",
+        &svg[..start],
+        new_tag,
+        &svg[end..close],
+        section,
+        &svg[close..]
+    ))
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::errors::ServiceError;
+use crate::CONFIG;
+
+lazy_static::lazy_static! {
+    // api key fingerprint -> today's usage
+    static ref USAGE: Mutex<HashMap<String, Usage>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Default, Debug, Clone, serde::Serialize)]
+pub struct Usage {
+    // the UTC day these counts are for, yyyy-mm-dd
+    pub day: String,
+    pub requests: u64,
+    pub rejected: u64,
+}
+
+/// How an api key is referred to in config, usage, and logs, so the key
+/// itself never has to be written anywhere but API_KEYS
+pub fn fingerprint(key: &str) -> String {
+    crate::cache::content_hash(key.as_bytes())[..8].to_string()
+}
+
+/// A key's daily quota from API_KEY_QUOTAS or API_KEY_DAILY_QUOTA, 0 for unlimited
+fn limit(fingerprint: &str) -> u64 {
+    CONFIG
+        .api_key_quotas
+        .iter()
+        .find(|(f, _)| f == fingerprint)
+        .map(|(_, quota)| *quota)
+        .unwrap_or(CONFIG.api_key_daily_quota)
+}
+
+fn today() -> String {
+    chrono::Utc::now().format("%Y-%m-%d").to_string()
+}
+
+/// Seconds until quotas reset, at midnight UTC
+pub fn seconds_until_reset() -> u64 {
+    const DAY_SECONDS: i64 = 24 * 60 * 60;
+    (DAY_SECONDS - chrono::Utc::now().timestamp().rem_euclid(DAY_SECONDS)) as u64
+}
+
+/// Count a request made with `key`. Returns the key's limit and what's
+/// left of it today, or `QuotaExceeded` once it's used up.
+pub fn record(key: &str) -> Result<Option<(u64, u64)>, ServiceError> {
+    let fingerprint = fingerprint(key);
+    let limit = limit(&fingerprint);
+    let day = today();
+    let mut usage = USAGE.lock().expect("usage lock poisoned");
+    let usage = usage.entry(fingerprint).or_default();
+    if usage.day != day {
+        *usage = Usage {
+            day,
+            ..Usage::default()
+        };
+    }
+    if limit > 0 && usage.requests >= limit {
+        usage.rejected += 1;
+        return Err(ServiceError::QuotaExceeded {
+            limit,
+            reset_seconds: seconds_until_reset(),
+        });
+    }
+    usage.requests += 1;
+    if limit == 0 {
+        return Ok(None);
+    }
+    Ok(Some((limit, limit - usage.requests)))
+}
+
+/// Today's usage of every configured api key, for /admin/usage
+pub fn report() -> serde_json::Value {
+    let day = today();
+    let usage = USAGE.lock().expect("usage lock poisoned");
+    let keys = CONFIG
+        .api_keys
+        .iter()
+        .map(|key| {
+            let fingerprint = fingerprint(key);
+            let limit = limit(&fingerprint);
+            let today = usage
+                .get(&fingerprint)
+                .filter(|u| u.day == day)
+                .cloned()
+                .unwrap_or_default();
+            serde_json::json!({
+                "key": fingerprint,
+                "daily_quota": if limit == 0 { None } else { Some(limit) },
+                "requests": today.requests,
+                "rejected": today.rejected,
+                "remaining": if limit == 0 { None } else { Some(limit.saturating_sub(today.requests)) },
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "day": day,
+        "resets_in_seconds": seconds_until_reset(),
+        "keys": keys,
+    })
+}
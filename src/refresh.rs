@@ -0,0 +1,175 @@
+use actix_web::rt;
+use chrono::{Datelike, Timelike};
+
+use crate::cache::{self, Refreshed};
+use crate::{CONFIG, LOG};
+
+lazy_static::lazy_static! {
+    // when to proactively revalidate everything in the cache
+    pub static ref WINDOW: Option<Schedule> = CONFIG
+        .refresh_window
+        .as_ref()
+        .map(|s| s.parse().expect("invalid refresh_window"));
+}
+
+/// One field of a cron expression, the values it allows
+#[derive(Debug)]
+struct Field {
+    allowed: Vec<bool>,
+    // whether this was `*`, which matters for day-of-month/day-of-week
+    any: bool,
+}
+impl Field {
+    fn parse(s: &str, min: u32, max: u32) -> anyhow::Result<Field> {
+        let mut allowed = vec![false; max as usize + 1];
+        for part in s.split(',') {
+            let (range, step) = match part.find('/') {
+                Some(i) => (&part[..i], part[i + 1..].parse::<u32>()?),
+                None => (part, 1),
+            };
+            if step == 0 {
+                anyhow::bail!("invalid step in {}", part);
+            }
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some(i) = range.find('-') {
+                (range[..i].parse()?, range[i + 1..].parse()?)
+            } else {
+                let n = range.parse()?;
+                // `5/10` means every 10 starting at 5
+                (n, if part.contains('/') { max } else { n })
+            };
+            if start < min || end > max || start > end {
+                anyhow::bail!("{} out of range {}-{}", part, min, max);
+            }
+            for n in (start..=end).step_by(step as usize) {
+                allowed[n as usize] = true;
+            }
+        }
+        Ok(Field {
+            allowed,
+            any: s == "*",
+        })
+    }
+
+    fn matches(&self, n: u32) -> bool {
+        self.allowed.get(n as usize).copied().unwrap_or(false)
+    }
+}
+
+/// A cron-like `minute hour day-of-month month day-of-week` expression,
+/// evaluated in UTC. The window is open during every minute it matches,
+/// e.g. `* 3-4 * * *` is open from 03:00 until 05:00.
+#[derive(Debug)]
+pub struct Schedule {
+    minute: Field,
+    hour: Field,
+    day_of_month: Field,
+    month: Field,
+    day_of_week: Field,
+}
+impl Schedule {
+    pub fn is_open(&self, at: chrono::DateTime<chrono::Utc>) -> bool {
+        let day_of_month = self.day_of_month.matches(at.day());
+        let day_of_week = self
+            .day_of_week
+            .matches(at.weekday().num_days_from_sunday());
+        // like cron, when both days are restricted either one matching will do
+        let day = if self.day_of_month.any || self.day_of_week.any {
+            day_of_month && day_of_week
+        } else {
+            day_of_month || day_of_week
+        };
+        day && self.minute.matches(at.minute())
+            && self.hour.matches(at.hour())
+            && self.month.matches(at.month())
+    }
+}
+impl std::str::FromStr for Schedule {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            anyhow::bail!("expected 5 fields, got {}: {}", fields.len(), s);
+        }
+        // both 0 and 7 are sunday
+        let mut day_of_week = Field::parse(fields[4], 0, 7)?;
+        if day_of_week.allowed[7] {
+            day_of_week.allowed[0] = true;
+        }
+        Ok(Schedule {
+            minute: Field::parse(fields[0], 0, 59)?,
+            hour: Field::parse(fields[1], 0, 23)?,
+            day_of_month: Field::parse(fields[2], 1, 31)?,
+            month: Field::parse(fields[3], 1, 12)?,
+            day_of_week,
+        })
+    }
+}
+
+async fn refresh_pass(window: &Schedule) {
+    let keys = cache::keys().await;
+    slog::info!(
+        LOG,
+        "refresh window open, revalidating {} entries",
+        keys.len()
+    );
+    let spacing = if CONFIG.refresh_window_rps > 0. {
+        std::time::Duration::from_secs_f64(1. / CONFIG.refresh_window_rps)
+    } else {
+        std::time::Duration::from_secs(0)
+    };
+    let mut refreshed = 0;
+    for key in keys.iter() {
+        if crate::health::in_maintenance() {
+            slog::info!(
+                LOG,
+                "refresh window pass stopped for maintenance after {} of {} entries",
+                refreshed,
+                keys.len()
+            );
+            return;
+        }
+        if !window.is_open(chrono::Utc::now()) {
+            slog::info!(
+                LOG,
+                "refresh window closed after {} of {} entries",
+                refreshed,
+                keys.len()
+            );
+            return;
+        }
+        let result = match cache::refresh_entry(key).await {
+            Ok(Refreshed::Unchanged) => "unchanged",
+            Ok(Refreshed::Modified) => "modified",
+            Ok(Refreshed::Skipped) => "skipped",
+            Err(e) => {
+                slog::error!(LOG, "error refreshing {}: {:?}", key, e);
+                "error"
+            }
+        };
+        crate::metrics::inc("refresh_window_total", &[("result", result)]);
+        refreshed += 1;
+        rt::time::delay_for(spacing).await;
+    }
+    slog::info!(LOG, "refresh window pass complete, {} entries", refreshed);
+}
+
+/// Check every minute for the window opening, making one pass
+/// over the cache each time it does
+pub async fn run() {
+    let window = match WINDOW.as_ref() {
+        Some(w) => w,
+        None => return,
+    };
+    let mut interval = rt::time::interval(std::time::Duration::from_secs(60));
+    let mut was_open = false;
+    loop {
+        interval.tick().await;
+        let open = window.is_open(chrono::Utc::now());
+        if open && !was_open {
+            refresh_pass(window).await;
+        }
+        was_open = open;
+    }
+}
@@ -1,156 +1,554 @@
 use actix_files::{Files, NamedFile};
-use actix_web::{http, rt, web, App, HttpRequest, HttpResponse, HttpServer};
-use async_mutex::Mutex;
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use actix_web::{http, web, App, HttpRequest, HttpResponse, HttpServer};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use tera::{Context, Tera};
+use tera::Context;
 
+use crate::cache::{self, Content};
+use crate::errors::{self, ServiceError};
+use crate::templates::{Page, Templates};
+use crate::text::TruncatedString;
 use crate::{CONFIG, LOG};
 
-#[derive(Debug, Clone)]
-pub struct CachedFile {
+/// Context shared by every page, for the maintenance banner
+fn page_context(locale: &str) -> Context {
+    let mut ctx = Context::new();
+    ctx.insert("lang", locale);
+    ctx.insert("t", &crate::i18n::strings(locale));
+    if let Some(message) = crate::health::maintenance() {
+        ctx.insert("maintenance", &message);
+    }
+    ctx
+}
+
+/// Serve a rendered page, or a 304 when the client already has it.
+/// Pages change with resets and maintenance, so clients always revalidate.
+fn page_response(request: &HttpRequest, page: Page) -> HttpResponse {
+    let matches = request
+        .headers()
+        .get(http::header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',')
+                .any(|t| t.trim() == page.etag || t.trim() == "*")
+        })
+        .unwrap_or(false);
+    let mut resp = if matches {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    resp.header(http::header::ETAG, page.etag.as_str())
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .header(http::header::VARY, "Accept-Language");
+    if matches {
+        resp.finish()
+    } else {
+        resp.content_type("text/html").body(page.body)
+    }
+}
+
+async fn index(
+    template: web::Data<Templates>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let locale = crate::i18n::negotiate(&request);
+    let page = template.render_static("landing.html", &page_context(&locale))?;
+    Ok(page_response(&request, page))
+}
+
+// how many resets to remember for the reset page
+const RECENT_RESETS_LEN: usize = 20;
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct RecentReset {
+    path: String,
     cache_name: String,
-    created_millis: u128,
-    file_path: PathBuf,
+    reset_at: String,
 }
 
 lazy_static::lazy_static! {
-    pub static ref CACHE: Mutex<HashMap<String, Arc<Mutex<CachedFile>>>> = {
-        Mutex::new(HashMap::with_capacity(512))
+    static ref RECENT_RESETS: std::sync::Mutex<std::collections::VecDeque<RecentReset>> = {
+        std::sync::Mutex::new(std::collections::VecDeque::with_capacity(RECENT_RESETS_LEN))
     };
 }
 
-async fn cleanup_cache_dir() -> anyhow::Result<()> {
-    use futures::stream::StreamExt;
-    slog::info!(LOG, "cleaning cache dir: {}", &CONFIG.cache_dir);
-    let reader = tokio::fs::read_dir(&CONFIG.cache_dir).await?;
+fn record_reset(path: &str, cache_name: &str) {
+    let mut recent = RECENT_RESETS.lock().expect("recent resets lock poisoned");
+    if recent.len() >= RECENT_RESETS_LEN {
+        recent.pop_back();
+    }
+    recent.push_front(RecentReset {
+        path: path.to_string(),
+        cache_name: cache_name.to_string(),
+        reset_at: chrono::Utc::now().to_rfc3339(),
+    });
+}
 
-    reader
-        .for_each(|entry| async {
-            let entry = match entry {
-                Ok(entry) => entry,
-                Err(e) => {
-                    slog::error!(LOG, "failed unwraping dir entry: {:?}", e);
-                    return;
-                }
-            };
-            let path = entry.path();
-            if path.is_dir() {
-                return;
-            }
-            let file_name = match entry.file_name().into_string() {
-                Ok(n) => n,
-                Err(e) => {
-                    slog::error!(LOG, "failed converting filename to string: {:?}", e);
-                    return;
+fn recent_resets_list() -> Vec<RecentReset> {
+    let recent = RECENT_RESETS.lock().expect("recent resets lock poisoned");
+    recent.iter().cloned().collect()
+}
+
+async fn recent_resets() -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "resets": recent_resets_list(),
+    })))
+}
+
+fn reset_context(locale: &str) -> Context {
+    let mut ctx = page_context(locale);
+    ctx.insert("recent_resets", &recent_resets_list());
+    ctx
+}
+
+async fn reset(
+    template: web::Data<Templates>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let locale = crate::i18n::negotiate(&request);
+    let page = template.render_static("reset.html", &reset_context(&locale))?;
+    Ok(page_response(&request, page))
+}
+
+// shields styles and image formats shown on the preview page
+const PREVIEW_STYLES: &[&str] = &["flat", "flat-square", "plastic", "for-the-badge", "social"];
+const PREVIEW_EXTS: &[&str] = &["svg", "png"];
+
+#[derive(serde::Serialize)]
+struct PreviewRow {
+    style: String,
+    urls: Vec<String>,
+}
+
+/// Render the badge in every style and format, each going through the cache
+async fn preview(
+    template: web::Data<Templates>,
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    // pull out our own params and pass the rest along to each badge
+    let mut kind = Kind::Crate;
+    let mut passthrough = vec![];
+    for pair in request.query_string().split('&').filter(|p| !p.is_empty()) {
+        let key = pair.split('=').next().unwrap_or("");
+        match key {
+            "kind" => {
+                if let Ok(k) = pair.trim_start_matches("kind=").parse() {
+                    kind = k;
                 }
-            };
-            if file_name == ".gitkeep" {
-                return;
             }
+            "style" => (),
+            _ => passthrough.push(pair),
+        }
+    }
+    let params = Params::new(&name, kind, &request).map_err(|e| {
+        slog::error!(LOG, "error parsing badge {}: {:?}", name, e);
+        e
+    })?;
+    let route = params.kind.route();
 
-            // file names should also be the cache names
-            let guard = CACHE.lock().await;
-            if guard.get(&file_name).is_none() {
-                // If it's been evicted from the cache, then delete the file.
-                // This means most things will be deleted on startup.
-                slog::info!(LOG, "removing stale cached file: {}, {:?}", file_name, path);
-                match tokio::fs::remove_file(&path).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        slog::error!(LOG, "failed removing stale file: {:?}, {:?}", path, e);
-                    }
-                }
+    let rows = PREVIEW_STYLES
+        .iter()
+        .map(|style| {
+            let mut query = passthrough.clone();
+            let style_param = format!("style={}", style);
+            query.push(&style_param);
+            let urls = PREVIEW_EXTS
+                .iter()
+                .map(|ext| format!("{}/{}.{}?{}", route, params.name, ext, query.join("&")))
+                .collect();
+            PreviewRow {
+                style: style.to_string(),
+                urls,
             }
         })
-        .await;
-    Ok(())
+        .collect::<Vec<_>>();
+
+    let mut ctx = page_context(&crate::i18n::negotiate(&request));
+    ctx.insert("name", &params.name);
+    ctx.insert("exts", PREVIEW_EXTS);
+    ctx.insert("rows", &rows);
+    let s = template.render("preview.html", &ctx)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
 }
 
-async fn cleanup() {
-    let start =
-        rt::time::Instant::now() + std::time::Duration::from_secs(CONFIG.cleanup_delay_seconds);
-    let mut interval = rt::time::interval_at(
-        start,
-        std::time::Duration::from_secs(CONFIG.cleanup_interval_seconds),
-    );
-    loop {
-        interval.tick().await;
-        slog::info!(LOG, "cleaning stale items");
-
-        let now = now_millis();
-        let removed_from_cache = {
-            let mut cache = CACHE.lock().await;
-            let mut to_remove = vec![];
-            // can't use ::retain since we need to lock
-            // and async mutex for each entry
-            for (k, v) in cache.iter() {
-                let v = v.lock().await;
-                let diff_ms = now - v.created_millis;
-                if diff_ms > CONFIG.cache_ttl_millis {
-                    slog::info!(LOG, "invalidating cached item: {}", v.cache_name);
-                    to_remove.push(k.clone());
-                }
+#[derive(serde::Serialize, serde_derive::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub enum Kind {
+    Crate,
+    Badge,
+    GithubRelease,
+    GithubTag,
+    Pypi,
+    Npm,
+    MavenCentral,
+    Nuget,
+    DockerPulls,
+    DockerImageSize,
+    Codecov,
+    Coveralls,
+}
+impl Kind {
+    pub const ALL: &'static [Kind] = &[
+        Kind::Crate,
+        Kind::Badge,
+        Kind::GithubRelease,
+        Kind::GithubTag,
+        Kind::Pypi,
+        Kind::Npm,
+        Kind::MavenCentral,
+        Kind::Nuget,
+        Kind::DockerPulls,
+        Kind::DockerImageSize,
+        Kind::Codecov,
+        Kind::Coveralls,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Crate => "crate",
+            Kind::Badge => "badge",
+            Kind::GithubRelease => "github-release",
+            Kind::GithubTag => "github-tag",
+            Kind::Pypi => "pypi",
+            Kind::Npm => "npm",
+            Kind::MavenCentral => "maven-central",
+            Kind::Nuget => "nuget",
+            Kind::DockerPulls => "docker-pulls",
+            Kind::DockerImageSize => "docker-image-size",
+            Kind::Codecov => "codecov",
+            Kind::Coveralls => "coveralls",
+        }
+    }
+
+    /// Where the kind's badges live, both here and on shields
+    pub fn route(&self) -> &'static str {
+        match self {
+            Kind::Crate => "/crates/v",
+            Kind::Badge => "/badge",
+            Kind::GithubRelease => "/github/v/release",
+            Kind::GithubTag => "/github/v/tag",
+            Kind::Pypi => "/pypi/v",
+            Kind::Npm => "/npm/v",
+            Kind::MavenCentral => "/maven-central/v",
+            Kind::Nuget => "/nuget/v",
+            Kind::DockerPulls => "/docker/pulls",
+            Kind::DockerImageSize => "/docker/image-size",
+            Kind::Codecov => "/codecov/c/github",
+            Kind::Coveralls => "/coveralls/github",
+        }
+    }
+
+    /// How long the kind's badges are cached for
+    pub fn ttl_millis(&self) -> u128 {
+        match self {
+            // pull counts move much faster than versions do
+            Kind::DockerPulls | Kind::DockerImageSize => CONFIG.docker_cache_ttl_millis,
+            _ => CONFIG.cache_ttl_millis,
+        }
+    }
+
+    /// How many `/` separated segments a badge name is made of, at least and at most
+    pub fn name_segments(&self) -> (usize, usize) {
+        match self {
+            Kind::Crate | Kind::Badge | Kind::Pypi | Kind::Nuget => (1, 1),
+            // {group}/{artifact}, see `normalize_name`
+            Kind::MavenCentral => (2, 16),
+            // scoped packages are @{scope}/{package}
+            Kind::Npm => (1, 2),
+            // {owner}/{repo}
+            Kind::GithubRelease | Kind::GithubTag => (2, 2),
+            // {owner}/{repo}, where the repo can be nested, and image
+            // sizes can be followed by a /{tag}
+            Kind::DockerPulls | Kind::DockerImageSize => (2, 8),
+            // {owner}/{repo}/{branch}, the branch being optional
+            Kind::Codecov | Kind::Coveralls => (2, 3),
+        }
+    }
+}
+// crates.io's limit on crate name length
+const MAX_CRATE_NAME_LENGTH: usize = 64;
+
+impl Kind {
+    /// Whether a name could possibly exist upstream, to turn away scanner
+    /// junk before it gets a cache entry or an upstream request
+    fn is_valid_name(&self, name: &str) -> bool {
+        match self {
+            // crates.io names are ascii alphanumerics, `-` and `_`,
+            // starting with a letter
+            Kind::Crate => {
+                name.len() <= MAX_CRATE_NAME_LENGTH
+                    && name
+                        .chars()
+                        .next()
+                        .map(|c| c.is_ascii_alphabetic())
+                        .unwrap_or(false)
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
             }
-            for k in to_remove.iter() {
-                cache.remove(k);
+            _ => true,
+        }
+    }
+
+    /// Put a badge name into the form upstream expects, so
+    /// equivalent names share a cache key
+    fn normalize_name(&self, name: String) -> String {
+        match self {
+            // maven coordinates are sometimes written path-style,
+            // org/apache/commons/commons-lang3 for org.apache.commons/commons-lang3
+            Kind::MavenCentral => {
+                let segments = name.split('/').collect::<Vec<_>>();
+                let (artifact, group) = match segments.split_last() {
+                    Some(split) => split,
+                    None => return name,
+                };
+                format!("{}/{}", group.join("."), artifact)
             }
-            to_remove
-        };
-        slog::info!(
-            LOG,
-            "removed {} stale items from cache",
-            removed_from_cache.len()
-        );
-        cleanup_cache_dir()
-            .await
-            .map_err(|e| {
-                slog::error!(LOG, "error cleaning caching dir {:?}", e);
+            _ => name,
+        }
+    }
+
+    /// Coverage badges for a branch can be asked for either as
+    /// `{owner}/{repo}?branch={branch}` or `{owner}/{repo}/{branch}`,
+    /// shields only understands the latter so the query param is
+    /// moved into the name, which also gives both forms the same key
+    fn branch_from_query(&self, name: String, query_params: String) -> (String, String) {
+        if !matches!(self, Kind::Codecov | Kind::Coveralls) || name.split('/').count() != 2 {
+            return (name, query_params);
+        }
+        let mut branch = None;
+        let rest = query_params
+            .split('&')
+            .filter(|p| match p.strip_prefix("branch=") {
+                Some(b) if !b.is_empty() && !b.contains('/') => {
+                    branch = Some(b.to_string());
+                    false
+                }
+                _ => true,
             })
-            .ok();
+            .collect::<Vec<_>>()
+            .join("&");
+        match branch {
+            Some(branch) => (format!("{}/{}", name, branch), rest),
+            None => (name, query_params),
+        }
     }
 }
 
-async fn index(
-    template: web::Data<tera::Tera>,
-) -> actix_web::Result<HttpResponse, actix_web::Error> {
-    let s = template
-        .render("landing.html", &Context::new())
-        .map_err(|_| actix_web::error::ErrorInternalServerError("content error"))?;
-    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+// what can be filled in to an upstream url template
+const URL_PLACEHOLDERS: &[&str] = &["{name}", "{ext}", "{query}"];
+
+fn fill_url_template(template: &str, name: &str, ext: &str, query: &str) -> String {
+    let url = template.replace("{name}", name).replace("{ext}", ext);
+    if url.contains("{query}") {
+        url.replace("{query}", query)
+    } else if query.is_empty() {
+        url
+    } else {
+        format!("{}?{}", url, query)
+    }
 }
 
-async fn reset(
-    template: web::Data<tera::Tera>,
-) -> actix_web::Result<HttpResponse, actix_web::Error> {
-    let s = template
-        .render("reset.html", &Context::new())
-        .map_err(|_| actix_web::error::ErrorInternalServerError("content error"))?;
-    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+/// Check an upstream url template only uses known placeholders
+/// and fills in to a valid http(s) url
+pub fn validate_url_template(template: &str) -> anyhow::Result<()> {
+    if !template.contains("{name}") {
+        anyhow::bail!("missing {{name}} placeholder: {}", template);
+    }
+    let stripped = URL_PLACEHOLDERS
+        .iter()
+        .fold(template.to_string(), |t, p| t.replace(p, ""));
+    if stripped.contains('{') || stripped.contains('}') {
+        anyhow::bail!("unknown placeholder: {}", template);
+    }
+    let url = reqwest::Url::parse(&fill_url_template(template, "name", "svg", "q=1"))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        anyhow::bail!("unsupported scheme {}: {}", url.scheme(), template);
+    }
+    Ok(())
 }
 
-#[derive(serde::Serialize, Debug)]
-enum Kind {
-    Crate,
-    Badge,
+impl Kind {
+    /// Where the kind's badges are fetched from, the configured
+    /// KIND_<KIND>_URL or the kind's route on shields
+    pub fn url_template(&self) -> String {
+        CONFIG
+            .kind_url_templates
+            .iter()
+            .find(|(kind, _)| kind == self)
+            .map(|(_, template)| template.clone())
+            .unwrap_or_else(|| format!("https://img.shields.io{}/{{name}}.{{ext}}", self.route()))
+    }
+
+    pub fn upstream_url(&self, name: &str, ext: &str, query: &str) -> String {
+        fill_url_template(&self.url_template(), name, ext, query)
+    }
+
+    /// Where the kind's badges are shadow fetched from while a
+    /// KIND_<KIND>_SHADOW_URL is set, see `shadow`
+    pub fn shadow_upstream_url(&self, name: &str, ext: &str, query: &str) -> Option<String> {
+        CONFIG
+            .kind_shadow_url_templates
+            .iter()
+            .find(|(kind, _)| kind == self)
+            .map(|(_, template)| fill_url_template(template, name, ext, query))
+    }
+}
+
+impl std::str::FromStr for Kind {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim().to_lowercase();
+        Kind::ALL
+            .iter()
+            .find(|k| k.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("unknown kind: {}", s))
+    }
+}
+
+/// Drop any query params that aren't in the allowlist
+fn allowed_query_params(query_params: &str) -> String {
+    if CONFIG.query_param_allowlist.is_empty() {
+        return query_params.to_string();
+    }
+    let (allowed, stripped): (Vec<_>, Vec<_>) = query_params
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .partition(|p| {
+            let key = p.split('=').next().unwrap_or("");
+            CONFIG.query_param_allowlist.iter().any(|a| a == key)
+        });
+    if !stripped.is_empty() {
+        crate::metrics::inc_by("query_params_stripped_total", &[], stripped.len() as u64);
+    }
+    allowed.join("&")
+}
+
+// asks a badge endpoint to redirect to its immutable url
+const IMMUTABLE_PARAM: &str = "immutable";
+// a year, immutable urls never change content
+const IMMUTABLE_MAX_AGE_SECONDS: u64 = 365 * 24 * 60 * 60;
+
+// overlays how long ago the badge was fetched
+const SHOW_AGE_PARAM: &str = "show_age";
+// recolors the badge, see `processing::Theme`
+const THEME_PARAM: &str = "theme";
+// a logo from `LOGO_DIR`, anything else is left to upstream
+const LOGO_PARAM: &str = "logo";
+
+/// Pull `key` (bare, or `key=value`) out of a query string, it's
+/// ours and never part of the cache key or upstream url
+fn take_param(query_params: String, key: &str) -> (Option<String>, String) {
+    let mut value = None;
+    let rest = query_params
+        .split('&')
+        .filter(|p| {
+            let mut kv = p.splitn(2, '=');
+            if kv.next() != Some(key) {
+                return true;
+            }
+            value = Some(kv.next().unwrap_or("").to_string());
+            false
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    match value {
+        Some(value) => (Some(value), rest),
+        None => (None, query_params),
+    }
+}
+
+fn join_query(query_params: &str, params: &str) -> String {
+    match (query_params.is_empty(), params.is_empty()) {
+        (_, true) => query_params.to_string(),
+        (true, false) => params.to_string(),
+        (false, false) => format!("{}&{}", query_params, params),
+    }
+}
+
+/// The params applied here rather than upstream that change what's stored
+fn local_params(theme: Option<crate::processing::Theme>, logo: Option<&str>) -> String {
+    let mut params = vec![];
+    if let Some(theme) = theme {
+        params.push(theme.query_param());
+    }
+    if let Some(logo) = logo {
+        params.push(format!("{}={}", LOGO_PARAM, logo));
+    }
+    params.join("&")
+}
+
+/// Pull a bare `flag` (or `flag=...`) out of a query string
+fn take_flag(query_params: String, flag: &str) -> (bool, String) {
+    let (value, rest) = take_param(query_params, flag);
+    let set = match value.as_deref() {
+        None | Some("false") | Some("0") => false,
+        Some(_) => true,
+    };
+    (set, rest)
 }
 
 #[derive(serde::Serialize)]
-struct Params {
-    kind: Kind,
-    name: String,
-    ext: String,
-    query_params: String,
-    cache_name: String,
-    redirect_url: String,
+pub struct Params {
+    pub kind: Kind,
+    pub name: String,
+    pub ext: String,
+    pub query_params: String,
+    pub cache_name: String,
+    // the key this badge had under the previous key format, checked
+    // on a miss while the new format is rolled out
+    pub legacy_cache_name: Option<String>,
+    pub redirect_url: String,
+    // the ext was picked from the Accept header rather than the path
+    pub vary_accept: bool,
+    // redirect to the badge's immutable url rather than serving it
+    pub immutable: bool,
+    // overlay the badge's age, see `processing`
+    pub show_age: bool,
+    // recolor the stored svg, part of the cache key but not the upstream url
+    pub theme: Option<crate::processing::Theme>,
+    // a local logo embedded in the stored svg, like `theme`
+    pub logo: Option<String>,
 }
 impl Params {
-    fn new(full_name: &str, kind: Kind, request: &HttpRequest) -> anyhow::Result<Params> {
+    /// The query for links back to this badge, with the params
+    /// that are handled here and still change what's served
+    fn query_for_links(&self) -> String {
+        join_query(
+            &self.query_params,
+            &local_params(self.theme, self.logo.as_deref()),
+        )
+    }
+
+    fn new(full_name: &str, kind: Kind, request: &HttpRequest) -> errors::Result<Params> {
+        // without an ext in the path, the format is negotiated
+        let default_ext = || {
+            ext_from_accept(request)
+                .map(String::from)
+                .unwrap_or_else(|| CONFIG.default_file_ext.clone())
+        };
+        let truncate = |what: &str, s: String, max_len: usize| {
+            let truncated = TruncatedString::new(s, max_len);
+            if truncated.was_truncated() {
+                slog::info!(
+                    LOG,
+                    "{} too long {}, truncating to {}: {}",
+                    what,
+                    truncated.original_len(),
+                    max_len,
+                    truncated.as_str()
+                );
+            }
+            truncated.into_string()
+        };
+        let mut vary_accept = false;
         let parts = full_name.split('.').collect::<Vec<_>>();
         let (name, ext) = if parts.len() < 2 {
-            (full_name.to_string(), CONFIG.default_file_ext.clone())
+            vary_accept = true;
+            (full_name.to_string(), default_ext())
         } else {
             let parts_len = parts.len();
             let end_ind = parts_len - 1;
@@ -159,393 +557,2509 @@ impl Params {
                 .copied()
                 .collect::<Vec<_>>()
                 .join(".");
-            let name = if name.len() > CONFIG.max_name_length {
-                let (name_head, _) = name.split_at(CONFIG.max_name_length);
-                slog::info!(
-                    LOG,
-                    "name too long {}, truncating to {}: {}",
-                    name.len(),
-                    CONFIG.max_name_length,
-                    name_head
-                );
-                name_head.to_string()
-            } else {
-                name
-            };
+            let name = truncate("name", name, CONFIG.max_name_length);
 
             let ext = parts[end_ind].to_string();
             let (name, ext) = if !["svg", "png", "json"].contains(&ext.as_str()) {
                 // put back the "ext" and use the default extension
-                (format!("{}.{}", name, ext), CONFIG.default_file_ext.clone())
+                vary_accept = true;
+                (format!("{}.{}", name, ext), default_ext())
             } else {
                 (name, ext)
             };
-            let ext = if ext.len() > CONFIG.max_ext_length {
-                let (ext_head, _) = ext.split_at(CONFIG.max_ext_length);
-                slog::info!(
-                    LOG,
-                    "ext too long {}, truncating to {}: {}",
-                    ext.len(),
-                    CONFIG.max_ext_length,
-                    ext_head
-                );
-                ext_head.to_string()
-            } else {
-                ext
-            };
+            let ext = truncate("ext", ext, CONFIG.max_ext_length);
             (name, ext)
         };
+        let (min_segments, max_segments) = kind.name_segments();
+        let segments = name.split('/').collect::<Vec<_>>();
+        if segments.iter().any(|s| s.is_empty())
+            || segments.len() < min_segments
+            || segments.len() > max_segments
+        {
+            return Err(ServiceError::Parse(full_name.to_string()));
+        }
+        if !kind.is_valid_name(&name) {
+            crate::metrics::inc("invalid_names_total", &[("kind", kind.as_str())]);
+            return Err(ServiceError::Parse(full_name.to_string()));
+        }
+        let name = kind.normalize_name(name);
 
-        let query_params = request.query_string().to_string();
-        let query_params = if query_params.len() > CONFIG.max_qs_length {
-            let (qs_head, _) = query_params.split_at(CONFIG.max_qs_length);
-            slog::info!(
-                LOG,
-                "query string too long {}, truncating to {}: {}",
-                query_params.len(),
-                CONFIG.max_qs_length,
-                qs_head
-            );
-            qs_head.to_string()
-        } else {
-            query_params
+        let query_params = truncate(
+            "query string",
+            request.query_string().to_string(),
+            CONFIG.max_qs_length,
+        );
+        let (immutable, query_params) = take_flag(query_params, IMMUTABLE_PARAM);
+        let (show_age, query_params) = take_flag(query_params, SHOW_AGE_PARAM);
+        // credentials never go upstream or into the cache key
+        let (_, query_params) = take_param(query_params, crate::auth::API_KEY_PARAM);
+        let (theme, query_params) = take_param(query_params, THEME_PARAM);
+        // only svgs can be recolored
+        let theme = theme
+            .and_then(|t| crate::processing::Theme::parse(&t))
+            .filter(|_| ext == "svg");
+        let (logo, query_params) = match take_param(query_params.clone(), LOGO_PARAM) {
+            (Some(logo), rest) if ext == "svg" && crate::processing::logo(&logo).is_some() => {
+                (Some(logo), rest)
+            }
+            _ => (None, query_params),
         };
+        let (name, query_params) = kind.branch_from_query(name, query_params);
+        let query_params = allowed_query_params(&query_params);
+        // themed and locally branded badges are stored separately from the plain one
+        let key_query = join_query(&query_params, &local_params(theme, logo.as_deref()));
 
-        let full_name = if query_params.is_empty() {
+        let name_for_file = if key_query.is_empty() {
             format!("{}.{}", name, ext)
         } else {
-            format!("{}.{}?{}", name, ext, query_params)
+            format!("{}_{}.{}", key_query, name, ext)
         };
-        let name_for_file = if query_params.is_empty() {
-            format!("{}.{}", name, ext)
-        } else {
-            format!("{}_{}.{}", query_params, name, ext)
+        let v1_cache_name = format!("{:?}_{}", kind, name_for_file);
+        let key_format = request
+            .headers()
+            .get(KEY_FORMAT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(CONFIG.cache_key_format);
+        let (cache_name, legacy_cache_name) = match key_format {
+            KeyFormat::V1 => (v1_cache_name, None),
+            KeyFormat::V2 => {
+                let mut query = key_query
+                    .split('&')
+                    .filter(|p| !p.is_empty())
+                    .collect::<Vec<_>>();
+                query.sort_unstable();
+                let cache_name = if query.is_empty() {
+                    format!("{}/{}.{}", kind.as_str(), name, ext)
+                } else {
+                    format!("{}/{}.{}?{}", kind.as_str(), name, ext, query.join("&"))
+                };
+                let legacy = if CONFIG.cache_key_dual_read {
+                    Some(v1_cache_name)
+                } else {
+                    None
+                };
+                (cache_name, legacy)
+            }
         };
-        let cache_name = format!("{:?}_{}", kind, name_for_file);
 
-        let base_url = "https://img.shields.io";
-        let redirect_url = match kind {
-            Kind::Crate => format!("{}/crates/v/{}", base_url, full_name),
-            Kind::Badge => format!("{}/badge/{}", base_url, full_name),
-        };
+        let redirect_url = kind.upstream_url(&name, &ext, &query_params);
         Ok(Params {
             kind,
             name,
             ext,
             query_params,
             cache_name,
+            legacy_cache_name,
             redirect_url,
+            vary_accept,
+            immutable,
+            show_age,
+            theme,
+            logo,
         })
     }
 }
 
-#[derive(Default)]
-struct BadgeResult {
-    was_cached: bool,
-    file_path: Option<PathBuf>,
-    redirect_url: String,
-}
-impl BadgeResult {
-    async fn into_response(self, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
-        let path = if let Some(p) = self.file_path {
-            tokio::fs::metadata(&p).await.map_err(|e| {
-                anyhow::anyhow!("path not accessible or doesn't exist: {:?}. {:?}", p, e)
-            })?;
-            Some(p)
-        } else {
-            None
+/// Pick the badge format the client prefers from its `Accept` header,
+/// `None` if it doesn't ask for any format we know about
+fn ext_from_accept(request: &HttpRequest) -> Option<&'static str> {
+    let accept = request.headers().get(http::header::ACCEPT)?.to_str().ok()?;
+    let mut best: Option<(f32, &'static str)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media_type = parts.next().unwrap_or("").trim().to_lowercase();
+        let ext = match media_type.as_str() {
+            "image/svg+xml" => "svg",
+            "image/png" => "png",
+            "application/json" => "json",
+            _ => continue,
         };
-        if let Some(p) = path {
-            let mut resp = NamedFile::open(p)?
-                .into_response(request)
-                .map_err(|e| anyhow::anyhow!("asset not found: {:?}", e))?;
-            let hdrs = resp.headers_mut();
-
-            let ctrl = http::HeaderValue::from_str(&format!(
-                "max-age={}, public",
-                CONFIG.http_expiry_seconds
-            ))?;
-            hdrs.insert(http::header::CACHE_CONTROL, ctrl);
-
-            let expiry_dt = chrono::Utc::now()
-                .checked_add_signed(chrono::Duration::seconds(CONFIG.http_expiry_seconds))
-                .ok_or_else(|| anyhow::anyhow!("error creating expiry datetime"))?;
-            let exp = http::HeaderValue::from_str(&expiry_dt.to_rfc2822())?;
-            hdrs.insert(http::header::EXPIRES, exp);
-            hdrs.insert(
-                http::HeaderName::from_static("x-was-cached"),
-                http::HeaderValue::from_str(&format!("{}", self.was_cached))?,
-            );
-            Ok(resp)
-        } else {
-            Ok(HttpResponse::TemporaryRedirect()
-                .set_header("Location", self.redirect_url)
-                .finish())
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .next()
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.);
+        if q > 0. && best.map(|(best_q, _)| q > best_q).unwrap_or(true) {
+            best = Some((q, ext));
         }
     }
+    best.map(|(_, ext)| ext)
 }
 
-async fn _request_badge_to_file(badge_url: &str, file_path: &Path) -> anyhow::Result<()> {
-    slog::info!(
-        LOG,
-        "requesting fresh badge {} -> {:?}",
-        badge_url,
-        file_path
-    );
-    let resp = reqwest::get(badge_url)
-        .await
-        .map_err(|e| anyhow::anyhow!("request failed: {}", e))?
-        .bytes()
-        .await
-        .map_err(|e| anyhow::anyhow!("request read failed: {}", e))?;
+// lets individual requests try out a key format ahead of `CACHE_KEY_FORMAT`
+const KEY_FORMAT_HEADER: &str = "x-badge-key-format";
 
-    use tokio::io::AsyncWriteExt;
-    let mut f = tokio::fs::File::create(file_path)
-        .await
-        .map_err(|e| anyhow::anyhow!("failed to create file {}", e))?;
-    f.write_all(&resp)
-        .await
-        .map_err(|e| anyhow::anyhow!("failed writing response to file {}", e))?;
-    Ok(())
+/// How cache keys are built from a request
+#[derive(Debug, Clone, Copy, PartialEq, serde_derive::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyFormat {
+    /// `{Kind}_{query}_{name}.{ext}`, query params as given
+    V1,
+    /// `{kind}/{name}.{ext}?{query}`, query params sorted
+    V2,
+}
+impl KeyFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyFormat::V1 => "v1",
+            KeyFormat::V2 => "v2",
+        }
+    }
+}
+impl std::str::FromStr for KeyFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "v1" => KeyFormat::V1,
+            "v2" => KeyFormat::V2,
+            other => anyhow::bail!("unknown cache key format: {}", other),
+        })
+    }
 }
 
-fn now_millis() -> u128 {
-    let now = std::time::SystemTime::now();
-    now.duration_since(std::time::SystemTime::UNIX_EPOCH)
-        .map(|dur| dur.as_millis())
-        .unwrap_or(0)
-}
-
-async fn _get_cached_badge(params: &Params) -> anyhow::Result<(bool, PathBuf)> {
-    //  generate new cache values
-    let file_path = Path::new(&CONFIG.cache_dir).join(&params.cache_name);
-    let new_created_millis = now_millis();
-    let new_inner = Arc::new(Mutex::new(CachedFile {
-        cache_name: params.cache_name.clone(),
-        created_millis: new_created_millis,
-        file_path: file_path.clone(),
-    }));
-
-    // lock the cache and get or insert
-    let mut cache = CACHE.lock().await;
-    let inner = cache
-        .entry(params.cache_name.clone())
-        .or_insert_with(|| new_inner.clone());
-
-    // clone the inner pointer and lock the individual entry
-    // while we're still holding the cache lock.
-    let owned_inner = inner.clone();
-    let locked_inner = owned_inner.lock().await;
-
-    // we've got a cached value if it doesn't match our new insertion timestamp
-    let is_cached = locked_inner.created_millis != new_created_millis;
-    let is_cached = if is_cached {
-        // and if it hasn't expired
-        let now = now_millis();
-        let diff = now - locked_inner.created_millis;
-        if diff > CONFIG.cache_ttl_millis {
-            // if it did expire, swap the existing thing for our new entry
-            slog::info!(LOG, "cached badge expired: {}", params.cache_name);
-            *inner = new_inner.clone();
-            false
-        } else {
-            true
+/// What to serve when a badge can't be retrieved from the cache or upstream
+#[derive(Debug, Clone, Copy, PartialEq, serde_derive::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailoverMode {
+    /// redirect the client to the upstream badge
+    Redirect,
+    /// fetch the upstream badge on the client's behalf without caching it
+    Proxy,
+    /// respond with a 502 and an error badge
+    Error,
+}
+impl FailoverMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailoverMode::Redirect => "redirect",
+            FailoverMode::Proxy => "proxy",
+            FailoverMode::Error => "error",
         }
-    } else {
-        false
-    };
+    }
+}
+impl std::str::FromStr for FailoverMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "redirect" => FailoverMode::Redirect,
+            "proxy" => FailoverMode::Proxy,
+            "error" => FailoverMode::Error,
+            other => anyhow::bail!("unknown failover mode: {}", other),
+        })
+    }
+}
 
-    // drop the lock on the cache as a whole - we've still got the
-    // lock on the individual entry so no one else can be retrieving
-    // and saving this badge at the same time.
-    std::mem::drop(cache);
+fn content_type_for_ext(ext: &str) -> &'static str {
+    match ext {
+        "png" => "image/png",
+        "json" => "application/json",
+        _ => "image/svg+xml",
+    }
+}
 
-    if !is_cached {
-        _request_badge_to_file(&params.redirect_url, &locked_inner.file_path).await?;
+/// Render a shields-style flat badge (or its json equivalent) for
+/// when we have nothing better to serve
+fn render_error_badge(label: &str, message: &str, ext: &str) -> (&'static str, String) {
+    if ext == "json" {
+        let body = serde_json::json!({
+            "name": label,
+            "value": message,
+        });
+        return ("application/json", body.to_string());
     }
-    Ok((is_cached, locked_inner.file_path.clone()))
+    // roughly the width of shields' verdana 11px
+    let text_width = |s: &str| s.chars().count() * 7 + 10;
+    let label_width = text_width(label);
+    let message_width = text_width(message);
+    let width = label_width + message_width;
+    let svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}"><title>{label}: {message}</title><g shape-rendering="crispEdges"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{message_width}" height="20" fill="#e05d44"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11"><text x="{label_x}" y="14">{label}</text><text x="{message_x}" y="14">{message}</text></g></svg>"##,
+        width = width,
+        label = label,
+        message = message,
+        label_width = label_width,
+        message_width = message_width,
+        label_x = label_width / 2,
+        message_x = label_width + message_width / 2,
+    );
+    ("image/svg+xml", svg)
 }
 
-async fn get_cached_badge(params: &Params) -> anyhow::Result<BadgeResult> {
-    let cache_result = _get_cached_badge(params).await.map_err(|e| {
-        slog::error!(LOG, "error requesting badge {:?}", e);
-        e
-    });
-    let (was_cached, file_path) = match cache_result.ok() {
-        Some((was_cached, file_path)) => (was_cached, Some(file_path)),
-        None => (false, None),
-    };
-    Ok(BadgeResult {
-        was_cached,
-        file_path,
-        redirect_url: params.redirect_url.clone(),
-    })
+// request headers worth forwarding upstream when proxying
+const PROXY_REQUEST_HEADERS: &[http::header::HeaderName] = &[
+    http::header::ACCEPT,
+    http::header::IF_NONE_MATCH,
+    http::header::IF_MODIFIED_SINCE,
+    http::header::USER_AGENT,
+];
+
+// set on requests forwarded to the node that owns a badge, which
+// always handles them itself, even if its view of the cluster differs
+const FORWARDED_HEADER: &str = "x-badge-forwarded";
+
+// hop-by-hop headers that only apply to a single connection
+const HOP_BY_HOP_HEADERS: &[http::header::HeaderName] = &[
+    http::header::CONNECTION,
+    http::header::TRANSFER_ENCODING,
+    http::header::CONTENT_LENGTH,
+    http::header::TE,
+    http::header::TRAILER,
+    http::header::UPGRADE,
+    http::header::PROXY_AUTHENTICATE,
+    http::header::PROXY_AUTHORIZATION,
+];
+
+/// Stream the upstream badge straight through to the client
+async fn proxy_upstream(url: &str) -> errors::Result<HttpResponse> {
+    proxy_upstream_with_headers(url, None).await
 }
 
-async fn get_badge_result_for_kind(
-    name: String,
-    request: HttpRequest,
-    kind: Kind,
-) -> actix_web::Result<HttpResponse, actix_web::Error> {
-    let params = Params::new(&name, kind, &request).map_err(|e| {
-        slog::error!(LOG, "error parsing badge {}: {:?}", name, e);
-        actix_web::error::ErrorBadRequest(format!("invalid badge name: {}", name))
-    })?;
-    let badge = get_cached_badge(&params).await.map_err(|e| {
-        slog::error!(LOG, "error retrieving badge {}: {:?}", name, e);
-        actix_web::error::ErrorInternalServerError(format!("error retrieving badge: {}", name))
-    })?;
-    let resp = badge.into_response(&request).await.map_err(|e| {
-        slog::error!(LOG, "error loading badge {}: {:?}", name, e);
-        actix_web::error::ErrorInternalServerError(format!("error loading badge: {}", name))
-    })?;
-    Ok(resp)
+/// Stream the upstream badge straight through to the client, passing
+/// along the client's request headers and all of upstream's response headers
+async fn proxy_upstream_with_headers(
+    url: &str,
+    request: Option<&HttpRequest>,
+) -> errors::Result<HttpResponse> {
+    _proxy_upstream(url, request, reqwest::header::HeaderMap::new()).await
 }
 
-async fn get_crate(
-    web::Path(name): web::Path<String>,
-    request: HttpRequest,
-) -> actix_web::Result<HttpResponse, actix_web::Error> {
-    let resp = get_badge_result_for_kind(name, request, Kind::Crate).await?;
-    Ok(resp)
+async fn _proxy_upstream(
+    url: &str,
+    request: Option<&HttpRequest>,
+    mut headers: reqwest::header::HeaderMap,
+) -> errors::Result<HttpResponse> {
+    use futures::TryStreamExt;
+    if let Some(request) = request {
+        for name in PROXY_REQUEST_HEADERS {
+            if let Some(value) = request.headers().get(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    let upstream = crate::upstream::get_with_headers(url, headers).await?;
+    let status =
+        http::StatusCode::from_u16(upstream.status().as_u16()).map_err(ServiceError::internal)?;
+    let mut resp = HttpResponse::build(status);
+    for (name, value) in upstream.headers().iter() {
+        if request.is_none() && name != http::header::CONTENT_TYPE {
+            continue;
+        }
+        if HOP_BY_HOP_HEADERS.contains(name) {
+            continue;
+        }
+        resp.header(name.clone(), value.clone());
+    }
+    resp.header(
+        http::HeaderName::from_static("x-was-cached"),
+        http::HeaderValue::from_static("false"),
+    );
+    let body = upstream
+        .bytes_stream()
+        .map_err(actix_web::error::ErrorBadGateway);
+    Ok(resp.streaming(Box::pin(body)))
 }
 
-async fn get_badge(
-    web::Path(name): web::Path<String>,
-    request: HttpRequest,
-) -> actix_web::Result<HttpResponse, actix_web::Error> {
-    let resp = get_badge_result_for_kind(name, request, Kind::Badge).await?;
-    Ok(resp)
+// http dates only go down to the second
+fn refreshed_system_time(refreshed_millis: u128) -> std::time::SystemTime {
+    std::time::UNIX_EPOCH + std::time::Duration::from_secs((refreshed_millis / 1000) as u64)
 }
 
-async fn _reset_cached_badge(params: &Params) -> anyhow::Result<()> {
-    slog::info!(LOG, "dropping cached badge: {}", params.cache_name);
-    let mut guard = CACHE.lock().await;
-    guard.remove(&params.cache_name);
-    Ok(())
+/// Whether the client's `If-Modified-Since` is at or after the time the
+/// content was refreshed. `If-None-Match` takes precedence when both are sent.
+fn not_modified_since(request: &HttpRequest, refreshed_millis: u128) -> bool {
+    if request.headers().contains_key(http::header::IF_NONE_MATCH) {
+        return false;
+    }
+    let since = request
+        .headers()
+        .get(http::header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<http::header::HttpDate>().ok());
+    match since {
+        Some(since) => {
+            refreshed_system_time(refreshed_millis) <= std::time::SystemTime::from(since)
+        }
+        None => false,
+    }
 }
 
-async fn reset_cached_badge(
-    name: String,
-    request: HttpRequest,
+/// A cached file's body, read up front or left to be streamed
+enum FileBody {
+    Read(Vec<u8>),
+    Streamed(NamedFile),
+}
+
+/// Open a cached file, reading it in when it's under `stream_threshold_bytes`
+fn open_content_file(path: std::path::PathBuf) -> std::io::Result<FileBody> {
+    use std::io::Read;
+    let mut file = std::fs::File::open(&path)?;
+    let len = file.metadata()?.len();
+    if len < CONFIG.stream_threshold_bytes {
+        let mut buf = Vec::with_capacity(len as usize);
+        file.read_to_end(&mut buf)?;
+        Ok(FileBody::Read(buf))
+    } else {
+        NamedFile::from_file(file, path).map(FileBody::Streamed)
+    }
+}
+
+struct BadgeResult {
+    was_cached: bool,
+    content: Option<Content>,
+    // when the served content was fetched from upstream
+    refreshed_millis: Option<u128>,
+    expires_millis: Option<u128>,
+    vary_accept: bool,
+    ext: String,
+    redirect_url: String,
+    show_age: bool,
+}
+impl BadgeResult {
+    fn content_type(&self) -> &'static str {
+        content_type_for_ext(&self.ext)
+    }
+
+    /// What to change in the cached svg before serving it
+    fn transforms(&self) -> Vec<crate::processing::Transform> {
+        let mut transforms = vec![];
+        if self.ext != "svg" {
+            return transforms;
+        }
+        if let (true, Some(refreshed_millis)) = (self.show_age, self.refreshed_millis) {
+            transforms.push(crate::processing::Transform::ShowAge {
+                age_millis: cache::now_millis().saturating_sub(refreshed_millis),
+            });
+        }
+        transforms
+    }
+
+    /// An RFC 9211 `Cache-Status`. Stale hits, served when upstream can't
+    /// be reached in time, have a negative ttl. A miss is `stored` unless
+    /// the badge wasn't admitted to the cache.
+    fn cache_status(&self) -> String {
+        match &self.content {
+            Some(_) if self.was_cached => match self.expires_millis {
+                Some(expires) => {
+                    let ttl = (expires as i128 - cache::now_millis() as i128) / 1000;
+                    format!("badge-cache; hit; ttl={}", ttl)
+                }
+                None => "badge-cache; hit".to_string(),
+            },
+            Some(_) if self.refreshed_millis.is_some() => {
+                "badge-cache; fwd=miss; stored".to_string()
+            }
+            Some(_) => "badge-cache; fwd=miss".to_string(),
+            None => "badge-cache; fwd=miss; detail=failover".to_string(),
+        }
+    }
+
+    /// What finding the badge came down to, for `Server-Timing`
+    fn lookup_step(&self) -> &'static str {
+        match &self.content {
+            Some(_) if self.was_cached => "cache",
+            Some(_) => "upstream",
+            // the failover's own time is part of serving the response
+            None => "cache",
+        }
+    }
+
+    /// Which upstream the badge is from, and whether it was served
+    /// from memory, disk, freshly fetched, or left to the failover
+    fn source(&self) -> String {
+        let upstream = reqwest::Url::parse(&self.redirect_url)
+            .ok()
+            .and_then(|u| u.host_str().map(String::from))
+            .unwrap_or_else(|| "unknown".to_string());
+        let from = match &self.content {
+            Some(content) if self.was_cached => content.tier(),
+            Some(_) => "upstream",
+            None => "failover",
+        };
+        format!("upstream={}; from={}", upstream, from)
+    }
+
+    async fn failover_response(self) -> errors::Result<HttpResponse> {
+        match CONFIG.failover_mode {
+            FailoverMode::Redirect => Ok(HttpResponse::TemporaryRedirect()
+                .set_header("Location", self.redirect_url)
+                .finish()),
+            FailoverMode::Proxy => match proxy_upstream(&self.redirect_url).await {
+                Ok(resp) => Ok(resp),
+                Err(e) => {
+                    slog::error!(LOG, "error proxying badge {}: {:?}", self.redirect_url, e);
+                    let (content_type, body) =
+                        render_error_badge("badge", "unavailable", &self.ext);
+                    Ok(HttpResponse::BadGateway()
+                        .content_type(content_type)
+                        .body(body))
+                }
+            },
+            FailoverMode::Error => {
+                let (content_type, body) = render_error_badge("badge", "unavailable", &self.ext);
+                Ok(HttpResponse::BadGateway()
+                    .content_type(content_type)
+                    .header(http::header::CACHE_CONTROL, "no-cache")
+                    .body(body))
+            }
+        }
+    }
+
+    async fn into_response(self, request: &HttpRequest) -> errors::Result<HttpResponse> {
+        let not_modified = self
+            .refreshed_millis
+            .map(|m| not_modified_since(request, m))
+            .unwrap_or(false);
+        let source = http::HeaderValue::from_str(&self.source()).map_err(ServiceError::internal)?;
+        let cache_status =
+            http::HeaderValue::from_str(&self.cache_status()).map_err(ServiceError::internal)?;
+        let transforms = self.transforms();
+        let mut resp = match &self.content {
+            Some(_) if not_modified => HttpResponse::NotModified().finish(),
+            Some(content) if !transforms.is_empty() => {
+                let bytes = match content {
+                    Content::Bytes(b) => b.clone(),
+                    Content::File(p) => {
+                        let p = p.clone();
+                        bytes::Bytes::from(
+                            crate::blocking::run("read", move || std::fs::read(p)).await?,
+                        )
+                    }
+                };
+                HttpResponse::Ok()
+                    .content_type(self.content_type())
+                    .body(crate::processing::apply(&bytes, &transforms))
+            }
+            Some(Content::File(p)) => {
+                let p = p.clone();
+                match crate::blocking::run("open", move || open_content_file(p)).await? {
+                    FileBody::Read(body) => {
+                        crate::metrics::inc("file_content_served_total", &[("mode", "read")]);
+                        HttpResponse::Ok()
+                            .content_type(self.content_type())
+                            .body(body)
+                    }
+                    // content files are shared between entries, so their mtime
+                    // says nothing about when this badge was refreshed
+                    FileBody::Streamed(file) => {
+                        crate::metrics::inc("file_content_served_total", &[("mode", "stream")]);
+                        file.use_last_modified(false)
+                            .into_response(request)
+                            .map_err(|e| {
+                                ServiceError::Internal(format!("asset not found: {:?}", e))
+                            })?
+                    }
+                }
+            }
+            Some(Content::Bytes(b)) => HttpResponse::Ok()
+                .content_type(self.content_type())
+                .body(b.clone()),
+            None => {
+                let vary_accept = self.vary_accept;
+                let mut resp = self.failover_response().await?;
+                if vary_accept {
+                    resp.headers_mut()
+                        .insert(http::header::VARY, http::HeaderValue::from_static("Accept"));
+                }
+                resp.headers_mut()
+                    .insert(http::HeaderName::from_static("x-badge-source"), source);
+                resp.headers_mut()
+                    .insert(http::HeaderName::from_static("cache-status"), cache_status);
+                return Ok(resp);
+            }
+        };
+        let hdrs = resp.headers_mut();
+
+        let ctrl =
+            http::HeaderValue::from_str(&format!("max-age={}, public", CONFIG.http_expiry_seconds))
+                .map_err(ServiceError::internal)?;
+        hdrs.insert(http::header::CACHE_CONTROL, ctrl);
+
+        let expiry_dt = chrono::Utc::now()
+            .checked_add_signed(chrono::Duration::seconds(CONFIG.http_expiry_seconds))
+            .ok_or_else(|| ServiceError::internal("error creating expiry datetime"))?;
+        let exp =
+            http::HeaderValue::from_str(&expiry_dt.to_rfc2822()).map_err(ServiceError::internal)?;
+        hdrs.insert(http::header::EXPIRES, exp);
+        hdrs.insert(
+            http::HeaderName::from_static("x-was-cached"),
+            http::HeaderValue::from_str(&format!("{}", self.was_cached))
+                .map_err(ServiceError::internal)?,
+        );
+        hdrs.insert(http::HeaderName::from_static("x-badge-source"), source);
+        hdrs.insert(http::HeaderName::from_static("cache-status"), cache_status);
+        if self.vary_accept {
+            hdrs.insert(http::header::VARY, http::HeaderValue::from_static("Accept"));
+        }
+        if let Some(refreshed_millis) = self.refreshed_millis {
+            let age_seconds = cache::now_millis().saturating_sub(refreshed_millis) / 1000;
+            hdrs.insert(
+                http::HeaderName::from_static("x-badge-age"),
+                http::HeaderValue::from_str(&age_seconds.to_string())
+                    .map_err(ServiceError::internal)?,
+            );
+            use chrono::TimeZone;
+            let refreshed_at = chrono::Utc.timestamp_millis(refreshed_millis as i64);
+            hdrs.insert(
+                http::HeaderName::from_static("x-badge-refreshed-at"),
+                http::HeaderValue::from_str(&refreshed_at.to_rfc2822())
+                    .map_err(ServiceError::internal)?,
+            );
+            let last_modified =
+                http::header::HttpDate::from(refreshed_system_time(refreshed_millis));
+            hdrs.insert(
+                http::header::LAST_MODIFIED,
+                http::HeaderValue::from_str(&last_modified.to_string())
+                    .map_err(ServiceError::internal)?,
+            );
+        }
+        Ok(resp)
+    }
+}
+
+/// Look up the badge within the request's deadline. If the lookup runs
+/// over, whatever copy we have on hand is served rather than holding
+/// the connection open.
+async fn get_cached_within_deadline(params: &Params) -> errors::Result<(bool, Content)> {
+    if CONFIG.request_deadline_ms == 0 {
+        return cache::get_cached(params).await;
+    }
+    let deadline = std::time::Duration::from_millis(CONFIG.request_deadline_ms);
+    match actix_web::rt::time::timeout(deadline, cache::get_cached(params)).await {
+        Ok(result) => result,
+        Err(_) => {
+            crate::metrics::inc("request_deadline_exceeded_total", &[]);
+            slog::warn!(
+                LOG,
+                "deadline exceeded retrieving {}, falling back",
+                params.cache_name
+            );
+            match cache::get_stale(&params.cache_name).await {
+                Some(content) => Ok((true, content)),
+                None => Err(ServiceError::Timeout),
+            }
+        }
+    }
+}
+
+async fn get_cached_badge(params: &Params) -> errors::Result<BadgeResult> {
+    let (was_cached, content) = match get_cached_within_deadline(params).await {
+        Ok((was_cached, content)) => (was_cached, Some(content)),
+        Err(e @ ServiceError::NotFound(_)) | Err(e @ ServiceError::Overloaded) => return Err(e),
+        Err(ServiceError::DiskFull)
+        | Err(ServiceError::ReadOnly)
+        | Err(ServiceError::Maintenance)
+        | Err(ServiceError::RateLimited(_)) => (false, None),
+        Err(e) => {
+            slog::error!(LOG, "error requesting badge {:?}", e);
+            (false, None)
+        }
+    };
+    let refreshed_millis = content
+        .as_ref()
+        .and_then(|_| cache::refreshed_millis(&params.cache_name));
+    let expires_millis = content
+        .as_ref()
+        .and_then(|_| cache::expires_millis(&params.cache_name));
+    Ok(BadgeResult {
+        was_cached,
+        content,
+        refreshed_millis,
+        expires_millis,
+        vary_accept: params.vary_accept,
+        ext: params.ext.clone(),
+        redirect_url: params.redirect_url.clone(),
+        show_age: params.show_age,
+    })
+}
+
+/// Upstream doesn't know this badge, so there's no point redirecting
+/// clients there. Browsers and CDNs are told to hold on to this only as
+/// long as we hold on to the negative result.
+fn not_found_response(params: &Params) -> HttpResponse {
+    slog::info!(LOG, "unknown badge: {}", params.cache_name);
+    crate::metrics::inc(
+        "unknown_badge_requests_total",
+        &[("kind", params.kind.as_str())],
+    );
+    let (content_type, body) = render_error_badge(params.kind.as_str(), "not found", &params.ext);
+    let mut resp = HttpResponse::NotFound();
+    if params.vary_accept {
+        resp.header(http::header::VARY, "Accept");
+    }
+    resp.content_type(content_type)
+        .header(
+            http::header::CACHE_CONTROL,
+            format!("max-age={}, public", CONFIG.negative_cache_seconds),
+        )
+        .header(
+            http::HeaderName::from_static("x-was-cached"),
+            http::HeaderValue::from_static("false"),
+        )
+        .body(body)
+}
+
+// how long clients are told to wait when misses are being shed
+const OVERLOAD_RETRY_AFTER_SECONDS: u64 = 5;
+
+/// Too many misses are already waiting on upstream. Clients are told when
+/// to come back instead of joining the queue.
+fn overloaded_response(params: &Params) -> HttpResponse {
+    let (content_type, body) = render_error_badge(params.kind.as_str(), "busy", &params.ext);
+    HttpResponse::ServiceUnavailable()
+        .content_type(content_type)
+        .header(
+            http::header::RETRY_AFTER,
+            OVERLOAD_RETRY_AFTER_SECONDS.to_string(),
+        )
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .body(body)
+}
+
+async fn get_badge_result_for_kind(
+    name: String,
+    request: HttpRequest,
+    kind: Kind,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    match crate::bots::check(&request) {
+        crate::bots::Verdict::Allow => (),
+        crate::bots::Verdict::Deny => return Ok(HttpResponse::Forbidden().body("forbidden")),
+        crate::bots::Verdict::Throttle { retry_after } => {
+            return Ok(HttpResponse::TooManyRequests()
+                .header(
+                    http::header::RETRY_AFTER,
+                    retry_after.as_secs_f64().ceil().to_string(),
+                )
+                .body("too many requests"))
+        }
+    }
+    let params = Params::new(&name, kind, &request).map_err(|e| {
+        slog::error!(LOG, "error parsing badge {}: {:?}", name, e);
+        e
+    })?;
+    if CONFIG.proxy_only {
+        return proxy_badge_for_params(&params, &request).await;
+    }
+    if let Some(resp) = forward_to_owner(&params, &request).await {
+        return Ok(resp);
+    }
+    let start = std::time::Instant::now();
+    let badge = match get_cached_badge(&params).await {
+        Ok(badge) => badge,
+        Err(ServiceError::NotFound(_)) => return Ok(not_found_response(&params)),
+        Err(ServiceError::Overloaded) => return Ok(overloaded_response(&params)),
+        Err(e) => {
+            slog::error!(LOG, "error retrieving badge {}: {:?}", name, e);
+            errors::record(&params.cache_name, &e);
+            return Err(e.into());
+        }
+    };
+    if params.immutable && badge.content.is_some() {
+        if let Some(file_name) = cache::published_file_name(&params.cache_name) {
+            return Ok(immutable_redirect(&params, &file_name));
+        }
+    }
+    let lookup = (badge.lookup_step(), start.elapsed());
+    let serve_start = std::time::Instant::now();
+    let mut resp = badge.into_response(&request).await.map_err(|e| {
+        slog::error!(LOG, "error loading badge {}: {:?}", name, e);
+        errors::record(&params.cache_name, &e);
+        e
+    })?;
+    set_timing_headers(
+        &mut resp,
+        &[lookup, ("serve", serve_start.elapsed())],
+        start.elapsed(),
+    )?;
+    Ok(resp)
+}
+
+/// Send `?immutable` requests on to the url for the content currently
+/// published, which only lives as long as the badge stays the same
+fn immutable_redirect(params: &Params, file_name: &str) -> HttpResponse {
+    let hash = file_name.split('.').next().unwrap_or(file_name);
+    let mut location = format!(
+        "/immutable/{}{}/{}.{}",
+        hash,
+        params.kind.route(),
+        params.name,
+        params.ext
+    );
+    let query = params.query_for_links();
+    if !query.is_empty() {
+        location = format!("{}?{}", location, query);
+    }
+    crate::metrics::inc(
+        "immutable_redirects_total",
+        &[("kind", params.kind.as_str())],
+    );
+    HttpResponse::Found()
+        .header(http::header::LOCATION, location)
+        .header(
+            http::header::CACHE_CONTROL,
+            format!("max-age={}, public", CONFIG.http_expiry_seconds),
+        )
+        .finish()
+}
+
+/// A badge by its content hash. What's behind the url never changes so
+/// browsers can hold on to it for good, once the badge moves on the
+/// hash is gone and requests go back to the plain badge url.
+async fn immutable_badge(
+    web::Path((hash, path)): web::Path<(String, String)>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let ext = path.rsplit('.').next().unwrap_or("");
+    let is_hash = hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_hash || !BADGE_EXTS.contains(&ext) {
+        return p404().await;
+    }
+    if let Some(bytes) = cache::content_bytes(&format!("{}.{}", hash, ext)).await? {
+        crate::metrics::inc("immutable_requests_total", &[("result", "hit")]);
+        return Ok(HttpResponse::Ok()
+            .content_type(content_type_for_ext(ext))
+            .header(
+                http::header::CACHE_CONTROL,
+                format!("public, max-age={}, immutable", IMMUTABLE_MAX_AGE_SECONDS),
+            )
+            .header(http::header::ETAG, format!("\"{}\"", hash))
+            .body(bytes));
+    }
+    crate::metrics::inc("immutable_requests_total", &[("result", "gone")]);
+    let mut location = format!("/{}", path);
+    if !request.query_string().is_empty() {
+        location = format!("{}?{}", location, request.query_string());
+    }
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, location)
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .finish())
+}
+
+/// Let clients see where the time went, `Server-Timing` shows up in
+/// browser dev tools, and `X-Runtime` is the total in seconds
+fn set_timing_headers(
+    resp: &mut HttpResponse,
+    steps: &[(&str, std::time::Duration)],
+    total: std::time::Duration,
+) -> errors::Result<()> {
+    let timing = steps
+        .iter()
+        .map(|(step, dur)| format!("{};dur={:.3}", step, dur.as_secs_f64() * 1000.))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let hdrs = resp.headers_mut();
+    hdrs.insert(
+        http::HeaderName::from_static("server-timing"),
+        http::HeaderValue::from_str(&timing).map_err(ServiceError::internal)?,
+    );
+    hdrs.insert(
+        http::HeaderName::from_static("x-runtime"),
+        http::HeaderValue::from_str(&format!("{:.6}", total.as_secs_f64()))
+            .map_err(ServiceError::internal)?,
+    );
+    Ok(())
+}
+
+/// Hand a badge owned by another node in the cluster off to it, so it's
+/// only fetched and stored there. `None` when it should be handled here,
+/// including when the owner can't be reached.
+async fn forward_to_owner(params: &Params, request: &HttpRequest) -> Option<HttpResponse> {
+    if request.headers().contains_key(FORWARDED_HEADER) {
+        return None;
+    }
+    let owner = crate::cluster::remote_owner(&params.cache_name)?;
+    let mut url = format!(
+        "{}{}/{}.{}",
+        owner,
+        params.kind.route(),
+        params.name,
+        params.ext
+    );
+    let query = params.query_for_links();
+    if !query.is_empty() {
+        url = format!("{}?{}", url, query);
+    }
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::HeaderName::from_static(FORWARDED_HEADER),
+        reqwest::header::HeaderValue::from_static("1"),
+    );
+    if let Some(key_format) = request.headers().get(KEY_FORMAT_HEADER) {
+        headers.insert(
+            reqwest::header::HeaderName::from_static(KEY_FORMAT_HEADER),
+            key_format.clone(),
+        );
+    }
+    match _proxy_upstream(&url, Some(request), headers).await {
+        Ok(resp) if !resp.status().is_server_error() => {
+            crate::metrics::inc("cluster_forwards_total", &[("result", "forwarded")]);
+            Some(resp)
+        }
+        Ok(resp) => {
+            crate::metrics::inc("cluster_forwards_total", &[("result", "error")]);
+            slog::error!(
+                LOG,
+                "owner {} failed badge {}: {}",
+                owner,
+                params.cache_name,
+                resp.status()
+            );
+            None
+        }
+        Err(e) => {
+            crate::metrics::inc("cluster_forwards_total", &[("result", "error")]);
+            slog::error!(
+                LOG,
+                "unable to forward badge {} to {}: {:?}",
+                params.cache_name,
+                owner,
+                e
+            );
+            None
+        }
+    }
+}
+
+async fn proxy_badge_for_params(
+    params: &Params,
+    request: &HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let resp = proxy_upstream_with_headers(&params.redirect_url, Some(request))
+        .await
+        .map_err(|e| {
+            slog::error!(LOG, "error proxying badge {}: {:?}", params.cache_name, e);
+            e
+        })?;
+    Ok(resp)
+}
+
+async fn proxy_badge_for_kind(
+    name: String,
+    request: HttpRequest,
+    kind: Kind,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let params = Params::new(&name, kind, &request).map_err(|e| {
+        slog::error!(LOG, "error parsing badge {}: {:?}", name, e);
+        e
+    })?;
+    proxy_badge_for_params(&params, &request).await
+}
+
+async fn proxy_crate(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let resp = proxy_badge_for_kind(name, request, Kind::Crate).await?;
+    Ok(resp)
+}
+
+async fn proxy_badge(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let resp = proxy_badge_for_kind(name, request, Kind::Badge).await?;
+    Ok(resp)
+}
+
+async fn get_crate(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let resp = get_badge_result_for_kind(name, request, Kind::Crate).await?;
+    Ok(resp)
+}
+
+async fn get_badge(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let resp = get_badge_result_for_kind(name, request, Kind::Badge).await?;
+    Ok(resp)
+}
+
+/// CDNs and image proxies tend to HEAD a badge before they GET it. Rather
+/// than have the GET pay for the miss, start fetching the badge into the
+/// cache in the background and answer the HEAD right away.
+async fn head_badge_for_kind(
+    name: String,
+    request: HttpRequest,
+    kind: Kind,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    match crate::bots::check(&request) {
+        crate::bots::Verdict::Allow => (),
+        crate::bots::Verdict::Deny => return Ok(HttpResponse::Forbidden().finish()),
+        crate::bots::Verdict::Throttle { .. } => {
+            return Ok(HttpResponse::TooManyRequests().finish())
+        }
+    }
+    let params = Params::new(&name, kind, &request)?;
+    let fresh = cache::expires_millis(&params.cache_name)
+        .map(|expires| expires > cache::now_millis())
+        .unwrap_or(false);
+    let handled_here = !CONFIG.proxy_only
+        && (request.headers().contains_key(FORWARDED_HEADER)
+            || crate::cluster::remote_owner(&params.cache_name).is_none());
+    let content_type = content_type_for_ext(&params.ext);
+    if !fresh && handled_here {
+        crate::metrics::inc("head_prefetches_total", &[("kind", params.kind.as_str())]);
+        actix_web::rt::spawn(async move {
+            if let Err(e) = cache::get_cached(&params).await {
+                slog::debug!(LOG, "error prefetching {}: {:?}", params.cache_name, e);
+            }
+        });
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .header(
+            http::HeaderName::from_static("x-was-cached"),
+            http::HeaderValue::from_static(if fresh { "true" } else { "false" }),
+        )
+        .finish())
+}
+
+async fn head_crate(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    head_badge_for_kind(name, request, Kind::Crate).await
+}
+
+/// With `CANONICAL_REDIRECTS` set, a 301 from an alias route to the same
+/// badge on its kind's canonical route, so caches in front of us only
+/// ever see one url per badge
+fn canonical_redirect(kind: Kind, name: &str, request: &HttpRequest) -> Option<HttpResponse> {
+    if !CONFIG.canonical_redirects {
+        return None;
+    }
+    let mut location = format!("{}/{}", kind.route(), name);
+    if !request.query_string().is_empty() {
+        location = format!("{}?{}", location, request.query_string());
+    }
+    crate::metrics::inc("canonical_redirects_total", &[("kind", kind.as_str())]);
+    Some(
+        HttpResponse::MovedPermanently()
+            .header(http::header::LOCATION, location)
+            .header(
+                http::header::CACHE_CONTROL,
+                format!("max-age={}, public", CONFIG.http_expiry_seconds),
+            )
+            .finish(),
+    )
+}
+
+/// A badge requested on an alias route, `/crate/{name}` or a `ROUTE_ALIASES` path
+async fn get_alias(
+    name: String,
+    request: HttpRequest,
     kind: Kind,
 ) -> actix_web::Result<HttpResponse, actix_web::Error> {
-    let params = Params::new(&name, kind, &request)
-        .map_err(|_| actix_web::error::ErrorBadRequest(format!("invalid badge name: {}", name)))?;
-    _reset_cached_badge(&params).await.map_err(|e| {
-        slog::error!(LOG, "error resting badge {}: {:?}", name, e);
-        actix_web::error::ErrorInternalServerError(format!("error resting badge: {}", name))
-    })?;
+    match canonical_redirect(kind, &name, &request) {
+        Some(resp) => Ok(resp),
+        None => get_badge_result_for_kind(name, request, kind).await,
+    }
+}
+
+async fn head_alias(
+    name: String,
+    request: HttpRequest,
+    kind: Kind,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    match canonical_redirect(kind, &name, &request) {
+        Some(resp) => Ok(resp),
+        None => head_badge_for_kind(name, request, kind).await,
+    }
+}
+
+async fn head_badge(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    head_badge_for_kind(name, request, Kind::Badge).await
+}
+
+async fn _reset_cached_badge(params: &Params) -> errors::Result<()> {
+    slog::info!(LOG, "dropping cached badge: {}", params.cache_name);
+    cache::invalidate(&params.cache_name).await;
+    Ok(())
+}
+
+async fn reset_cached_badge(
+    name: String,
+    request: HttpRequest,
+    kind: Kind,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let params = Params::new(&name, kind, &request)?;
+    _reset_cached_badge(&params).await.map_err(|e| {
+        slog::error!(LOG, "error resting badge {}: {:?}", name, e);
+        e
+    })?;
+    let path = request.path().trim_start_matches("/reset");
+    let path = if request.query_string().is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, request.query_string())
+    };
+    record_reset(&path, &params.cache_name);
+    crate::audit::record(
+        "reset",
+        &crate::audit::actor(&request),
+        &[params.cache_name.clone()],
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "ok": "ok",
+        "cache_name": params.cache_name,
+    })))
+}
+
+lazy_static::lazy_static! {
+    // signatures of reset links that have been used, mapped to their expiry
+    static ref USED_RESET_LINKS: dashmap::DashMap<String, u64> = dashmap::DashMap::new();
+}
+
+fn reset_link_message(cache_name: &str, expires: u64) -> String {
+    format!("reset\n{}\n{}", cache_name, expires)
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct SignedReset {
+    key: String,
+    expires: u64,
+    sig: String,
+}
+impl SignedReset {
+    fn check(&self) -> Result<(), &'static str> {
+        let signing_key = CONFIG.reset_signing_key.as_ref().ok_or("disabled")?;
+        let message = reset_link_message(&self.key, self.expires);
+        if !crate::signing::verify_any(
+            signing_key,
+            &CONFIG.reset_signing_key_previous,
+            &message,
+            &self.sig,
+        ) {
+            return Err("invalid signature");
+        }
+        if self.expires < (cache::now_millis() / 1000) as u64 {
+            return Err("expired");
+        }
+        Ok(())
+    }
+}
+
+/// Create a signed link that resets a single badge, without
+/// handing out the admin token
+async fn admin_reset_link_for_kind(
+    name: String,
+    request: HttpRequest,
+    kind: Kind,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let signing_key = match (&CONFIG.admin_token, &CONFIG.reset_signing_key) {
+        (Some(_), Some(key)) => key,
+        _ => return p404().await,
+    };
+    if !admin_authorized(&request) {
+        return Ok(unauthorized());
+    }
+    let params = Params::new(&name, kind, &request)?;
+    let expires = (cache::now_millis() / 1000) as u64 + CONFIG.reset_link_ttl_seconds;
+    let sig = crate::signing::sign(
+        signing_key,
+        &reset_link_message(&params.cache_name, expires),
+    );
+    let mut url =
+        reqwest::Url::parse("http://localhost/reset/signed").map_err(ServiceError::internal)?;
+    url.query_pairs_mut()
+        .append_pair("key", &params.cache_name)
+        .append_pair("expires", &expires.to_string())
+        .append_pair("sig", &sig);
+    let path = format!("{}?{}", url.path(), url.query().unwrap_or(""));
+    crate::audit::record(
+        "admin_reset_link",
+        &crate::audit::actor(&request),
+        &[params.cache_name.clone()],
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "cache_name": params.cache_name,
+        "expires": expires,
+        "path": path,
+    })))
+}
+
+async fn admin_reset_link_crate(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    admin_reset_link_for_kind(name, request, Kind::Crate).await
+}
+
+async fn admin_reset_link_badge(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    admin_reset_link_for_kind(name, request, Kind::Badge).await
+}
+
+/// Check whether a signed reset link is still good
+async fn signed_reset_check(query: web::Query<SignedReset>) -> actix_web::Result<HttpResponse> {
+    let result = query.check().and_then(|_| {
+        if USED_RESET_LINKS.contains_key(&query.sig) {
+            Err("already used")
+        } else {
+            Ok(())
+        }
+    });
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "valid": result.is_ok(),
+        "reason": result.err(),
+        "cache_name": query.key,
+        "expires": query.expires,
+    })))
+}
+
+/// Use up a signed reset link, dropping the badge it was made for
+async fn signed_reset(
+    query: web::Query<SignedReset>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    let forbidden = |reason: &str| {
+        HttpResponse::Forbidden().json(serde_json::json!({
+            "ok": false,
+            "reason": reason,
+        }))
+    };
+    if let Err(reason) = query.check() {
+        return Ok(forbidden(reason));
+    }
+    // forget links that have expired anyway
+    let now = (cache::now_millis() / 1000) as u64;
+    USED_RESET_LINKS.retain(|_, expires| *expires >= now);
+    // claiming the link is the check that it's unused, so
+    // concurrent requests with the same link can't both get through
+    if USED_RESET_LINKS
+        .insert(query.sig.clone(), query.expires)
+        .is_some()
+    {
+        return Ok(forbidden("already used"));
+    }
+
+    slog::info!(LOG, "dropping cached badge via signed link: {}", query.key);
+    cache::invalidate(&query.key).await;
+    record_reset("/signed", &query.key);
+    crate::audit::record(
+        "signed_reset",
+        &crate::audit::actor(&request),
+        &[query.key.clone()],
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "ok": "ok",
+        "cache_name": query.key,
+    })))
+}
+
+/// Let a crate's owners purge its badges, proving ownership with their crates.io token
+async fn purge_crate(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    // no crate could have this name, so don't spend the caller's token asking
+    if !CONFIG.public_purge || !Kind::Crate.is_valid_name(&name) {
+        return p404().await;
+    }
+    let token = request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer ").trim())
+        .unwrap_or("");
+    if token.is_empty() {
+        return Ok(unauthorized());
+    }
+    use crate::crates_io::Ownership;
+    match crate::crates_io::check_ownership(token, &name).await {
+        Ok(Ownership::Owner(login)) => {
+            let purged = cache::purge_crate(&name).await;
+            slog::info!(
+                LOG,
+                "{} purged {} cached badges for crate {}",
+                login,
+                purged.len(),
+                name
+            );
+            crate::metrics::inc("public_purges_total", &[("result", "purged")]);
+            for key in purged.iter() {
+                record_reset(&format!("/purge/crate/{}", name), key);
+            }
+            crate::audit::record(
+                "purge",
+                &format!("{} crates.io:{}", crate::audit::actor(&request), login),
+                &purged,
+            );
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "ok": "ok",
+                "purged": purged,
+            })))
+        }
+        Ok(Ownership::NotOwner(login)) => {
+            slog::warn!(
+                LOG,
+                "{} tried to purge crate {} they don't own",
+                login,
+                name
+            );
+            crate::metrics::inc("public_purges_total", &[("result", "not_owner")]);
+            Ok(HttpResponse::Forbidden().body(format!("{} is not an owner of {}", login, name)))
+        }
+        Ok(Ownership::InvalidToken) => {
+            crate::metrics::inc("public_purges_total", &[("result", "invalid_token")]);
+            Ok(unauthorized())
+        }
+        Err(e) => {
+            slog::error!(LOG, "error checking ownership of {}: {:?}", name, e);
+            crate::metrics::inc("public_purges_total", &[("result", "error")]);
+            Err(e.into())
+        }
+    }
+}
+
+async fn reset_crate(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let resp = reset_cached_badge(name, request, Kind::Crate).await?;
+    Ok(resp)
+}
+
+async fn reset_badge(
+    web::Path(name): web::Path<String>,
+    request: web::HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let resp = reset_cached_badge(name, request, Kind::Badge).await?;
+    Ok(resp)
+}
+
+macro_rules! make_file_serve_fns {
+    ($([$name:ident, $path:expr]),* $(,),*) => {
+        $(
+            async fn $name() -> actix_web::Result<NamedFile> {
+                Ok(crate::blocking::run("open", || NamedFile::open($path))
+                    .await
+                    .map_err(|_| actix_web::error::ErrorInternalServerError("asset not found"))?)
+            }
+        )*
+    };
+}
+
+make_file_serve_fns!([favicon, "static/favicon.ico"],);
+
+// the html pages worth listing in a sitemap
+const SITEMAP_PAGES: &[&str] = &["/", "/reset"];
+
+fn render_robots() -> String {
+    let mut lines = vec!["User-agent: *".to_string()];
+    if CONFIG.robots_disallow_all {
+        lines.push("Disallow: /".to_string());
+    } else {
+        lines.push("Allow: /$".to_string());
+        for path in CONFIG.robots_disallow.iter() {
+            lines.push(format!("Disallow: {}", path));
+        }
+        if let Some(base) = &CONFIG.sitemap_base_url {
+            lines.push(String::new());
+            lines.push(format!(
+                "Sitemap: {}/sitemap.xml",
+                base.trim_end_matches('/')
+            ));
+        }
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+async fn robots() -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(render_robots()))
+}
+
+async fn sitemap() -> actix_web::Result<HttpResponse> {
+    let base = match &CONFIG.sitemap_base_url {
+        Some(base) if !CONFIG.robots_disallow_all => base.trim_end_matches('/'),
+        _ => return p404().await,
+    };
+    let urls = SITEMAP_PAGES
+        .iter()
+        .filter(|page| {
+            !CONFIG
+                .robots_disallow
+                .iter()
+                .any(|d| d != "/" && page.starts_with(d.as_str()))
+        })
+        .map(|page| format!("  <url><loc>{}{}</loc></url>", base, page))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n{}\n</urlset>\n",
+        urls
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(body))
+}
+
+async fn status() -> actix_web::Result<HttpResponse> {
+    let status = if crate::health::upstream_down() {
+        "degraded"
+    } else {
+        "ok"
+    };
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": status,
+        "version": CONFIG.version,
+        "upstream": crate::health::upstream_report(),
+    })))
+}
+
+async fn health() -> actix_web::Result<HttpResponse> {
+    let (healthy, report) = crate::health::report();
+    if healthy {
+        Ok(HttpResponse::Ok().json(report))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(report))
+    }
+}
+
+/// Check the request's bearer token against a configured token
+fn bearer_token(request: &HttpRequest) -> &str {
+    request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("")
+}
+
+fn bearer_authorized(request: &HttpRequest, expected: &Option<String>) -> bool {
+    let expected = match expected {
+        Some(t) => t,
+        None => return false,
+    };
+    crate::signing::constant_time_eq(bearer_token(request), expected)
+}
+
+/// Whether `given` is the admin token, current or previous
+fn is_admin_token(given: &str) -> bool {
+    match &CONFIG.admin_token {
+        Some(token) => crate::signing::matches_any(given, token, &CONFIG.admin_token_previous),
+        None => false,
+    }
+}
+
+/// The admin token as a bearer token, or a session from `/admin/login`
+fn admin_authorized(request: &HttpRequest) -> bool {
+    is_admin_token(bearer_token(request)) || crate::session::valid(request)
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .header(http::header::WWW_AUTHENTICATE, "Bearer")
+        .body("unauthorized")
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct CacheDirSwitch {
+    dir: String,
+    #[serde(default)]
+    migrate: bool,
+}
+
+/// Switch to a different cache dir without restarting
+async fn admin_cache_dir(
+    request: HttpRequest,
+    body: web::Json<CacheDirSwitch>,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    if !admin_authorized(&request) {
+        return Ok(unauthorized());
+    }
+    crate::audit::record(
+        "admin_cache_dir",
+        &crate::audit::actor(&request),
+        &[body.dir.clone()],
+    );
+    let previous = cache::switch_cache_dir(&body.dir, body.migrate).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "ok": "ok",
+        "dir": body.dir,
+        "previous": previous,
+        "migrate": body.migrate,
+    })))
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct Maintenance {
+    enabled: bool,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// Turn maintenance mode on or off. While on, badges are only served
+/// from what's cached, and pages show the message as a banner
+async fn admin_maintenance(
+    request: HttpRequest,
+    body: web::Json<Maintenance>,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    if !admin_authorized(&request) {
+        return Ok(unauthorized());
+    }
+    let message = if body.enabled {
+        Some(
+            body.message
+                .clone()
+                .unwrap_or_else(|| "badge-cache is undergoing maintenance".to_string()),
+        )
+    } else {
+        None
+    };
+    slog::warn!(
+        LOG,
+        "maintenance mode {}",
+        if body.enabled { "on" } else { "off" }
+    );
+    crate::audit::record(
+        "admin_maintenance",
+        &crate::audit::actor(&request),
+        &[body.enabled.to_string()],
+    );
+    crate::health::set_maintenance(message.clone());
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "ok": "ok",
+        "enabled": body.enabled,
+        "message": message,
     })))
 }
 
-async fn reset_crate(
-    web::Path(name): web::Path<String>,
+/// The most recent upstream, io, and template errors
+async fn admin_errors(request: HttpRequest) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    if !admin_authorized(&request) {
+        return Ok(unauthorized());
+    }
+    crate::audit::record("admin_errors", &crate::audit::actor(&request), &[]);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "errors": errors::recent(),
+    })))
+}
+
+/// Today's request counts and quotas for each api key
+async fn admin_usage(request: HttpRequest) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    if !admin_authorized(&request) {
+        return Ok(unauthorized());
+    }
+    crate::audit::record("admin_usage", &crate::audit::actor(&request), &[]);
+    Ok(HttpResponse::Ok().json(crate::quota::report()))
+}
+
+/// Config, health, cache, disk, and upstream state for incident triage
+async fn admin_diagnostics(
     request: HttpRequest,
 ) -> actix_web::Result<HttpResponse, actix_web::Error> {
-    let resp = reset_cached_badge(name, request, Kind::Crate).await?;
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    if !admin_authorized(&request) {
+        return Ok(unauthorized());
+    }
+    crate::audit::record("admin_diagnostics", &crate::audit::actor(&request), &[]);
+    Ok(HttpResponse::Ok().json(crate::diagnostics::report().await))
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct AdminLogin {
+    token: String,
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .header(http::header::LOCATION, location)
+        .finish()
+}
+
+fn admin_login_page(
+    template: &Templates,
+    error: Option<&str>,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    // admin pages are for operators, and stay in the default locale
+    let mut ctx = page_context(crate::i18n::DEFAULT_LOCALE);
+    if let Some(error) = error {
+        ctx.insert("error", error);
+    }
+    let s = template.render("admin_login.html", &ctx)?;
+    let mut resp = match error {
+        Some(_) => HttpResponse::Unauthorized(),
+        None => HttpResponse::Ok(),
+    };
+    Ok(resp.content_type("text/html").body(s))
+}
+
+async fn admin_login_form(
+    template: web::Data<Templates>,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    admin_login_page(&template, None)
+}
+
+/// Trade the admin token for a short-lived signed session cookie,
+/// so the admin pages can be used from a browser
+async fn admin_login(
+    template: web::Data<Templates>,
+    form: web::Form<AdminLogin>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    let actor = crate::audit::actor(&request);
+    if !is_admin_token(&form.token) {
+        crate::audit::record("admin_login_failed", &actor, &[]);
+        return admin_login_page(&template, Some("wrong token"));
+    }
+    crate::audit::record("admin_login", &actor, &[]);
+    let cookie = crate::session::issue(&request)
+        .ok_or_else(|| ServiceError::internal("no admin token to sign sessions with"))?;
+    let mut resp = redirect("/admin");
+    resp.headers_mut().insert(
+        http::header::SET_COOKIE,
+        http::HeaderValue::from_str(&cookie).map_err(ServiceError::internal)?,
+    );
     Ok(resp)
 }
 
-async fn reset_badge(
+async fn admin_logout() -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let mut resp = redirect("/admin/login");
+    resp.headers_mut().insert(
+        http::header::SET_COOKIE,
+        http::HeaderValue::from_str(&crate::session::clear()).map_err(ServiceError::internal)?,
+    );
+    Ok(resp)
+}
+
+/// The admin dashboard, for a logged in browser
+async fn admin_dashboard(
+    template: web::Data<Templates>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    if !admin_authorized(&request) {
+        return Ok(redirect("/admin/login"));
+    }
+    let mut ctx = page_context(crate::i18n::DEFAULT_LOCALE);
+    ctx.insert("recent_errors", &crate::errors::recent());
+    ctx.insert("diagnostics", &crate::diagnostics::report().await);
+    let s = template.render("admin.html", &ctx)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html")
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .body(s))
+}
+
+/// Every fresh entry, for a peer warming its cache from ours
+async fn admin_index(request: HttpRequest) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    if !admin_authorized(&request) {
+        return Ok(unauthorized());
+    }
+    crate::audit::record("admin_index", &crate::audit::actor(&request), &[]);
+    let now = cache::now_millis();
+    let entries = cache::entries()
+        .into_iter()
+        .filter(|e| !cache::is_expired(e, now))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// Drop a key a peer has reset or seen change
+async fn admin_invalidate(
+    request: HttpRequest,
+    body: web::Json<crate::peer::Invalidation>,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    if !admin_authorized(&request) {
+        return Ok(unauthorized());
+    }
+    slog::info!(LOG, "dropping cached badge for peer: {}", body.cache_name);
+    crate::audit::record(
+        "admin_invalidate",
+        &crate::audit::actor(&request),
+        &[body.cache_name.clone()],
+    );
+    cache::remove(&body.cache_name).await;
+    crate::metrics::inc("peer_invalidations_received_total", &[]);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "ok": "ok",
+        "cache_name": body.cache_name,
+    })))
+}
+
+/// Raw content by its content-addressed file name, for a peer
+async fn admin_content(
+    file_name: web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.admin_token.is_none() {
+        return p404().await;
+    }
+    if !admin_authorized(&request) {
+        return Ok(unauthorized());
+    }
+    crate::audit::record(
+        "admin_content",
+        &crate::audit::actor(&request),
+        &[file_name.to_string()],
+    );
+    match cache::content_bytes(&file_name).await? {
+        Some(bytes) => Ok(HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(bytes)),
+        None => p404().await,
+    }
+}
+
+/// Show how a badge request maps to a cache key and what's cached
+/// under it, without serving or fetching the badge
+async fn debug_badge_for_kind(
+    name: String,
+    request: HttpRequest,
+    kind: Kind,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.debug_token.is_none() {
+        return p404().await;
+    }
+    if !bearer_authorized(&request, &CONFIG.debug_token) {
+        return Ok(unauthorized());
+    }
+    let params = Params::new(&name, kind, &request)?;
+    let cache = cache::inspect(&params.cache_name).await;
+    Ok(HttpResponse::Ok()
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .json(serde_json::json!({
+            "params": params,
+            "cache": cache,
+        })))
+}
+
+async fn debug_crate(
     web::Path(name): web::Path<String>,
-    request: web::HttpRequest,
+    request: HttpRequest,
 ) -> actix_web::Result<HttpResponse, actix_web::Error> {
-    let resp = reset_cached_badge(name, request, Kind::Badge).await?;
-    Ok(resp)
+    debug_badge_for_kind(name, request, Kind::Crate).await
 }
 
-macro_rules! make_file_serve_fns {
-    ($([$name:ident, $path:expr]),* $(,),*) => {
-        $(
-            async fn $name() -> actix_web::Result<NamedFile> {
-                Ok(NamedFile::open($path).map_err(|_| actix_web::error::ErrorInternalServerError("asset not found"))?)
-            }
-        )*
-    };
+async fn debug_badge(
+    web::Path(name): web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    debug_badge_for_kind(name, request, Kind::Badge).await
+}
+
+#[derive(Debug, Clone, Copy, serde_derive::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BadgeSort {
+    Name,
+    Hits,
+    Age,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct BadgesQuery {
+    kind: Option<Kind>,
+    prefix: Option<String>,
+    sort: Option<BadgeSort>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
 }
 
-make_file_serve_fns!(
-    [favicon, "static/favicon.ico"],
-    [robots, "static/robots.txt"],
-);
+// formats every badge can be requested in
+const BADGE_EXTS: &[&str] = &["svg", "png", "json"];
 
-async fn status() -> actix_web::Result<HttpResponse> {
+/// Every badge route and the ways to reset badges, built from the same
+/// kinds and aliases the routes are registered from
+async fn api_index() -> actix_web::Result<HttpResponse> {
+    let kinds = Kind::ALL
+        .iter()
+        .map(|kind| {
+            let path = format!("{}/{{name}}.{{ext}}", kind.route());
+            let (min_segments, max_segments) = kind.name_segments();
+            let mut aliases = CONFIG
+                .route_aliases
+                .iter()
+                .filter(|(_, k)| k == kind)
+                .map(|(p, _)| p.clone())
+                .collect::<Vec<_>>();
+            if *kind == Kind::Crate {
+                aliases.insert(0, "/crate/{name}".to_string());
+            }
+            serde_json::json!({
+                "kind": kind.as_str(),
+                "badge": {"method": "GET", "path": path},
+                "proxy": {"method": "GET", "path": format!("/proxy{}", path)},
+                "reset": {"method": "DELETE", "path": format!("/reset{}", path)},
+                "debug": {"method": "GET", "path": format!("/debug{}", path)},
+                "aliases": aliases,
+                "name_segments": {"min": min_segments, "max": max_segments},
+                "upstream": kind.url_template(),
+            })
+        })
+        .collect::<Vec<_>>();
     Ok(HttpResponse::Ok().json(serde_json::json!({
-        "status": "ok",
         "version": CONFIG.version,
+        "exts": BADGE_EXTS,
+        // empty when every query param is passed along upstream
+        "query_params": CONFIG.query_param_allowlist,
+        "kinds": kinds,
+        "reset": [
+            {"method": "GET", "path": "/reset", "description": "reset form"},
+            {"method": "GET", "path": "/reset/recent", "description": "recently reset badges"},
+            {"method": "POST", "path": "/reset/signed", "description": "reset with a signed link"},
+            {"method": "POST", "path": "/purge/crate/{name}", "description": "reset every badge for a crate"},
+        ],
+    })))
+}
+
+async fn openapi() -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(crate::openapi::document()))
+}
+
+// page size bounds for /api/v1/badges
+const BADGES_DEFAULT_LIMIT: usize = 50;
+const BADGES_MAX_LIMIT: usize = 500;
+
+/// A crate's version, read out of its cached badge when there is one,
+/// otherwise looked up on crates.io
+async fn api_crate_version(
+    web::Path(name): web::Path<String>,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if !Kind::Crate.is_valid_name(&name) {
+        return Err(ServiceError::Parse(name).into());
+    }
+    if let Some((version, fetched_millis)) = cache::crate_version(&name).await {
+        use chrono::TimeZone;
+        crate::metrics::inc("crate_version_lookups_total", &[("source", "cache")]);
+        return Ok(HttpResponse::Ok().json(serde_json::json!({
+            "version": version.trim_start_matches('v'),
+            "as_of": chrono::Utc.timestamp_millis(fetched_millis as i64).to_rfc3339(),
+            "source": "cache",
+        })));
+    }
+    crate::metrics::inc("crate_version_lookups_total", &[("source", "crates.io")]);
+    match crate::crates_io::latest_version(&name).await {
+        Ok(version) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "version": version,
+            "as_of": chrono::Utc::now().to_rfc3339(),
+            "source": "crates.io",
+        }))),
+        Err(ServiceError::NotFound(_)) => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("crate {} not found", name),
+        }))),
+        Err(e) => {
+            slog::error!(LOG, "error looking up version of {}: {:?}", name, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// The daily snapshots of a badge, by its cache key
+async fn api_history(
+    web::Path(key): web::Path<String>,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    if CONFIG.history_file.is_none() {
+        return p404().await;
+    }
+    match crate::history::timeline(&key) {
+        Some(snapshots) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "key": key,
+            "snapshots": snapshots,
+        }))),
+        None => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("no history for {}", key),
+        }))),
+    }
+}
+
+/// Metadata about cached badges, filtered, sorted, and paginated
+async fn api_badges(query: web::Query<BadgesQuery>) -> actix_web::Result<HttpResponse> {
+    let query = query.into_inner();
+    let now = cache::now_millis();
+    let kind = query.kind.map(|k| k.as_str());
+    let mut entries = cache::entries()
+        .into_iter()
+        .filter(|e| kind.map(|k| e.kind == k).unwrap_or(true))
+        .filter(|e| {
+            query
+                .prefix
+                .as_ref()
+                .map(|p| e.name.starts_with(p.as_str()))
+                .unwrap_or(true)
+        })
+        .map(|e| {
+            let hits = cache::hits(&e.cache_name);
+            (e, hits)
+        })
+        .collect::<Vec<_>>();
+    match query.sort.unwrap_or(BadgeSort::Name) {
+        BadgeSort::Name => entries.sort_by(|(a, _), (b, _)| a.cache_name.cmp(&b.cache_name)),
+        BadgeSort::Hits => entries.sort_by(|(a, a_hits), (b, b_hits)| {
+            b_hits
+                .cmp(a_hits)
+                .then_with(|| a.cache_name.cmp(&b.cache_name))
+        }),
+        // oldest first
+        BadgeSort::Age => entries.sort_by_key(|(e, _)| e.created_millis),
+    }
+
+    let total = entries.len();
+    let limit = query
+        .limit
+        .unwrap_or(BADGES_DEFAULT_LIMIT)
+        .min(BADGES_MAX_LIMIT);
+    let badges = entries
+        .into_iter()
+        .skip(query.offset)
+        .take(limit)
+        .map(|(e, hits)| {
+            serde_json::json!({
+                "cache_name": e.cache_name,
+                "kind": e.kind,
+                "name": e.name,
+                "ext": e.ext,
+                "hits": hits,
+                "created_millis": e.created_millis as u64,
+                "age_seconds": (now.saturating_sub(e.created_millis) / 1000) as u64,
+                "expired": cache::is_expired(&e, now),
+                "content_hash": e.content_hash,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "total": total,
+        "offset": query.offset,
+        "limit": limit,
+        "badges": badges,
+    })))
+}
+
+async fn stats() -> actix_web::Result<HttpResponse> {
+    cache::record_cardinality();
+    let cache_entries = cache::CACHE.lock().await.len();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "cache_entries": cache_entries,
+        "cache_index_bytes": cache::index_bytes(),
+        "upstream": crate::upstream::SCHEDULER.stats(),
+        "metrics": crate::metrics::snapshot(),
     })))
 }
 
+async fn metrics() -> actix_web::Result<HttpResponse> {
+    cache::record_cardinality();
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(crate::metrics::render()))
+}
+
 async fn p404() -> actix_web::Result<HttpResponse> {
     Ok(HttpResponse::NotFound().body("nothing here"))
 }
 
+// how far off a path's first segment can be from a route's and still be suggested
+const MAX_ROUTE_DISTANCE: usize = 2;
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            row[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(row[j] + 1);
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+/// Badge urls a mistyped path was probably meant to be, e.g.
+/// `/crates/foo` -> `/crates/v/foo.svg`, `/badges/a-b-c` -> `/badge/a-b-c.svg`
+fn route_suggestions(path: &str) -> Vec<String> {
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    let first = match segments.first() {
+        Some(first) if first.len() >= 3 => first.to_lowercase(),
+        _ => return vec![],
+    };
+    let mut suggestions = vec![];
+    for kind in Kind::ALL {
+        let route = kind
+            .route()
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        if edit_distance(&first, route[0]) > MAX_ROUTE_DISTANCE {
+            continue;
+        }
+        // whatever's left after the parts of the route that were given is the name
+        let mut rest = &segments[1..];
+        for part in &route[1..] {
+            match rest.split_first() {
+                Some((s, tail)) if s.eq_ignore_ascii_case(part) => rest = tail,
+                _ => break,
+            }
+        }
+        let name = rest.join("/");
+        let name = match name.rfind('.') {
+            Some(i) if i > 0 => &name[..i],
+            _ => name.as_str(),
+        };
+        let name = if name.is_empty() { "<name>" } else { name };
+        let suggestion = format!("{}/{}.svg", kind.route(), name);
+        if !suggestions.contains(&suggestion) {
+            suggestions.push(suggestion);
+        }
+    }
+    suggestions
+}
+
+/// Anything that didn't match a route. Paths that look like a badge url
+/// gone wrong get suggestions for the right shape, as json when asked for.
+async fn not_found(
+    template: web::Data<Templates>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let path = request.path();
+    let suggestions = route_suggestions(path);
+    if !suggestions.is_empty() {
+        // just the shape, names would make these impossible to group
+        let segments = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        let pattern = std::iter::once(segments[0])
+            .chain(segments[1..].iter().map(|_| "*"))
+            .collect::<Vec<_>>()
+            .join("/");
+        slog::info!(
+            LOG,
+            "no route for /{}, suggesting {:?}",
+            pattern,
+            suggestions
+        );
+    }
+    let wants_json = request
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("application/json"))
+        .unwrap_or(false);
+    if wants_json {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "not found",
+            "path": path,
+            "suggestions": suggestions,
+        })));
+    }
+    let mut ctx = page_context(&crate::i18n::negotiate(&request));
+    ctx.insert("path", path);
+    ctx.insert("suggestions", &suggestions);
+    let s = template.render("404.html", &ctx)?;
+    Ok(HttpResponse::NotFound()
+        .content_type("text/html")
+        .header(http::header::VARY, "Accept-Language")
+        .body(s))
+}
+
+/// One method on one registered path
+pub struct RouteEntry {
+    pub method: &'static str,
+    pub path: String,
+    pub kind: Option<Kind>,
+    pub handler: &'static str,
+}
+
+/// A route as it's registered, along with how `routes` lists it
+struct RouteDef {
+    entry: RouteEntry,
+    route: actix_web::Route,
+}
+
+fn route<F, T, R, U>(
+    method: &'static str,
+    path: impl Into<String>,
+    kind: Option<Kind>,
+    handler_name: &'static str,
+    handler: F,
+) -> RouteDef
+where
+    F: actix_web::dev::Factory<T, R, U>,
+    T: actix_web::FromRequest + 'static,
+    R: std::future::Future<Output = U> + 'static,
+    U: actix_web::Responder + 'static,
+{
+    RouteDef {
+        entry: RouteEntry {
+            method,
+            path: path.into(),
+            kind,
+            handler: handler_name,
+        },
+        route: web::method(method.parse().expect("invalid route method")).to(handler),
+    }
+}
+
+// `route`, listed under the name of its handler
+macro_rules! route {
+    ($method:expr, $path:expr, $handler:ident) => {
+        route($method, $path, None, stringify!($handler), $handler)
+    };
+    ($method:expr, $path:expr, $kind:expr, $handler:ident) => {
+        route($method, $path, Some($kind), stringify!($handler), $handler)
+    };
+}
+
+async fn head_index() -> HttpResponse {
+    HttpResponse::Ok().header("x-head", "less").finish()
+}
+
+async fn head_ok() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+// static files are served by `Files` rather than a route
+const STATIC_PATH: &str = "/static";
+
+/// Routes that aren't tied to a kind
+fn fixed_routes() -> Vec<RouteDef> {
+    vec![
+        route!("GET", "/", index),
+        route!("HEAD", "/", head_index),
+        route!("GET", "/immutable/{hash}/{path:.+}", immutable_badge),
+        route!("HEAD", "/immutable/{hash}/{path:.+}", head_ok),
+        route!("GET", "/reset", reset),
+        route!("HEAD", "/reset", head_ok),
+        route!("GET", "/preview/{name}", preview),
+        route!("HEAD", "/preview/{name}", head_ok),
+        route!("GET", "/reset/recent", recent_resets),
+        route!("POST", "/purge/crate/{name}", purge_crate),
+        route!("GET", "/reset/signed", signed_reset_check),
+        route!("POST", "/reset/signed", signed_reset),
+        route!("DELETE", "/reset/signed", signed_reset),
+        route!("GET", "/status", status),
+        route!("GET", "/health", health),
+        route!("GET", "/stats", stats),
+        route!("GET", "/api", api_index),
+        route!("GET", "/openapi.json", openapi),
+        route!("GET", "/api/v1/badges", api_badges),
+        route!("GET", "/api/crate/{name}/version", api_crate_version),
+        route!("GET", "/api/history/{key:.+}", api_history),
+        route!("GET", "/admin", admin_dashboard),
+        route!("GET", "/admin/login", admin_login_form),
+        route!("POST", "/admin/login", admin_login),
+        route!("POST", "/admin/logout", admin_logout),
+        route!("POST", "/admin/cache-dir", admin_cache_dir),
+        route!("GET", "/admin/index", admin_index),
+        route!("GET", "/admin/diagnostics", admin_diagnostics),
+        route!("GET", "/admin/errors", admin_errors),
+        route!("GET", "/admin/usage", admin_usage),
+        route!("PUT", "/admin/maintenance", admin_maintenance),
+        route!("POST", "/admin/invalidate", admin_invalidate),
+        route!("GET", "/admin/content/{file_name}", admin_content),
+        route!("GET", "/metrics", metrics),
+        route!("GET", "/favicon.ico", favicon),
+        route!("GET", "/robots.txt", robots),
+        route!("GET", "/sitemap.xml", sitemap),
+    ]
+}
+
+/// Every route a kind is served from
+fn kind_routes(kind: Kind) -> Vec<RouteDef> {
+    match kind {
+        Kind::Crate => {
+            let mut routes = vec![
+                route!("GET", "/crates/v/{name}", kind, get_crate),
+                route!("HEAD", "/crates/v/{name}", kind, head_crate),
+                // an alias, which may redirect to `/crates/v/{name}`
+                route(
+                    "GET",
+                    "/crate/{name}",
+                    Some(kind),
+                    "get_alias",
+                    move |name: web::Path<String>, request: HttpRequest| {
+                        get_alias(name.into_inner(), request, kind)
+                    },
+                ),
+                route(
+                    "HEAD",
+                    "/crate/{name}",
+                    Some(kind),
+                    "head_alias",
+                    move |name: web::Path<String>, request: HttpRequest| {
+                        head_alias(name.into_inner(), request, kind)
+                    },
+                ),
+            ];
+            for path in &["/crates/v/{name}", "/crate/{name}"] {
+                routes.extend(vec![
+                    route!("GET", format!("/proxy{}", path), kind, proxy_crate),
+                    route!("HEAD", format!("/proxy{}", path), kind, head_ok),
+                    route!("DELETE", format!("/reset{}", path), kind, reset_crate),
+                    route!("HEAD", format!("/reset{}", path), kind, head_ok),
+                    route!("GET", format!("/debug{}", path), kind, debug_crate),
+                    route!(
+                        "POST",
+                        format!("/admin/reset-link{}", path),
+                        kind,
+                        admin_reset_link_crate
+                    ),
+                ]);
+            }
+            routes
+        }
+        Kind::Badge => vec![
+            route!("GET", "/badge/{name}", kind, get_badge),
+            route!("HEAD", "/badge/{name}", kind, head_badge),
+            route!("GET", "/proxy/badge/{name}", kind, proxy_badge),
+            route!("HEAD", "/proxy/badge/{name}", kind, head_ok),
+            route!("DELETE", "/reset/badge/{name}", kind, reset_badge),
+            route!("HEAD", "/reset/badge/{name}", kind, head_ok),
+            route!("GET", "/debug/badge/{name}", kind, debug_badge),
+            route!(
+                "POST",
+                "/admin/reset-link/badge/{name}",
+                kind,
+                admin_reset_link_badge
+            ),
+        ],
+        // names of the other kinds can span several path segments
+        _ => {
+            let path = format!("{}/{{name:.+}}", kind.route());
+            vec![
+                route(
+                    "GET",
+                    path.as_str(),
+                    Some(kind),
+                    "get_badge_result_for_kind",
+                    move |name: web::Path<String>, request: HttpRequest| {
+                        get_badge_result_for_kind(name.into_inner(), request, kind)
+                    },
+                ),
+                route(
+                    "HEAD",
+                    path.as_str(),
+                    Some(kind),
+                    "head_badge_for_kind",
+                    move |name: web::Path<String>, request: HttpRequest| {
+                        head_badge_for_kind(name.into_inner(), request, kind)
+                    },
+                ),
+                route(
+                    "GET",
+                    format!("/proxy{}", path),
+                    Some(kind),
+                    "proxy_badge_for_kind",
+                    move |name: web::Path<String>, request: HttpRequest| {
+                        proxy_badge_for_kind(name.into_inner(), request, kind)
+                    },
+                ),
+                route!("HEAD", format!("/proxy{}", path), kind, head_ok),
+                route(
+                    "DELETE",
+                    format!("/reset{}", path),
+                    Some(kind),
+                    "reset_cached_badge",
+                    move |name: web::Path<String>, request: HttpRequest| {
+                        reset_cached_badge(name.into_inner(), request, kind)
+                    },
+                ),
+                route!("HEAD", format!("/reset{}", path), kind, head_ok),
+                route(
+                    "GET",
+                    format!("/debug{}", path),
+                    Some(kind),
+                    "debug_badge_for_kind",
+                    move |name: web::Path<String>, request: HttpRequest| {
+                        debug_badge_for_kind(name.into_inner(), request, kind)
+                    },
+                ),
+                route(
+                    "POST",
+                    format!("/admin/reset-link{}", path),
+                    Some(kind),
+                    "admin_reset_link_for_kind",
+                    move |name: web::Path<String>, request: HttpRequest| {
+                        admin_reset_link_for_kind(name.into_inner(), request, kind)
+                    },
+                ),
+            ]
+        }
+    }
+}
+
+/// Extra paths for badges, from `ROUTE_ALIASES`
+fn alias_routes() -> Vec<RouteDef> {
+    let mut routes = vec![];
+    for (path, kind) in CONFIG.route_aliases.iter() {
+        let kind = *kind;
+        routes.push(route(
+            "GET",
+            path.as_str(),
+            Some(kind),
+            "get_alias",
+            move |name: web::Path<String>, request: HttpRequest| {
+                get_alias(name.into_inner(), request, kind)
+            },
+        ));
+        routes.push(route(
+            "HEAD",
+            path.as_str(),
+            Some(kind),
+            "head_alias",
+            move |name: web::Path<String>, request: HttpRequest| {
+                head_alias(name.into_inner(), request, kind)
+            },
+        ));
+    }
+    routes
+}
+
+/// Every route, in the order they're registered
+fn routes() -> Vec<RouteDef> {
+    let mut routes = fixed_routes();
+    for kind in Kind::ALL.iter().copied() {
+        routes.extend(kind_routes(kind));
+    }
+    routes.extend(alias_routes());
+    routes
+}
+
+/// The effective routing table, kinds and aliases included
+pub fn route_table() -> Vec<RouteEntry> {
+    let mut table = routes().into_iter().map(|r| r.entry).collect::<Vec<_>>();
+    table.push(RouteEntry {
+        method: "GET",
+        path: format!("{}/*", STATIC_PATH),
+        kind: None,
+        handler: "static files",
+    });
+    table
+}
+
+/// What it takes to get through to a path: the auth middleware's
+/// requirement for its group, and any token the handler checks itself
+pub fn route_auth(path: &str) -> String {
+    let group = crate::auth::Group::for_path(path);
+    let mut auth = match crate::auth::requirement(group) {
+        crate::auth::Requirement::Open => format!("{}:open", group.as_str()),
+        crate::auth::Requirement::Required => format!("{}:required", group.as_str()),
+    };
+    if path.starts_with("/admin") && path != "/admin/login" && path != "/admin/logout" {
+        auth.push_str(" +admin token");
+    } else if path.starts_with("/debug") {
+        auth.push_str(" +debug token");
+    } else if path == "/reset/signed" {
+        auth.push_str(" +signed link");
+    }
+    auth
+}
+
+/// Register `routes`, each path's methods under a single resource
+fn configure_routes(cfg: &mut web::ServiceConfig) {
+    let mut paths: Vec<(String, Vec<actix_web::Route>)> = vec![];
+    for RouteDef { entry, route } in routes() {
+        match paths.iter_mut().find(|(path, _)| *path == entry.path) {
+            Some((_, group)) => group.push(route),
+            None => paths.push((entry.path, vec![route])),
+        }
+    }
+    for (path, group) in paths {
+        let resource = group
+            .into_iter()
+            .fold(web::resource(path.as_str()), |resource, route| {
+                resource.route(route)
+            });
+        cfg.service(resource);
+    }
+}
+
+static CLEANUP_STARTED: AtomicBool = AtomicBool::new(false);
+
 pub async fn start() -> anyhow::Result<()> {
     let addr = format!("{}:{}", CONFIG.host, CONFIG.port);
     slog::info!(LOG, "** Listening on {} **", addr);
 
-    HttpServer::new(|| {
-        actix_web::rt::spawn(cleanup());
-        let tera = Tera::new("templates/**/*.html").expect("unable to compile templates");
+    // compiled once, a bad template shouldn't take down every worker
+    let templates = web::Data::new(Templates::load("templates/**/*.html"));
+    // render the pages bots hit most up front
+    for (name, ctx) in &[
+        ("landing.html", page_context(crate::i18n::DEFAULT_LOCALE)),
+        ("reset.html", reset_context(crate::i18n::DEFAULT_LOCALE)),
+    ] {
+        if let Err(e) = templates.render_static(name, ctx) {
+            slog::error!(LOG, "unable to pre-render {}: {:?}", name, e);
+        }
+    }
+
+    HttpServer::new(move || {
+        // every worker runs this factory, but only one set of background tasks is needed
+        // a read-only cache is never cleaned up, it may be shared with the writer
+        if !CONFIG.read_only && !CLEANUP_STARTED.swap(true, Ordering::SeqCst) {
+            actix_web::rt::spawn(cache::supervise_cleanup());
+            actix_web::rt::spawn(crate::refresh::run());
+            actix_web::rt::spawn(crate::history::run());
+            actix_web::rt::spawn(crate::probe::run());
+        }
 
         App::new()
-            .data(tera)
+            .app_data(templates.clone())
+            .wrap(crate::deprecation::Deprecation::new())
+            .wrap(crate::auth::Auth::new())
             .wrap(crate::logger::Logger::new())
-            .service(
-                web::resource("/")
-                    .route(web::get().to(index))
-                    .route(web::head().to(|| HttpResponse::Ok().header("x-head", "less").finish())),
-            )
-            .service(
-                web::resource("/crates/v/{name}")
-                    .route(web::get().to(get_crate))
-                    .route(web::head().to(|| HttpResponse::Ok().finish())),
-            )
-            .service(
-                web::resource("/crate/{name}")
-                    .route(web::get().to(get_crate))
-                    .route(web::head().to(|| HttpResponse::Ok().finish())),
-            )
-            .service(
-                web::resource("/badge/{name}")
-                    .route(web::get().to(get_badge))
-                    .route(web::head().to(|| HttpResponse::Ok().finish())),
-            )
-            .service(
-                web::resource("/reset")
-                    .route(web::get().to(reset))
-                    .route(web::head().to(|| HttpResponse::Ok().finish())),
-            )
-            .service(
-                web::resource("/reset/crates/v/{name}")
-                    .route(web::delete().to(reset_crate))
-                    .route(web::head().to(|| HttpResponse::Ok().finish())),
-            )
-            .service(
-                web::resource("/reset/crate/{name}")
-                    .route(web::delete().to(reset_crate))
-                    .route(web::head().to(|| HttpResponse::Ok().finish())),
-            )
-            .service(
-                web::resource("/reset/badge/{name}")
-                    .route(web::delete().to(reset_badge))
-                    .route(web::head().to(|| HttpResponse::Ok().finish())),
-            )
-            // static files
-            .service(Files::new("/static", "static"))
-            // status
-            .service(web::resource("/status").route(web::get().to(status)))
-            // special resources
-            .service(web::resource("/favicon.ico").route(web::get().to(favicon)))
-            .service(web::resource("/robots.txt").route(web::get().to(robots)))
+            .configure(configure_routes)
+            .service(Files::new(STATIC_PATH, "static"))
             // 404s
-            .default_service(web::resource("").route(web::get().to(p404)))
+            .default_service(web::resource("").route(web::get().to(not_found)))
     })
     .bind(addr)?
     .run()
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn request() -> HttpRequest {
+        actix_web::test::TestRequest::default().to_http_request()
+    }
+
+    #[test]
+    fn maven_path_style_names_are_dotted() {
+        assert_eq!(
+            Kind::MavenCentral.normalize_name("org/apache/commons/commons-lang3".to_string()),
+            "org.apache.commons/commons-lang3"
+        );
+        assert_eq!(
+            Kind::MavenCentral.normalize_name("org.apache.commons/commons-lang3".to_string()),
+            "org.apache.commons/commons-lang3"
+        );
+    }
+
+    #[test]
+    fn maven_name_forms_share_a_cache_key() {
+        let req = request();
+        let path_style = Params::new(
+            "org/apache/commons/commons-lang3.svg",
+            Kind::MavenCentral,
+            &req,
+        )
+        .unwrap();
+        let dotted = Params::new(
+            "org.apache.commons/commons-lang3.svg",
+            Kind::MavenCentral,
+            &req,
+        )
+        .unwrap();
+        assert_eq!(path_style.name, "org.apache.commons/commons-lang3");
+        assert_eq!(path_style.cache_name, dotted.cache_name);
+    }
+
+    #[test]
+    fn every_kind_is_routed() {
+        let table = route_table();
+        for kind in Kind::ALL.iter().copied() {
+            let has = |method: &str, prefix: &str| {
+                table.iter().any(|r| {
+                    r.kind == Some(kind) && r.method == method && r.path.starts_with(prefix)
+                })
+            };
+            assert!(has("GET", kind.route()), "no GET route for {:?}", kind);
+            assert!(has("HEAD", kind.route()), "no HEAD route for {:?}", kind);
+            let reset = format!("/reset{}", kind.route());
+            assert!(has("DELETE", &reset), "no reset route for {:?}", kind);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn cache_keys_round_trip(
+            name in "[a-zA-Z][a-zA-Z0-9_-]{0,30}",
+            ext in "(svg|png|json)",
+        ) {
+            let req = request();
+            let params = Params::new(&format!("{}.{}", name, ext), Kind::Crate, &req).unwrap();
+            prop_assert_eq!(&params.name, &name);
+            prop_assert_eq!(&params.ext, &ext);
+            prop_assert!(!params.vary_accept);
+
+            let again = Params::new(
+                &format!("{}.{}", params.name, params.ext),
+                Kind::Crate,
+                &req,
+            )
+            .unwrap();
+            prop_assert_eq!(params.cache_name, again.cache_name);
+        }
+
+        #[test]
+        fn arbitrary_names_never_panic(name in any::<String>()) {
+            let req = request();
+            for kind in Kind::ALL {
+                let _ = Params::new(&name, *kind, &req);
+            }
+        }
+
+        #[test]
+        fn long_names_truncate_on_char_boundaries(name in "[aé漢🦀]{200,400}") {
+            let params = Params::new(&format!("{}.svg", name), Kind::Pypi, &request()).unwrap();
+            prop_assert!(params.name.len() <= CONFIG.max_name_length);
+            prop_assert!(name.starts_with(&params.name));
+        }
+
+        #[test]
+        fn missing_or_unknown_ext_uses_the_default(
+            name in "[a-z][a-z0-9]{0,20}",
+            ext in "[a-z]{0,5}",
+        ) {
+            prop_assume!(!["svg", "png", "json"].contains(&ext.as_str()));
+            let full_name = if ext.is_empty() {
+                name
+            } else {
+                format!("{}.{}", name, ext)
+            };
+            let params = Params::new(&full_name, Kind::Pypi, &request()).unwrap();
+            prop_assert_eq!(&params.ext, &CONFIG.default_file_ext);
+            prop_assert!(params.vary_accept);
+            prop_assert_eq!(params.name, full_name);
+        }
+    }
+}
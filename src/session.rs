@@ -0,0 +1,66 @@
+use actix_web::{HttpMessage, HttpRequest};
+
+use crate::CONFIG;
+
+pub const COOKIE_NAME: &str = "badge_cache_admin";
+
+fn message(expires_millis: u128) -> String {
+    format!("admin-session:{}", expires_millis)
+}
+
+fn set_cookie(value: &str, max_age_seconds: u64, secure: bool) -> String {
+    let mut cookie = format!(
+        "{}={}; Path=/admin; Max-Age={}; HttpOnly; SameSite=Strict",
+        COOKIE_NAME, value, max_age_seconds
+    );
+    if secure {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+/// A `Set-Cookie` good for `ADMIN_SESSION_SECONDS`, holding nothing but
+/// its expiry and a signature over it. Nothing is kept server side, and
+/// dropping an `ADMIN_TOKEN` (the signing key) logs out its sessions.
+pub fn issue(request: &HttpRequest) -> Option<String> {
+    let key = CONFIG.admin_token.as_ref()?;
+    let expires_millis = crate::cache::now_millis() + CONFIG.admin_session_seconds as u128 * 1000;
+    let sig = crate::signing::sign(key, &message(expires_millis));
+    let secure = request.connection_info().scheme() == "https";
+    Some(set_cookie(
+        &format!("{}.{}", expires_millis, sig),
+        CONFIG.admin_session_seconds,
+        secure,
+    ))
+}
+
+/// A `Set-Cookie` replacing the session cookie with one that's already expired
+pub fn clear() -> String {
+    set_cookie("", 0, false)
+}
+
+/// Whether the request carries an unexpired session cookie signed with
+/// the current or a previous `ADMIN_TOKEN`
+pub fn valid(request: &HttpRequest) -> bool {
+    let key = match &CONFIG.admin_token {
+        Some(key) => key,
+        None => return false,
+    };
+    let cookie = match request.cookie(COOKIE_NAME) {
+        Some(cookie) => cookie,
+        None => return false,
+    };
+    let mut parts = cookie.value().splitn(2, '.');
+    let expires_millis = match parts.next().and_then(|e| e.parse::<u128>().ok()) {
+        Some(e) => e,
+        None => return false,
+    };
+    let sig = parts.next().unwrap_or("");
+    expires_millis > crate::cache::now_millis()
+        && crate::signing::verify_any(
+            key,
+            &CONFIG.admin_token_previous,
+            &message(expires_millis),
+            sig,
+        )
+}
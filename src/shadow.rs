@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::errors::{self, ServiceError};
+use crate::service::{Kind, Params};
+use crate::{CONFIG, LOG};
+
+/// The shadow url to also fetch a badge from, for `SHADOW_SAMPLE_PERCENT`
+/// of the fetches of kinds with a KIND_<KIND>_SHADOW_URL
+pub fn sample(params: &Params) -> Option<String> {
+    let url = params
+        .kind
+        .shadow_upstream_url(&params.name, &params.ext, &params.query_params)?;
+    if rand::thread_rng().gen_range(0.0..100.0) >= CONFIG.shadow_sample_percent {
+        return None;
+    }
+    Some(url)
+}
+
+async fn fetch(url: &str) -> errors::Result<bytes::Bytes> {
+    let resp = crate::upstream::get(url).await?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(ServiceError::UpstreamStatus {
+            url: url.to_string(),
+            status: status.as_u16(),
+        });
+    }
+    Ok(resp.bytes().await?)
+}
+
+/// Fetch a badge from its shadow upstream and compare it with what the
+/// primary returned. Only logged and counted, nothing is served or stored.
+pub async fn compare(
+    kind: Kind,
+    cache_name: String,
+    url: String,
+    primary_hash: String,
+    primary_latency: Duration,
+) {
+    let start = Instant::now();
+    let result = fetch(&url).await;
+    let latency = start.elapsed();
+    crate::metrics::observe(
+        "shadow_request_duration_seconds",
+        &[("kind", kind.as_str())],
+        latency.as_secs_f64(),
+    );
+    let outcome = match result {
+        Ok(bytes) => {
+            let shadow_hash = crate::cache::content_hash(&bytes);
+            if shadow_hash == primary_hash {
+                "match"
+            } else {
+                slog::warn!(
+                    LOG, "shadow upstream content differs";
+                    "key" => &cache_name,
+                    "url" => &url,
+                    "primary_hash" => &primary_hash,
+                    "shadow_hash" => &shadow_hash,
+                    "primary_millis" => primary_latency.as_millis() as u64,
+                    "shadow_millis" => latency.as_millis() as u64,
+                );
+                "mismatch"
+            }
+        }
+        Err(e) => {
+            slog::warn!(
+                LOG, "shadow upstream request failed";
+                "key" => &cache_name,
+                "url" => &url,
+                "error" => e.to_string(),
+            );
+            "error"
+        }
+    };
+    crate::metrics::inc(
+        "shadow_comparisons_total",
+        &[("kind", kind.as_str()), ("result", outcome)],
+    );
+}
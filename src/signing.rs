@@ -0,0 +1,40 @@
+use hmac::{Hmac, Mac, NewMac};
+
+type HmacSha256 = Hmac<sha2::Sha256>;
+
+/// Compare the whole of both strings so timing doesn't give away a matching prefix
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Hex encoded hmac-sha256 of `message`
+pub fn sign(key: &str, message: &str) -> String {
+    let mut mac = HmacSha256::new_varkey(key.as_bytes()).expect("hmac accepts any key length");
+    mac.update(message.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+pub fn verify(key: &str, message: &str, signature: &str) -> bool {
+    constant_time_eq(&sign(key, message), signature)
+}
+
+/// Keys to accept, the current one first. Previous keys are still
+/// accepted while rotating, so links and tokens handed out before the
+/// rotation keep working until they're dropped from config.
+fn accepted<'a>(current: &'a str, previous: &'a [String]) -> impl Iterator<Item = &'a str> {
+    std::iter::once(current).chain(previous.iter().map(|k| k.as_str()))
+}
+
+/// Whether `signature` was made with the current or a previous key
+pub fn verify_any(current: &str, previous: &[String], message: &str, signature: &str) -> bool {
+    accepted(current, previous).any(|key| verify(key, message, signature))
+}
+
+/// Whether `given` is the current or a previous secret
+pub fn matches_any(given: &str, current: &str, previous: &[String]) -> bool {
+    accepted(current, previous).any(|secret| constant_time_eq(given, secret))
+}
@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+// a crate that's always going to exist on crates.io
+const SMOKE_CRATE: &str = "serde";
+const SMOKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Check = Result<(), String>;
+
+fn expect_status(resp: &reqwest::Response, expected: u16) -> Check {
+    let status = resp.status().as_u16();
+    if status == expected {
+        Ok(())
+    } else {
+        Err(format!("expected status {}, got {}", expected, status))
+    }
+}
+
+fn expect_header(resp: &reqwest::Response, name: &str, expected: Option<&str>) -> Check {
+    let value = resp
+        .headers()
+        .get(name)
+        .ok_or_else(|| format!("missing {} header", name))?
+        .to_str()
+        .map_err(|e| format!("unreadable {} header: {}", name, e))?;
+    match expected {
+        Some(expected) if !value.starts_with(expected) => Err(format!(
+            "expected {} header {:?}, got {:?}",
+            name, expected, value
+        )),
+        _ => Ok(()),
+    }
+}
+
+async fn check_status(client: &reqwest::Client, base: &str) -> Check {
+    let resp = client
+        .get(&format!("{}/status", base))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    expect_status(&resp, 200)?;
+    let body = resp
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|e| e.to_string())?;
+    if body["status"] != "ok" {
+        return Err(format!("unexpected status body {}", body));
+    }
+    Ok(())
+}
+
+async fn check_badge(client: &reqwest::Client, url: &str) -> Check {
+    let resp = client.get(url).send().await.map_err(|e| e.to_string())?;
+    expect_status(&resp, 200)?;
+    expect_header(&resp, "content-type", Some("image/svg+xml"))?;
+    expect_header(&resp, "cache-control", None)?;
+    expect_header(&resp, "x-was-cached", None)?;
+    let body = resp.bytes().await.map_err(|e| e.to_string())?;
+    if !body.starts_with(b"<svg") {
+        return Err("badge body isn't an svg".to_string());
+    }
+    Ok(())
+}
+
+async fn check_reset(client: &reqwest::Client, url: &str) -> Check {
+    let resp = client.delete(url).send().await.map_err(|e| e.to_string())?;
+    expect_status(&resp, 200)
+}
+
+async fn check_refetched(client: &reqwest::Client, url: &str) -> Check {
+    let resp = client.get(url).send().await.map_err(|e| e.to_string())?;
+    expect_status(&resp, 200)?;
+    // just reset, so this can't have come from the cache
+    expect_header(&resp, "x-was-cached", Some("false"))
+}
+
+async fn check_metrics(client: &reqwest::Client, base: &str) -> Check {
+    let resp = client
+        .get(&format!("{}/metrics", base))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    expect_status(&resp, 200)?;
+    let body = resp.text().await.map_err(|e| e.to_string())?;
+    if !body.contains("# TYPE") {
+        return Err("no metrics in body".to_string());
+    }
+    Ok(())
+}
+
+/// Run through the critical paths against a live deployment, printing
+/// each check as it goes. Fails if any of them do.
+pub async fn run(base_url: &str) -> anyhow::Result<()> {
+    let base = base_url.trim_end_matches('/');
+    let client = reqwest::Client::builder().timeout(SMOKE_TIMEOUT).build()?;
+    let badge = format!("{}/crates/v/{}.svg", base, SMOKE_CRATE);
+    let reset = format!("{}/reset/crates/v/{}.svg", base, SMOKE_CRATE);
+
+    let results = vec![
+        ("status", check_status(&client, base).await),
+        ("fetch crate badge", check_badge(&client, &badge).await),
+        ("reset crate badge", check_reset(&client, &reset).await),
+        (
+            "refetch crate badge",
+            check_refetched(&client, &badge).await,
+        ),
+        ("metrics", check_metrics(&client, base).await),
+    ];
+    let mut failed = 0;
+    for (name, result) in results.iter() {
+        match result {
+            Ok(()) => println!("ok    {}", name),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL  {}: {}", name, e);
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} smoke checks failed", failed, results.len());
+    }
+    Ok(())
+}
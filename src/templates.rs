@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+use tera::{Context, Tera};
+
+use crate::errors::{self, ServiceError};
+use crate::{CONFIG, LOG};
+
+// served in place of any page when the templates didn't compile
+const FALLBACK_PAGE: &str = r#"<html>
+    <head>
+        <meta charset="utf-8">
+        <title> badge-cache.rs </title>
+    </head>
+    <body>
+<pre>
+Welcome to badge-cache!
+
+This page is unavailable right now, but badges are still being served:
+    /crates/v/&ltcrate-name&gt.svg?&ltshields-io-params&gt
+    /badge/&ltbadge-info-triple&gt.svg?&ltshields-io-params&gt
+</pre>
+    </body>
+</html>
+"#;
+
+// how many distinct renders of static pages to hold on to
+const RENDERED_CAPACITY: usize = 32;
+
+/// A rendered page and the etag it's served with
+#[derive(Clone)]
+pub struct Page {
+    pub body: String,
+    pub etag: String,
+}
+impl Page {
+    fn new(body: String) -> Self {
+        let etag = format!("\"{}\"", &crate::cache::content_hash(body.as_bytes())[..16]);
+        Self { body, etag }
+    }
+}
+
+fn compile(glob: &str) -> Option<Tera> {
+    match Tera::new(glob) {
+        Ok(tera) => Some(tera),
+        Err(e) => {
+            slog::error!(
+                LOG,
+                "unable to compile templates, serving fallback pages: {:?}",
+                e
+            );
+            crate::metrics::inc("template_compile_errors_total", &[]);
+            None
+        }
+    }
+}
+
+/// The compiled html templates, shared by every worker. When they fail to
+/// compile the error is logged once at startup and every page is served as
+/// a minimal built-in fallback, rather than taking the badge routes down
+/// with them.
+pub struct Templates {
+    glob: String,
+    tera: RwLock<Option<Tera>>,
+    // static pages by template name and context, which only change
+    // when something like the maintenance banner does
+    rendered: Mutex<HashMap<String, Page>>,
+}
+impl Templates {
+    pub fn load(glob: &str) -> Self {
+        Self {
+            glob: glob.to_string(),
+            tera: RwLock::new(compile(glob)),
+            rendered: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Recompile the templates from disk and drop every rendered page
+    pub fn rebuild(&self) {
+        let tera = compile(&self.glob);
+        *self.tera.write().expect("templates lock poisoned") = tera;
+        self.rendered
+            .lock()
+            .expect("rendered pages lock poisoned")
+            .clear();
+    }
+
+    pub fn render(&self, name: &str, ctx: &Context) -> errors::Result<String> {
+        match &*self.tera.read().expect("templates lock poisoned") {
+            Some(tera) => tera.render(name, ctx).map_err(|e| {
+                let e = ServiceError::from(e);
+                errors::record(name, &e);
+                e
+            }),
+            None => Ok(FALLBACK_PAGE.to_string()),
+        }
+    }
+
+    /// Render a page whose output only depends on its context, reusing
+    /// the previous render for the same context. In dev mode the templates
+    /// are rebuilt first, so edits show up without a restart.
+    pub fn render_static(&self, name: &str, ctx: &Context) -> errors::Result<Page> {
+        if CONFIG.dev_mode {
+            self.rebuild();
+        }
+        let key = format!("{}:{}", name, ctx.clone().into_json());
+        if let Some(page) = self
+            .rendered
+            .lock()
+            .expect("rendered pages lock poisoned")
+            .get(&key)
+        {
+            return Ok(page.clone());
+        }
+        let page = Page::new(self.render(name, ctx)?);
+        let mut rendered = self.rendered.lock().expect("rendered pages lock poisoned");
+        if rendered.len() >= RENDERED_CAPACITY {
+            rendered.clear();
+        }
+        rendered.insert(key, page.clone());
+        Ok(page)
+    }
+}
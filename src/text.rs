@@ -0,0 +1,63 @@
+/// A string cut down to at most some number of bytes. The cut is always
+/// made on a char boundary, backing off to the previous one if needed,
+/// so multibyte input can't panic whatever is truncating it.
+#[derive(Debug, Clone)]
+pub struct TruncatedString {
+    value: String,
+    original_len: usize,
+}
+impl TruncatedString {
+    pub fn new(mut s: String, max_len: usize) -> Self {
+        let original_len = s.len();
+        s.truncate(floor_char_boundary(&s, max_len));
+        Self {
+            value: s,
+            original_len,
+        }
+    }
+
+    pub fn was_truncated(&self) -> bool {
+        self.value.len() < self.original_len
+    }
+
+    /// The length, in bytes, before truncating
+    pub fn original_len(&self) -> usize {
+        self.original_len
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    pub fn into_string(self) -> String {
+        self.value
+    }
+}
+
+/// The largest index at or below `max` that's on a char boundary of `s`
+pub fn floor_char_boundary(s: &str, max: usize) -> usize {
+    (0..=max.min(s.len()))
+        .rev()
+        .find(|i| s.is_char_boundary(*i))
+        .unwrap_or(0)
+}
+
+/// Standard, padded base64
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | ((*b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
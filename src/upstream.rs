@@ -0,0 +1,247 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::rt;
+
+use crate::errors::{self, ServiceError};
+use crate::{CONFIG, LOG};
+
+lazy_static::lazy_static! {
+    // Shared client so connections to upstream hosts are pooled
+    pub static ref CLIENT: reqwest::Client = build_client();
+
+    // All outbound requests, no matter which subsystem makes them,
+    // are spaced out per host by this scheduler
+    pub static ref SCHEDULER: Scheduler = Scheduler::new(CONFIG.upstream_max_rps);
+
+    // hosts outbound requests may go to, `None` when any host is allowed
+    static ref ALLOWED_HOSTS: Option<HashSet<String>> = allowed_hosts();
+}
+
+// same as reqwest's default redirect policy
+const MAX_REDIRECTS: usize = 10;
+
+/// The configured allowlist, plus the hosts of the services we're
+/// explicitly pointed at: the crates.io api, peers, and cluster nodes.
+/// Url templates aren't included, a bad one shouldn't widen what's reachable.
+fn allowed_hosts() -> Option<HashSet<String>> {
+    if CONFIG.outbound_host_allowlist.is_empty() {
+        return None;
+    }
+    let mut hosts = CONFIG
+        .outbound_host_allowlist
+        .iter()
+        .cloned()
+        .collect::<HashSet<_>>();
+    let services = std::iter::once(&CONFIG.crates_io_api_url)
+        .chain(CONFIG.peer_sync_url.iter())
+        .chain(CONFIG.peer_urls.iter())
+        .chain(CONFIG.cluster_nodes.iter());
+    for url in services {
+        if let Some(host) = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_lowercase()))
+        {
+            hosts.insert(host);
+        }
+    }
+    Some(hosts)
+}
+
+fn host_allowed(host: &str) -> bool {
+    match &*ALLOWED_HOSTS {
+        Some(hosts) => hosts.contains(&host.to_lowercase()),
+        None => true,
+    }
+}
+
+fn build_client() -> reqwest::Client {
+    // redirects are checked against the allowlist too, so an upstream
+    // can't bounce requests somewhere they couldn't be sent directly
+    let redirects = reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        let host = attempt.url().host_str().unwrap_or("").to_string();
+        if host_allowed(&host) {
+            attempt.follow()
+        } else {
+            crate::metrics::inc(
+                "outbound_blocked_total",
+                &[("host", &host), ("via", "redirect")],
+            );
+            attempt.error(format!("redirect to disallowed host {}", host))
+        }
+    });
+    reqwest::Client::builder()
+        // trust-dns caches lookups for their ttl, so a brief resolver
+        // outage doesn't fail every upstream request
+        .trust_dns(CONFIG.upstream_dns_cache)
+        .redirect(redirects)
+        .build()
+        .expect("unable to build http client")
+}
+
+#[derive(Default, Debug, Clone, serde::Serialize)]
+pub struct HostStats {
+    pub requests: u64,
+    pub delayed: u64,
+    pub queued: u64,
+    pub total_wait_millis: u128,
+}
+
+#[derive(Default)]
+struct HostState {
+    next_slot: Option<Instant>,
+    stats: HostStats,
+}
+
+pub struct Scheduler {
+    min_interval: Option<Duration>,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+impl Scheduler {
+    pub fn new(max_rps: f64) -> Self {
+        let min_interval = if max_rps > 0. {
+            Some(Duration::from_secs_f64(1. / max_rps))
+        } else {
+            None
+        };
+        Self {
+            min_interval,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserve the next free slot for `host`, returning how long
+    /// the caller needs to wait before its slot comes up.
+    fn reserve(&self, host: &str) -> Duration {
+        let mut hosts = self.hosts.lock().expect("scheduler lock poisoned");
+        let state = hosts.entry(host.to_string()).or_default();
+        state.stats.requests += 1;
+
+        let now = Instant::now();
+        let slot = match state.next_slot {
+            Some(next) if next > now => next,
+            _ => now,
+        };
+        if let Some(min_interval) = self.min_interval {
+            state.next_slot = Some(slot + min_interval);
+        }
+
+        let wait = slot - now;
+        if wait > Duration::from_secs(0) {
+            state.stats.delayed += 1;
+            state.stats.queued += 1;
+            state.stats.total_wait_millis += wait.as_millis();
+        }
+        wait
+    }
+
+    fn release(&self, host: &str) {
+        let mut hosts = self.hosts.lock().expect("scheduler lock poisoned");
+        if let Some(state) = hosts.get_mut(host) {
+            state.stats.queued = state.stats.queued.saturating_sub(1);
+        }
+    }
+
+    /// Wait until `host` can be sent another request
+    pub async fn acquire(&self, host: &str) {
+        let wait = self.reserve(host);
+        if wait > Duration::from_secs(0) {
+            slog::debug!(LOG, "delaying upstream request to {} by {:?}", host, wait);
+            rt::time::delay_for(wait).await;
+            self.release(host);
+        }
+    }
+
+    /// Hold off on sending `host` anything else for `wait`
+    fn back_off(&self, host: &str, wait: Duration) {
+        let mut hosts = self.hosts.lock().expect("scheduler lock poisoned");
+        let state = hosts.entry(host.to_string()).or_default();
+        let until = Instant::now() + wait;
+        if state.next_slot.map(|next| next < until).unwrap_or(true) {
+            state.next_slot = Some(until);
+        }
+    }
+
+    pub fn stats(&self) -> HashMap<String, HostStats> {
+        let hosts = self.hosts.lock().expect("scheduler lock poisoned");
+        hosts
+            .iter()
+            .map(|(host, state)| (host.clone(), state.stats.clone()))
+            .collect()
+    }
+}
+
+fn scheduled_host(url: &str) -> errors::Result<(reqwest::Url, String)> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| ServiceError::Internal(format!("invalid url {}: {}", url, e)))?;
+    let host = parsed.host_str().unwrap_or("").to_string();
+    if !host_allowed(&host) {
+        crate::metrics::inc(
+            "outbound_blocked_total",
+            &[("host", &host), ("via", "request")],
+        );
+        slog::warn!(LOG, "blocked request to disallowed host: {}", url);
+        return Err(ServiceError::HostNotAllowed(host));
+    }
+    Ok((parsed, host))
+}
+
+/// Stop sending requests to `url`'s host for a while, after it's told us
+/// we're sending too many
+pub fn back_off(url: &str, wait: Duration) {
+    if let Ok(parsed) = reqwest::Url::parse(url) {
+        SCHEDULER.back_off(parsed.host_str().unwrap_or(""), wait);
+    }
+}
+
+fn send_error(host: &str, e: reqwest::Error) -> ServiceError {
+    if e.is_connect() {
+        crate::metrics::inc("upstream_connect_errors_total", &[("host", host)]);
+    }
+    ServiceError::from(e)
+}
+
+/// Make a scheduled GET request to an upstream
+pub async fn get(url: &str) -> errors::Result<reqwest::Response> {
+    get_with_headers(url, reqwest::header::HeaderMap::new()).await
+}
+
+/// Make a scheduled GET request to an upstream, sending along extra headers
+pub async fn get_with_headers(
+    url: &str,
+    headers: reqwest::header::HeaderMap,
+) -> errors::Result<reqwest::Response> {
+    let (parsed, host) = scheduled_host(url)?;
+    SCHEDULER.acquire(&host).await;
+    crate::faults::upstream(&host).await?;
+    let resp = CLIENT
+        .get(parsed)
+        .headers(headers)
+        .send()
+        .await
+        .map_err(|e| send_error(&host, e))?;
+    Ok(resp)
+}
+
+/// Make a scheduled POST request with a json body
+pub async fn post_json<T: serde::Serialize + ?Sized>(
+    url: &str,
+    headers: reqwest::header::HeaderMap,
+    body: &T,
+) -> errors::Result<reqwest::Response> {
+    let (parsed, host) = scheduled_host(url)?;
+    SCHEDULER.acquire(&host).await;
+    crate::faults::upstream(&host).await?;
+    let resp = CLIENT
+        .post(parsed)
+        .headers(headers)
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| send_error(&host, e))?;
+    Ok(resp)
+}